@@ -5,7 +5,7 @@ use std::{
         atomic::{AtomicUsize, Ordering},
         Arc, LazyLock,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
@@ -19,7 +19,13 @@ use moka::future::Cache;
 use once_cell::sync::Lazy;
 use picomux::{PicoMux, Stream};
 use rand::Rng;
-use sillad::{dialer::Dialer, listener::Listener, tcp::TcpListener, Pipe};
+use sillad::{
+    dialer::{Dialer, DialerExt, RetryPolicy},
+    listener::{Listener, ListenerExt},
+    proxy_protocol::ProxyProtocolListener,
+    tcp::TcpListener,
+    Pipe,
+};
 use smol::future::FutureExt as _;
 use smol::io::AsyncWriteExt;
 use smol_timeout2::TimeoutExt;
@@ -58,7 +64,16 @@ impl BridgeControlProtocol for State {
                     .local_addr()
                     .await
                     .tap_mut(|s| s.set_ip(self.my_ip));
-                let task = smolscale::spawn(handle_one_listener(listener, b2e_dest, metadata));
+                // behind a load balancer, the real client addresses are only known from the PROXY protocol
+                let task = if std::env::var("GEPH5_BRIDGE_PROXY_PROTOCOL").is_ok() {
+                    smolscale::spawn(handle_one_listener(
+                        ProxyProtocolListener::new(listener),
+                        b2e_dest,
+                        metadata,
+                    ))
+                } else {
+                    smolscale::spawn(handle_one_listener(listener, b2e_dest, metadata))
+                };
                 (addr, Arc::new(task))
             })
             .await
@@ -79,13 +94,23 @@ async fn random_tcp_listener() -> TcpListener {
     }
 }
 
+/// The most client connections that a single forwarding listener keeps open at once.
+const MAX_CONCURRENT_PER_LISTENER: usize = 10_000;
+
+/// The most client connections that a single forwarding listener accepts per second.
+const ACCEPT_RATE_PER_LISTENER: f64 = 200.0;
+
 async fn handle_one_listener(
-    mut listener: impl Listener,
+    listener: impl Listener,
     b2e_dest: SocketAddr,
     metadata: B2eMetadata,
 ) -> anyhow::Result<()> {
     static COUNT: AtomicUsize = AtomicUsize::new(0);
 
+    let mut listener = listener
+        .max_concurrent(MAX_CONCURRENT_PER_LISTENER)
+        .accept_rate(ACCEPT_RATE_PER_LISTENER);
+
     loop {
         let client_conn = listener.accept().await?;
         let count = COUNT.fetch_add(1, Ordering::Relaxed);
@@ -199,25 +224,49 @@ impl SinglePool {
         let (send, recv) = async_channel::bounded(100);
         let live_count = Arc::new(AtomicUsize::new(0));
         let mut tasks = vec![];
+        // retries give up after a few attempts, so that a dead exit gets logged instead of retried silently forever
+        let policy = RetryPolicy::default();
+        // all the workers share one breaker, so that a dead exit isn't hammered by every one of them
+        let dialer = sillad::tcp::TcpDialer { dest_addr: dest }
+            .circuit_breaker(8, Duration::from_secs(30))
+            .retry(policy)
+            .dynamic();
         for _ in 0..32 {
             let recv = recv.clone();
             let live_count = live_count.clone();
+            let dialer = dialer.clone();
             let task = smolscale::spawn(async move {
+                let mut failures = 0;
                 loop {
-                    let conn = sillad::tcp::TcpDialer { dest_addr: dest }.dial().await;
-                    if let Ok(conn) = conn {
-                        let (read, write) = conn.split();
-                        let mux = PicoMux::new(read, write);
-                        let recv = recv.clone();
-                        live_count.fetch_add(1, Ordering::Relaxed);
-                        scopeguard::defer!({
-                            live_count.fetch_sub(1, Ordering::Relaxed);
-                        });
-                        if let Err(err) = remote_once(recv.clone(), &mux).await {
-                            tracing::error!(dest = display(dest), "remote_once error: {}", err);
+                    match dialer.dial().await {
+                        Ok(conn) => {
+                            let (read, write) = conn.split();
+                            let mux = PicoMux::new(read, write);
+                            let recv = recv.clone();
+                            live_count.fetch_add(1, Ordering::Relaxed);
+                            scopeguard::defer!({
+                                live_count.fetch_sub(1, Ordering::Relaxed);
+                            });
+                            let start = Instant::now();
+                            if let Err(err) = remote_once(recv.clone(), &mux).await {
+                                tracing::error!(dest = display(dest), "remote_once error: {}", err);
+                            }
+                            // a session that stayed up for a while means the exit is healthy again
+                            if start.elapsed() > policy.max_delay {
+                                failures = 0;
+                            }
+                        }
+                        Err(err) => {
+                            tracing::warn!(
+                                dest = display(dest),
+                                failures,
+                                "could not reach exit: {}",
+                                err
+                            )
                         }
                     }
-                    smol::Timer::after(Duration::from_secs(30)).await;
+                    failures += 1;
+                    smol::Timer::after(policy.backoff(failures)).await;
                 }
             });
             tasks.push(task);
@@ -7,7 +7,7 @@ use std::{
     str::FromStr,
     sync::Arc,
     thread::available_parallelism,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::Context as _;
@@ -18,7 +18,11 @@ use sillad::{
     dialer::DialerExt,
     tcp::{TcpDialer, TcpListener},
 };
-use sillad_sosistab3::{listener::SosistabListener, Cookie};
+use sillad_sosistab3::{
+    listener::{CookieSet, SosistabListener},
+    replay::{FileReplayCache, MemoryReplayCache, ReplayCache},
+    Cookie,
+};
 use smol::future::FutureExt as _;
 
 use smol_timeout2::TimeoutExt;
@@ -27,13 +31,35 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilte
 #[global_allocator]
 static ALLOC: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
-fn main() {
+/// How often the control cookie is replaced by a fresh one.
+const COOKIE_ROTATION_INTERVAL: Duration = Duration::from_secs(6 * 3600);
+
+/// How long a replaced control cookie keeps working. This comfortably covers the time until the broker sees the new one.
+const COOKIE_ROTATION_GRACE: Duration = Duration::from_secs(600);
+
+/// How many handshakes the shared replay cache file has room for.
+const REPLAY_CACHE_SLOTS: usize = 1 << 16;
+
+fn main() -> anyhow::Result<()> {
+    // probers of the control port can be shown some other service instead
+    let fallback: Option<SocketAddr> = std::env::var("GEPH5_BRIDGE_FALLBACK")
+        .ok()
+        .map(|fallback| fallback.parse())
+        .transpose()
+        .context("GEPH5_BRIDGE_FALLBACK must be a socket address")?;
+
     if std::env::var("GEPH5_BRIDGE_POOL")
         .unwrap()
         .contains("yaofan")
     {
         smolscale::permanently_single_threaded();
         if std::env::var("GEPH5_BRIDGE_CHILD").is_err() {
+            // the children all listen on this host, so they all share one replay cache. the path is stable, so that restarts reuse the same file rather than leaving a new one behind every time
+            if std::env::var("GEPH5_BRIDGE_REPLAY_CACHE").is_err() {
+                let path =
+                    std::env::temp_dir().join(format!("geph5-bridge-replay-{REPLAY_CACHE_SLOTS}"));
+                std::env::set_var("GEPH5_BRIDGE_REPLAY_CACHE", path);
+            }
             for _ in 0..available_parallelism().unwrap().get() {
                 std::thread::spawn(|| {
                     std::env::set_var("GEPH5_BRIDGE_CHILD", "1");
@@ -69,7 +95,7 @@ fn main() {
                 .from_env_lossy(),
         )
         .init();
-    smolscale::block_on(async {
+    smolscale::block_on(async move {
         let my_ip = IpAddr::from_str(
             String::from_utf8_lossy(
                 &reqwest::get("https://checkip.amazonaws.com/")
@@ -85,17 +111,38 @@ fn main() {
 
         let port = rand::thread_rng().gen_range(1024..10000);
         let control_listen = SocketAddr::new(my_ip, port);
-        let control_cookie = format!("bridge-cookie-{}", rand::random::<u128>());
+        let control_cookie = new_control_cookie();
+        let cookies = CookieSet::new(Cookie::new(&control_cookie));
+
+        let replay_cache: Arc<dyn ReplayCache> = match std::env::var("GEPH5_BRIDGE_REPLAY_CACHE") {
+            Ok(path) => match FileReplayCache::open(&path, REPLAY_CACHE_SLOTS) {
+                Ok(cache) => Arc::new(cache),
+                Err(err) => {
+                    tracing::warn!(
+                        path,
+                        err = debug(err),
+                        "cannot open the shared replay cache, so falling back to one in memory"
+                    );
+                    Arc::new(MemoryReplayCache::default())
+                }
+            },
+            Err(_) => Arc::new(MemoryReplayCache::default()),
+        };
 
-        let upload_loop = broker_loop(control_listen, control_cookie.clone());
+        let upload_loop = broker_loop(control_listen, control_cookie, cookies.clone());
         let listen_loop = async {
             loop {
                 let listener = TcpListener::bind(format!("0.0.0.0:{port}").parse().unwrap())
                     .await
                     .unwrap();
 
-                let control_listener =
-                    SosistabListener::new(listener, Cookie::new(&control_cookie));
+                let fallback = fallback.map(|dest_addr| TcpDialer { dest_addr }.dynamic());
+                let control_listener = SosistabListener::new_with_replay_cache(
+                    listener,
+                    cookies.clone(),
+                    fallback,
+                    replay_cache.clone(),
+                );
                 if let Err(err) = listen_forward_loop(my_ip, control_listener).await {
                     tracing::error!(err = %err, "error in listen_forward_loop");
                 }
@@ -103,10 +150,15 @@ fn main() {
             }
         };
         upload_loop.race(listen_loop).await
-    })
+    });
+    Ok(())
+}
+
+fn new_control_cookie() -> String {
+    format!("bridge-cookie-{}", rand::random::<u128>())
 }
 
-async fn broker_loop(control_listen: SocketAddr, control_cookie: String) {
+async fn broker_loop(control_listen: SocketAddr, mut control_cookie: String, cookies: CookieSet) {
     let auth_token = std::env::var("GEPH5_BRIDGE_TOKEN").unwrap();
     let pool = std::env::var("GEPH5_BRIDGE_POOL").unwrap();
     let broker_addr: SocketAddr = std::env::var("GEPH5_BROKER_ADDR").unwrap().parse().unwrap();
@@ -127,7 +179,20 @@ async fn broker_loop(control_listen: SocketAddr, control_cookie: String) {
         ),
     ));
 
+    let mut last_rotation = Instant::now();
     loop {
+        if last_rotation.elapsed() >= COOKIE_ROTATION_INTERVAL {
+            // the broker may still use the old cookie until it sees our next upload
+            let old_cookie = std::mem::replace(&mut control_cookie, new_control_cookie());
+            cookies.add(Cookie::new(&control_cookie), None);
+            cookies.add(
+                Cookie::new(&old_cookie),
+                Some(SystemTime::now() + COOKIE_ROTATION_GRACE),
+            );
+            last_rotation = Instant::now();
+            tracing::info!("rotated the control cookie");
+        }
+
         tracing::info!(
             auth_token,
             broker_addr = display(broker_addr),
@@ -62,19 +62,22 @@ pub struct ExitRow {
     pub city: String,
     pub load: f32,
     pub expiry: i64,
+    /// Added by `alter table exits_new add column hybrid_kex boolean not null default false`.
+    pub hybrid_kex: bool,
 }
 
 pub async fn insert_exit(exit: &ExitRow) -> anyhow::Result<()> {
     sqlx::query(
-        r"INSERT INTO exits_new (pubkey, c2e_listen, b2e_listen, country, city, load, expiry)
-        VALUES ($1, $2, $3, $4, $5, $6, extract(epoch from now()) + ($7 - extract(epoch from now())))
+        r"INSERT INTO exits_new (pubkey, c2e_listen, b2e_listen, country, city, load, expiry, hybrid_kex)
+        VALUES ($1, $2, $3, $4, $5, $6, extract(epoch from now()) + ($7 - extract(epoch from now())), $8)
         ON CONFLICT (pubkey) DO UPDATE 
         SET c2e_listen = EXCLUDED.c2e_listen, 
             b2e_listen = EXCLUDED.b2e_listen, 
             country = EXCLUDED.country, 
             city = EXCLUDED.city, 
             load = EXCLUDED.load, 
-            expiry = extract(epoch from now()) + (EXCLUDED.expiry - extract(epoch from now()))
+            expiry = extract(epoch from now()) + (EXCLUDED.expiry - extract(epoch from now())),
+            hybrid_kex = EXCLUDED.hybrid_kex
         ",
     )
     .bind(exit.pubkey)
@@ -84,6 +87,7 @@ pub async fn insert_exit(exit: &ExitRow) -> anyhow::Result<()> {
     .bind(&exit.city)
     .bind(exit.load)
     .bind(exit.expiry)
+    .bind(exit.hybrid_kex)
     .execute(POSTGRES.deref())
     .await?;
     Ok(())
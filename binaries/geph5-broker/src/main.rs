@@ -11,8 +11,11 @@ use once_cell::sync::{Lazy, OnceCell};
 use rpc_impl::WrappedBrokerService;
 use self_stat::self_stat_loop;
 use serde::Deserialize;
+use sillad_sosistab3::ObfsParams;
 use smolscale::immortal::{Immortal, RespawnStrategy};
-use std::{fmt::Debug, fs, net::SocketAddr, path::PathBuf, sync::LazyLock};
+use std::{
+    collections::HashMap, fmt::Debug, fs, net::SocketAddr, path::PathBuf, sync::LazyLock,
+};
 use tikv_jemallocator::Jemalloc;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
@@ -113,6 +116,10 @@ struct ConfigFile {
     /// Optional InfluxDB configuration for metrics
     #[serde(default)]
     influxdb: Option<InfluxDbEndpoint>,
+
+    /// Obfuscation parameters for the sosistab3 cookies handed out for each bridge pool. When a pool lists several, each route picks one at random, so that they can be compared against each other. Pools not listed get cookies without length or timing obfuscation.
+    #[serde(default)]
+    bridge_obfs: HashMap<String, Vec<ObfsParams>>,
 }
 
 fn default_puzzle_difficulty() -> u16 {
@@ -6,7 +6,8 @@ use geph5_misc_rpc::bridge::{B2eMetadata, BridgeControlClient, ObfsProtocol};
 use moka::future::Cache;
 use nanorpc_sillad::DialerTransport;
 
-use rand::RngCore;
+use crate::CONFIG_FILE;
+use rand::{seq::SliceRandom, RngCore};
 use sillad::tcp::TcpDialer;
 use sillad_sosistab3::{dialer::SosistabDialer, Cookie};
 use smol_timeout2::TimeoutExt;
@@ -47,7 +48,11 @@ pub async fn bridge_to_leaf_route(
                     bridge.clone(),
                     exit_b2e,
                     ObfsProtocol::ConnTest(
-                        ObfsProtocol::Sosistab3New(gencookie(), ObfsProtocol::None.into()).into(),
+                        ObfsProtocol::Sosistab3New(
+                            gencookie(&bridge.pool),
+                            ObfsProtocol::None.into(),
+                        )
+                        .into(),
                     ),
                 )
                 .await?;
@@ -84,10 +89,19 @@ pub async fn bridge_to_leaf_route(
         .map_err(|e| anyhow::anyhow!(e))
 }
 
-fn gencookie() -> String {
+fn gencookie(pool: &str) -> String {
     let mut b = [0u8; 16];
     rand::thread_rng().fill_bytes(&mut b);
-    hex::encode(b)
+    let cookie = hex::encode(b);
+    match CONFIG_FILE
+        .wait()
+        .bridge_obfs
+        .get(pool)
+        .and_then(|choices| choices.choose(&mut rand::thread_rng()))
+    {
+        Some(params) => format!("{cookie}---{}", serde_json::to_string(params).unwrap()),
+        None => cookie,
+    }
 }
 
 async fn bridge_to_leaf_route_inner(
@@ -141,5 +155,9 @@ fn protocol_to_descriptor(protocol: ObfsProtocol, addr: SocketAddr) -> RouteDesc
             cookie,
             lower: protocol_to_descriptor(*obfs_protocol, addr).into(),
         },
+        ObfsProtocol::Websocket(obfs_protocol) => RouteDescriptor::Websocket {
+            url: "ws://labooyah-squish.be/".into(),
+            lower: protocol_to_descriptor(*obfs_protocol, addr).into(),
+        },
     }
 }
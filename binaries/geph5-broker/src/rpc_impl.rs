@@ -6,8 +6,8 @@ use ed25519_dalek::VerifyingKey;
 use futures_util::{future::join_all, TryFutureExt};
 use geph5_broker_protocol::{
     AccountLevel, AuthError, AvailabilityData, BridgeDescriptor, BrokerProtocol, BrokerService,
-    Credential, ExitDescriptor, ExitList, GenericError, Mac, NewsItem, RouteDescriptor, Signed,
-    UserInfo, VoucherInfo, DOMAIN_EXIT_DESCRIPTOR,
+    Credential, ExitDescriptor, ExitFeatures, ExitList, GenericError, Mac, NewsItem,
+    RouteDescriptor, Signed, UserInfo, VoucherInfo, DOMAIN_EXIT_DESCRIPTOR, DOMAIN_EXIT_FEATURES,
 };
 use influxdb_line_protocol::LineProtocolBuilder;
 use isocountry::CountryCode;
@@ -82,7 +82,11 @@ struct BrokerImpl {}
 
 impl BrokerImpl {
     async fn get_all_exits(&self) -> Result<ExitList, GenericError> {
-        static EXIT_CACHE: Lazy<Cache<(), ExitList>> = Lazy::new(|| {
+        Ok(self.get_all_exits_and_features().await?.0)
+    }
+
+    async fn get_all_exits_and_features(&self) -> Result<(ExitList, ExitFeatures), GenericError> {
+        static EXIT_CACHE: Lazy<Cache<(), (ExitList, ExitFeatures)>> = Lazy::new(|| {
             Cache::builder()
                 .time_to_live(Duration::from_secs(10))
                 .build()
@@ -90,31 +94,45 @@ impl BrokerImpl {
 
         let exit_list = EXIT_CACHE
             .try_get_with((), async {
-                let exits: Vec<(VerifyingKey, ExitDescriptor)> =
-                    sqlx::query_as("select * from exits_new")
-                        .fetch_all(POSTGRES.deref())
-                        .await?
-                        .into_iter()
-                        .map(|row: ExitRow| {
-                            (
-                                VerifyingKey::from_bytes(&row.pubkey).unwrap(),
-                                ExitDescriptor {
-                                    c2e_listen: row.c2e_listen.parse().unwrap(),
-                                    b2e_listen: row.b2e_listen.parse().unwrap(),
-                                    country: CountryCode::for_alpha2_caseless(&row.country)
-                                        .unwrap(),
-                                    city: row.city,
-                                    load: row.load,
-                                    expiry: row.expiry as _,
-                                },
-                            )
-                        })
-                        .collect();
+                let rows: Vec<ExitRow> = sqlx::query_as("select * from exits_new")
+                    .fetch_all(POSTGRES.deref())
+                    .await?;
+                let features = ExitFeatures {
+                    hybrid_kex: rows
+                        .iter()
+                        .filter(|row| row.hybrid_kex)
+                        .map(|row| VerifyingKey::from_bytes(&row.pubkey).unwrap())
+                        .collect(),
+                    // comfortably longer than the cache, yet short enough that an old list cannot be replayed for long
+                    expiry: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs()
+                        + 600,
+                };
+                let exits: Vec<(VerifyingKey, ExitDescriptor)> = rows
+                    .into_iter()
+                    .map(|row: ExitRow| {
+                        (
+                            VerifyingKey::from_bytes(&row.pubkey).unwrap(),
+                            ExitDescriptor {
+                                c2e_listen: row.c2e_listen.parse().unwrap(),
+                                b2e_listen: row.b2e_listen.parse().unwrap(),
+                                country: CountryCode::for_alpha2_caseless(&row.country).unwrap(),
+                                city: row.city,
+                                load: row.load,
+                                expiry: row.expiry as _,
+                                // served through get_exit_features instead, so that older clients can verify the list
+                                hybrid_kex: false,
+                            },
+                        )
+                    })
+                    .collect();
                 let exit_list = ExitList {
                     all_exits: exits,
                     city_names: serde_yaml::from_str(include_str!("city_names.yaml")).unwrap(),
                 };
-                Ok(exit_list)
+                Ok((exit_list, features))
             })
             .await
             .map_err(|e: Arc<GenericError>| e.deref().clone())?;
@@ -202,6 +220,15 @@ impl BrokerProtocol for BrokerImpl {
         ))
     }
 
+    async fn get_exit_features(&self) -> Result<Signed<ExitFeatures>, GenericError> {
+        let (_, features) = self.get_all_exits_and_features().await?;
+        Ok(Signed::new(
+            features,
+            DOMAIN_EXIT_FEATURES,
+            MASTER_SECRET.deref(),
+        ))
+    }
+
     async fn get_free_exits(&self) -> Result<Signed<ExitList>, GenericError> {
         let mut exit_list = self.get_all_exits().await?;
         exit_list.all_exits.retain(|(_, e)| !is_plus_exit(e));
@@ -312,6 +339,7 @@ impl BrokerProtocol for BrokerImpl {
             city: descriptor.city.clone(),
             load: descriptor.load,
             expiry: (now + 10) as _,
+            hybrid_kex: descriptor.hybrid_kex,
         };
         insert_exit(&exit).await?;
         Ok(())
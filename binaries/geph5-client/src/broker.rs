@@ -16,7 +16,10 @@ use serde::{Deserialize, Serialize};
 use sillad::tcp::TcpDialer;
 use std::net::SocketAddr;
 
-use crate::client::{Config, CtxField};
+use crate::{
+    client::{Config, CtxField},
+    upstream_proxy::UpstreamProxy,
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
@@ -39,19 +42,29 @@ pub enum BrokerSource {
 impl BrokerSource {
     /// Converts to a RpcTransport.
     pub fn rpc_transport(&self) -> DynRpcTransport {
+        self.rpc_transport_through(None)
+    }
+
+    /// Converts to a RpcTransport that makes all its connections through the given upstream proxy, if any.
+    pub fn rpc_transport_through(&self, upstream: Option<&UpstreamProxy>) -> DynRpcTransport {
         match self {
             BrokerSource::Direct(s) => DynRpcTransport::new(FrontedHttpTransport {
                 url: s.clone(),
                 host: None,
+                upstream: upstream.cloned(),
             }),
-            BrokerSource::DirectTcp(dest_addr) => {
-                DynRpcTransport::new(nanorpc_sillad::DialerTransport(TcpDialer {
+            BrokerSource::DirectTcp(dest_addr) => match upstream {
+                Some(upstream) => DynRpcTransport::new(nanorpc_sillad::DialerTransport(
+                    upstream.dialer(dest_addr.to_string()),
+                )),
+                None => DynRpcTransport::new(nanorpc_sillad::DialerTransport(TcpDialer {
                     dest_addr: *dest_addr,
-                }))
-            }
+                })),
+            },
             BrokerSource::Fronted { front, host } => DynRpcTransport::new(FrontedHttpTransport {
                 url: front.clone(),
                 host: Some(host.clone()),
+                upstream: upstream.cloned(),
             }),
             BrokerSource::AwsLambda {
                 function_name,
@@ -63,11 +76,12 @@ impl BrokerSource {
                 region: region.clone(),
                 access_key_id: access_key_id.clone(),
                 secret_access_key: secret_access_key.clone(),
+                upstream: upstream.cloned(),
             }),
             BrokerSource::Race(race_between) => {
                 let transports = race_between
                     .iter()
-                    .map(|bs| bs.rpc_transport())
+                    .map(|bs| bs.rpc_transport_through(upstream))
                     .collect_vec();
                 DynRpcTransport::new(RaceTransport::new(transports))
             }
@@ -82,8 +96,12 @@ pub fn broker_client(ctx: &AnyCtx<Config>) -> anyhow::Result<&BrokerClient> {
 }
 
 static BROKER_CLIENT: CtxField<Option<BrokerClient>> = |ctx| {
+    let upstream = ctx.init().upstream_proxy.as_ref();
+    if let Some(upstream) = upstream {
+        upstream.whitelist(ctx);
+    }
     ctx.init()
         .broker
         .as_ref()
-        .map(|src| BrokerClient::from(src.rpc_transport()))
+        .map(|src| BrokerClient::from(src.rpc_transport_through(upstream)))
 };
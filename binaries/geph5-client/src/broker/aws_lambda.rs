@@ -1,18 +1,29 @@
-use std::time::Instant;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+    time::Instant,
+};
 
 use anyhow::Context;
+use async_compat::Compat;
 use async_trait::async_trait;
 use aws_config::BehaviorVersion;
 use aws_sdk_lambda::{config::Credentials, primitives::Blob};
 use aws_smithy_runtime::client::http::hyper_014::HyperClientBuilder;
+use hyper014::client::connect::{Connected, Connection};
 use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
 use serde::Deserialize;
+use sillad::{dialer::Dialer, Pipe};
+
+use crate::upstream_proxy::UpstreamProxy;
 
 pub struct AwsLambdaTransport {
     pub function_name: String,
     pub region: String,
     pub access_key_id: String,
     pub secret_access_key: String,
+    pub upstream: Option<UpstreamProxy>,
 }
 
 #[async_trait]
@@ -27,11 +38,15 @@ impl RpcTransport for AwsLambdaTransport {
         let tls_connector = hyper_rustls::HttpsConnectorBuilder::new()
             .with_webpki_roots()
             .https_only()
-            .enable_http1()
-            .build();
+            .enable_http1();
 
-        // Create a hyper-based HTTP client that uses this TLS connector.
-        let http_client = HyperClientBuilder::new().build(tls_connector);
+        // Create a hyper-based HTTP client that uses this TLS connector, on top of the upstream proxy if there is one.
+        let http_client = if let Some(upstream) = &self.upstream {
+            HyperClientBuilder::new()
+                .build(tls_connector.wrap_connector(ProxyConnector(upstream.clone())))
+        } else {
+            HyperClientBuilder::new().build(tls_connector.build())
+        };
 
         let client = aws_sdk_lambda::Client::new(
             &aws_config::defaults(BehaviorVersion::v2024_03_28())
@@ -81,3 +96,68 @@ impl RpcTransport for AwsLambdaTransport {
 fn string_to_static_str(s: String) -> &'static str {
     Box::leak(s.into_boxed_str())
 }
+
+/// A hyper connector that opens its TCP connections through an upstream proxy.
+#[derive(Clone)]
+struct ProxyConnector(UpstreamProxy);
+
+impl tower_service::Service<hyper014::Uri> for ProxyConnector {
+    type Response = ProxiedStream;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = std::io::Result<ProxiedStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: hyper014::Uri) -> Self::Future {
+        let upstream = self.0.clone();
+        Box::pin(async move {
+            let host = uri.host().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "URI without a host")
+            })?;
+            let port = uri.port_u16().unwrap_or(443);
+            let pipe = upstream.dialer(format!("{host}:{port}")).dial().await?;
+            Ok(ProxiedStream(Compat::new(pipe)))
+        })
+    }
+}
+
+struct ProxiedStream(Compat<Box<dyn Pipe>>);
+
+impl Connection for ProxiedStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl tokio::io::AsyncRead for ProxiedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for ProxiedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
@@ -4,9 +4,12 @@ use anyhow::Context;
 use async_trait::async_trait;
 use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
 
+use crate::upstream_proxy::UpstreamProxy;
+
 pub struct FrontedHttpTransport {
     pub url: String,
     pub host: Option<String>,
+    pub upstream: Option<UpstreamProxy>,
 }
 
 #[async_trait]
@@ -15,7 +18,14 @@ impl RpcTransport for FrontedHttpTransport {
     async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
         tracing::debug!(method = req.method, "calling broker through http");
         let start = Instant::now();
-        let mut request_builder = reqwest::Client::new()
+        let client = if let Some(upstream) = &self.upstream {
+            reqwest::Client::builder()
+                .proxy(upstream.reqwest_proxy()?)
+                .build()?
+        } else {
+            reqwest::Client::new()
+        };
+        let mut request_builder = client
             .post(&self.url)
             .header("content-type", "application/json");
 
@@ -25,6 +25,7 @@ use crate::{
     pac::pac_serve,
     route::ExitConstraint,
     socks5::socks5_loop,
+    upstream_proxy::UpstreamProxy,
     vpn::{recv_vpn_packet, send_vpn_packet, vpn_loop},
 };
 
@@ -35,6 +36,9 @@ pub struct Config {
     pub pac_listen: Option<SocketAddr>,
 
     pub control_listen: Option<SocketAddr>,
+    /// Serves the control protocol on a Unix domain socket at this path, accessible only to the current user.
+    #[serde(default)]
+    pub control_listen_unix: Option<PathBuf>,
     pub exit_constraint: ExitConstraint,
     #[serde(default)]
     pub bridge_mode: BridgeMode,
@@ -42,6 +46,12 @@ pub struct Config {
 
     pub broker: Option<BrokerSource>,
     pub broker_keys: Option<BrokerKeys>,
+    /// Sends all of our own connections, including those to the broker, through this existing proxy.
+    #[serde(default)]
+    pub upstream_proxy: Option<UpstreamProxy>,
+    /// Sets `SO_MARK` on our own TCP connections to bridges, exits, and the upstream proxy, so that policy routing can keep them out of the VPN. Linux and Android only.
+    #[serde(default)]
+    pub socket_mark: Option<u32>,
 
     #[serde(default)]
     pub vpn: bool,
@@ -78,6 +88,7 @@ impl Config {
         this.http_proxy_listen = None;
         this.pac_listen = None;
         this.control_listen = None;
+        this.control_listen_unix = None;
         this
     }
 }
@@ -262,6 +273,21 @@ async fn client_main(ctx: AnyCtx<Config>) -> anyhow::Result<()> {
                 smol::future::pending().await
             }
         };
+        let rpc_serve_unix = async {
+            #[cfg(unix)]
+            if let Some(control_listen_unix) = &ctx.init().control_listen_unix {
+                // the control socket must never be reachable by other local users, not even briefly
+                let listener =
+                    sillad::unix::UnixListener::bind_with_mode(control_listen_unix, 0o600).await?;
+                nanorpc_sillad::rpc_serve(
+                    listener,
+                    ControlService(ControlProtocolImpl { ctx: ctx.clone() }),
+                )
+                .await?;
+                return anyhow::Ok(());
+            }
+            smol::future::pending().await
+        };
 
         let vpn_loop = vpn_loop(&ctx);
 
@@ -279,6 +305,7 @@ async fn client_main(ctx: AnyCtx<Config>) -> anyhow::Result<()> {
                     .inspect_err(|e| tracing::error!(err = debug(e), "auth loop stopped")),
             )
             .race(rpc_serve)
+            .race(rpc_serve_unix)
             .race(pac_serve(&ctx))
             .await
     }
@@ -3,16 +3,26 @@ use anyhow::Context;
 use bytes::Bytes;
 use clone_macro::clone;
 use ed25519_dalek::VerifyingKey;
-use futures_util::{future::join_all, AsyncReadExt as _};
+use futures_util::{
+    future::join_all,
+    io::{ReadHalf, WriteHalf},
+    AsyncReadExt as _, AsyncWriteExt as _,
+};
 use geph5_misc_rpc::{
-    exit::{ClientCryptHello, ClientExitCryptPipe, ClientHello, ExitHello, ExitHelloInner},
+    exit::{
+        hybrid_secret, ClientCryptHello, ClientExitCryptPipe, ClientHello, ExitHello,
+        ExitHelloInner, KemSecret,
+    },
     read_prepend_length, write_prepend_length,
 };
 use nursery_macro::nursery;
 
-use picomux::{LivenessConfig, PicoMux};
+use picomux::{LivenessConfig, PicoMux, Priority};
 use rand::Rng;
-use sillad::{dialer::Dialer as _, EitherPipe, Pipe};
+use sillad::{
+    dialer::{Dialer as _, RetryPolicy},
+    EitherPipe, Pipe,
+};
 use smol::future::FutureExt as _;
 use smol_timeout2::TimeoutExt;
 use std::{
@@ -36,19 +46,7 @@ pub async fn open_conn(
     protocol: &str,
     dest_addr: &str,
 ) -> anyhow::Result<Box<dyn sillad::Pipe>> {
-    let dest_addr = if let Ok(sock_addr) = SocketAddr::from_str(dest_addr) {
-        if let IpAddr::V4(v4) = sock_addr.ip() {
-            if let Some(orig) = fake_dns_backtranslate(ctx, v4) {
-                format!("{orig}:{}", sock_addr.port())
-            } else {
-                dest_addr.to_string()
-            }
-        } else {
-            dest_addr.to_string()
-        }
-    } else {
-        dest_addr.to_string()
-    };
+    let dest_addr = backtranslate_addr(ctx, dest_addr);
 
     if let Some((dest_host, _)) = dest_addr.rsplit_once(":") {
         if whitelist_host(ctx, dest_host) {
@@ -64,6 +62,118 @@ pub async fn open_conn(
         }
     }
 
+    Ok(Box::new(open_tunnel(ctx, protocol, &dest_addr).await?))
+}
+
+/// Like [`open_conn`], but waits until the exit has actually reached the destination, so that a
+/// refusal surfaces here as a [`picomux::StreamReset`] rather than as the connection closing later.
+pub async fn open_conn_accepted(
+    ctx: &AnyCtx<Config>,
+    protocol: &str,
+    dest_addr: &str,
+) -> anyhow::Result<Box<dyn sillad::Pipe>> {
+    let dest_addr = backtranslate_addr(ctx, dest_addr);
+    if is_passthrough(ctx, &dest_addr) {
+        return open_conn(ctx, protocol, &dest_addr).await;
+    }
+    let stream = open_tunnel(ctx, protocol, &dest_addr).await?;
+    stream.wait_accepted().await?;
+    Ok(Box::new(stream))
+}
+
+/// Opens a tunneled UDP flow. Whitelisted destinations go through [`open_conn`] as before.
+pub async fn open_udp(ctx: &AnyCtx<Config>, dest_addr: &str) -> anyhow::Result<UdpConn> {
+    let dest_addr = backtranslate_addr(ctx, dest_addr);
+    if is_passthrough(ctx, &dest_addr) {
+        return Ok(UdpConn::framed(open_conn(ctx, "udp", &dest_addr).await?));
+    }
+    let stream = open_tunnel(ctx, "udp", &dest_addr).await?;
+    if stream.metadata().starts_with(b"dgram$") {
+        Ok(UdpConn::Datagram(Box::new(stream)))
+    } else {
+        Ok(UdpConn::framed(Box::new(stream)))
+    }
+}
+
+/// A tunneled UDP flow, as returned by [`open_udp`].
+pub enum UdpConn {
+    /// Packets travel as picomux datagrams, and are dropped rather than queued under load.
+    Datagram(Box<picomux::Stream>),
+    /// Packets are length-prefixed within a reliable stream, for exits that predate datagrams.
+    Framed {
+        read: smol::lock::Mutex<ReadHalf<Box<dyn Pipe>>>,
+        write: smol::lock::Mutex<WriteHalf<Box<dyn Pipe>>>,
+    },
+}
+
+impl UdpConn {
+    fn framed(pipe: Box<dyn Pipe>) -> Self {
+        let (read, write) = pipe.split();
+        Self::Framed {
+            read: smol::lock::Mutex::new(read),
+            write: smol::lock::Mutex::new(write),
+        }
+    }
+
+    /// Sends a packet through the tunnel.
+    pub async fn send(&self, packet: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Self::Datagram(stream) => {
+                if !stream.send_datagram(packet) {
+                    tracing::trace!(len = packet.len(), "dropping UDP packet under backpressure");
+                }
+            }
+            Self::Framed { write, .. } => {
+                let mut write = write.lock().await;
+                write.write_all(&(packet.len() as u16).to_le_bytes()).await?;
+                write.write_all(packet).await?;
+                write.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Receives a packet from the tunnel.
+    pub async fn recv(&self) -> anyhow::Result<Bytes> {
+        match self {
+            Self::Datagram(stream) => Ok(stream.recv_datagram().await?),
+            Self::Framed { read, .. } => {
+                let mut read = read.lock().await;
+                let mut len_buf = [0u8; 2];
+                read.read_exact(&mut len_buf).await?;
+                let mut buf = vec![0u8; u16::from_le_bytes(len_buf) as usize];
+                read.read_exact(&mut buf).await?;
+                Ok(buf.into())
+            }
+        }
+    }
+}
+
+/// Whether [`open_conn`] connects to this address directly rather than through an exit.
+fn is_passthrough(ctx: &AnyCtx<Config>, dest_addr: &str) -> bool {
+    dest_addr
+        .rsplit_once(":")
+        .is_some_and(|(dest_host, _)| whitelist_host(ctx, dest_host))
+}
+
+/// Maps fake DNS addresses back to the hostnames they stand for.
+fn backtranslate_addr(ctx: &AnyCtx<Config>, dest_addr: &str) -> String {
+    if let Ok(sock_addr) = SocketAddr::from_str(dest_addr) {
+        if let IpAddr::V4(v4) = sock_addr.ip() {
+            if let Some(orig) = fake_dns_backtranslate(ctx, v4) {
+                return format!("{orig}:{}", sock_addr.port());
+            }
+        }
+    }
+    dest_addr.to_string()
+}
+
+/// Opens a stream to the destination through whichever session picks up the request.
+async fn open_tunnel(
+    ctx: &AnyCtx<Config>,
+    protocol: &str,
+    dest_addr: &str,
+) -> anyhow::Result<picomux::Stream> {
     let (send, recv) = oneshot::channel();
     let elem = (format!("{protocol}${dest_addr}"), send);
     let _ = ctx.get(CONN_REQ_CHAN).0.send(elem).await;
@@ -77,7 +187,7 @@ pub async fn open_conn(
         stat_incr_num(&ctx, "total_tx_bytes", n as _);
         ctx.get(TRAFF_COUNT).write().unwrap().incr(n as _);
     }));
-    Ok(Box::new(conn))
+    Ok(conn)
 }
 
 
@@ -117,6 +227,9 @@ static CONN_REQ_CHAN: CtxField<(
 
 pub static CONCURRENCY: usize = 3;
 
+/// How often each session reports its mux stats.
+const MUX_STATS_INTERVAL: Duration = Duration::from_secs(5);
+
 #[tracing::instrument(skip_all)]
 pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
     tracing::info!("(re)starting main logic");
@@ -131,6 +244,14 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
 
         let ctx = ctx.clone();
         smolscale::spawn(async move {
+            let backoff = RetryPolicy {
+                max_attempts: None,
+                initial_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(60),
+                multiplier: 2.0,
+                jitter: 0.5,
+            };
+            let mut failures = 0;
             loop {
                 let once = async {
                     *ctx.get(CURRENT_CONN_INFO).lock() = ConnInfo::Connecting;
@@ -144,7 +265,7 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
                             "dial completed"
                         );
 
-                        let authed_pipe = client_auth(&ctx, raw_pipe, pubkey)
+                        let authed_pipe = client_auth(&ctx, raw_pipe, pubkey, exit.hybrid_kex)
                             .await
                             .context("could not client auth")?;
 
@@ -166,6 +287,7 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
                             .unwrap_or_default(),
                         exit: exit.clone(),
                     });
+                    failures = 0;
                     let addr: SocketAddr = authed_pipe.remote_addr().unwrap_or("").parse()?;
                     proxy_loop(ctx.clone(), authed_pipe, instance)
                         .await
@@ -173,7 +295,8 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
 
                 };
                 if let Err(err) = once.await {
-                    let wait_time = Duration::from_secs_f64(rand::thread_rng().gen_range(1.0..10.0));
+                    failures += 1;
+                    let wait_time = backoff.backoff(failures);
                     tracing::warn!(instance, err = debug(err), wait_time=debug(wait_time), "individual client thread failed");
                     smol::Timer::after(wait_time).await;
                 }
@@ -219,12 +342,16 @@ async fn proxy_loop(
                 let mux = mux.clone();
                 let ctx = ctx.clone();
                 let (remote_addr, send_back) = ctx.get(CONN_REQ_CHAN).1.recv().await?;
-                if let Some(latency) = mux.last_latency() {
-                    stat_set_num(&ctx, "ping", latency.as_secs_f64());
-                }
                 spawn!(async move {
                     tracing::debug!(remote_addr = display(&remote_addr), "opening tunnel");
-                    let stream = mux.open(remote_addr.as_bytes()).await;
+                    // UDP flows become datagram flows once we know the exit won't choke on them
+                    let metadata = match remote_addr.strip_prefix("udp$") {
+                        Some(addr) if mux.peer_supports_datagrams() => format!("dgram${addr}"),
+                        _ => remote_addr.clone(),
+                    };
+                    let stream = mux
+                        .open_with_priority(metadata.as_bytes(), stream_priority(&remote_addr))
+                        .await;
                     match stream {
                         Ok(stream) => {
                             let _ = send_back.send(stream);
@@ -241,14 +368,45 @@ async fn proxy_loop(
             }
         })
     }.or(mux.wait_until_dead())
+    .or(async {
+        // sampled on a timer, so that an idle session still reports how it's doing
+        loop {
+            let stats = mux.stats();
+            if let Some(latency) = stats.ping_history.last() {
+                stat_set_num(&ctx, "ping", latency.as_secs_f64());
+            }
+            stat_set_num(&ctx, "mux_open_streams", stats.open_streams as f64);
+            stat_set_num(&ctx, "mux_queued_frames", stats.queued_frames as f64);
+            stat_set_num(&ctx, "mux_bw_estimate", stats.bw_estimate);
+            smol::Timer::after(MUX_STATS_INTERVAL).await;
+        }
+    })
+    .or(async {
+        let deadline = mux.wait_peer_goaway().await;
+        tracing::info!(instance, "exit is going away, starting a replacement session");
+        // the streams still on the old session get to finish in the background
+        let mux = mux.clone();
+        smolscale::spawn(async move { mux.shutdown_gracefully(deadline).await }).detach();
+        anyhow::Ok(())
+    })
     .await
 }
 
+/// DNS and UDP flows are mostly small, latency-sensitive packets, so they go ahead of bulk TCP transfers sharing the session.
+fn stream_priority(remote_addr: &str) -> Priority {
+    if remote_addr.starts_with("udp$") || remote_addr.ends_with(":53") {
+        Priority::Interactive
+    } else {
+        Priority::Normal
+    }
+}
+
 #[tracing::instrument(skip_all, fields(pubkey = hex::encode(pubkey.as_bytes())))]
 async fn client_auth(
     ctx: &AnyCtx<Config>,
     mut pipe: impl Pipe,
     pubkey: VerifyingKey,
+    hybrid: bool,
 ) -> anyhow::Result<impl Pipe> {
     let server = pipe.remote_addr().unwrap_or("").to_string();
 
@@ -288,11 +446,21 @@ async fn client_auth(
             }
         }
         None => {
-            tracing::debug!(server, "requiring full authentication");
+            // whether to go hybrid comes from the broker-signed exit features, never from how the exit reacts, so that nobody on the path can talk us out of it
+            tracing::debug!(server, hybrid, "requiring full authentication");
             let my_esk = x25519_dalek::EphemeralSecret::random_from_rng(rand::thread_rng());
+            let (kem_secret, crypt_hello) = if hybrid {
+                let (kem_secret, encap_key) = KemSecret::generate();
+                (
+                    Some(kem_secret),
+                    ClientCryptHello::X25519MlKem768((&my_esk).into(), encap_key),
+                )
+            } else {
+                (None, ClientCryptHello::X25519((&my_esk).into()))
+            };
             let client_hello = ClientHello {
                 credentials,
-                crypt_hello: ClientCryptHello::X25519((&my_esk).into()),
+                crypt_hello,
             };
             write_prepend_length(&client_hello.stdcode(), &mut pipe).await?;
             tracing::trace!(server, "wrote client hello");
@@ -315,6 +483,9 @@ async fn client_auth(
                     )
                 }
                 ExitHelloInner::X25519(their_epk) => {
+                    if hybrid {
+                        anyhow::bail!("exit answered our hybrid hello with plain X25519")
+                    }
                     let shared_secret = my_esk.diffie_hellman(&their_epk);
                     let read_key = blake3::derive_key("e2c", shared_secret.as_bytes());
                     let write_key = blake3::derive_key("c2e", shared_secret.as_bytes());
@@ -322,6 +493,20 @@ async fn client_auth(
                         pipe, read_key, write_key,
                     )))
                 }
+                ExitHelloInner::X25519MlKem768(their_epk, ciphertext) => {
+                    let kem_secret = kem_secret
+                        .context("exit sent a hybrid response to our X25519 hello")?
+                        .decapsulate(&ciphertext)?;
+                    let shared_secret = hybrid_secret(
+                        my_esk.diffie_hellman(&their_epk).as_bytes(),
+                        &kem_secret,
+                    );
+                    let read_key = blake3::derive_key("e2c", &shared_secret);
+                    let write_key = blake3::derive_key("c2e", &shared_secret);
+                    Ok(EitherPipe::Right(ClientExitCryptPipe::new(
+                        pipe, read_key, write_key,
+                    )))
+                }
             }
         }
     }
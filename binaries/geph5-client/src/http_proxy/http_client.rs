@@ -12,7 +12,7 @@ use std::future::Future;
 use std::pin::Pin;
 use std::task::{self, Poll};
 
-use crate::{client_inner::open_conn, Config};
+use crate::{client_inner::open_conn_accepted, Config};
 
 use super::address::host_addr;
 use super::rt_compat::HyperRtCompat;
@@ -47,9 +47,14 @@ impl tower_service::Service<Uri> for Connector {
                         let err = Error::new(ErrorKind::Other, "URI must be a valid Address");
                         Err(err)
                     }
-                    Some(addr) => open_conn(&ctx, "tcp", &addr.to_string())
+                    Some(addr) => open_conn_accepted(&ctx, "tcp", &addr.to_string())
                         .await
-                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e))
+                        // keep I/O errors as they are, so that the proxy can see stream resets
+                        .map_err(|e| {
+                            e.downcast::<std::io::Error>().unwrap_or_else(|e| {
+                                std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e)
+                            })
+                        })
                         .map(|c| HyperRtCompat::new(PicomuxConnection(c.compat()))),
                 }
             }
@@ -138,6 +138,19 @@ async fn server_dispatch(
         Some(h) => h,
     };
     if Method::CONNECT == req.method() {
+        // connect before answering, so that a refusal can still become an error status
+        let stream = match open_conn_accepted(&ctx, "tcp", &host.to_string()).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                tracing::trace!(
+                    client_addr = %client_addr,
+                    host = %host,
+                    error = %err,
+                    "CONNECT relay failed"
+                );
+                return Ok(make_relay_error(&host, err.as_ref()));
+            }
+        };
         tracing::trace!(
             method = %req.method(),
             client_addr = %client_addr,
@@ -152,10 +165,7 @@ async fn server_dispatch(
                         host = %host,
                         "CONNECT tunnel upgrade success"
                     );
-                    let stream = open_conn(&ctx, "tcp", &host.to_string()).await;
-                    if let Ok(stream) = stream {
-                        establish_connect_tunnel(upgraded, stream, client_addr).await
-                    }
+                    establish_connect_tunnel(upgraded, stream, client_addr).await
                 }
                 Err(e) => {
                     tracing::info!(
@@ -195,13 +205,7 @@ async fn server_dispatch(
                         error = %err,
                         "HTTP relay failed"
                     );
-                    let mut resp = Response::new(HttpEither::Left(
-                        Full::new(Bytes::from(format!("Relay failed to {}", host)))
-                            .map_err(|_| unreachable!())
-                            .boxed(),
-                    ));
-                    *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                    return Ok(resp);
+                    return Ok(make_relay_error(&host, &err));
                 }
             };
         let res_keep_alive =
@@ -285,9 +289,36 @@ fn make_bad_request() -> Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty
     resp
 }
 
+/// Explains a failure to reach the destination, passing on why the exit refused it if it did.
+fn make_relay_error(
+    host: &Address,
+    err: &(dyn std::error::Error + 'static),
+) -> Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>> {
+    let reset = StreamReset::find(err);
+    let status = match reset.map(|reset| reset.code) {
+        Some(ResetCode::NotAllowed) => StatusCode::FORBIDDEN,
+        Some(ResetCode::TimedOut) => StatusCode::GATEWAY_TIMEOUT,
+        Some(_) => StatusCode::BAD_GATEWAY,
+        None => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let body = match reset {
+        Some(reset) => format!("Relay failed to {}: {}", host, reset.reason),
+        None => format!("Relay failed to {}", host),
+    };
+    let mut resp = Response::new(HttpEither::Left(
+        Full::new(Bytes::from(body))
+            .map_err(|_| unreachable!())
+            .boxed(),
+    ));
+    *resp.status_mut() = status;
+    resp
+}
+
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-use crate::{client_inner::open_conn, Config};
+use picomux::{ResetCode, StreamReset};
+
+use crate::{client_inner::open_conn_accepted, Config};
 
 use self::address::{host_addr, Address};
 fn authority_addr(scheme_str: Option<&str>, authority: &Authority) -> Option<Address> {
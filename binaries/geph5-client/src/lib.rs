@@ -4,6 +4,7 @@ pub use client::Client;
 pub use client::{BridgeMode, BrokerKeys, Config};
 pub use control_prot::{ConnInfo, ControlClient};
 pub use route::ExitConstraint;
+pub use upstream_proxy::UpstreamProxy;
 
 mod auth;
 mod broker;
@@ -23,4 +24,5 @@ mod stats;
 mod taskpool;
 mod traffcount;
 mod updates;
+mod upstream_proxy;
 mod vpn;
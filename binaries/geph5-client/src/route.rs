@@ -1,4 +1,4 @@
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyctx::AnyCtx;
 use anyhow::Context;
@@ -7,17 +7,15 @@ use async_native_tls::TlsConnector;
 use ed25519_dalek::VerifyingKey;
 
 use geph5_broker_protocol::{
-    AccountLevel, ExitDescriptor, RouteDescriptor, DOMAIN_EXIT_DESCRIPTOR,
+    AccountLevel, ExitDescriptor, RouteDescriptor, DOMAIN_EXIT_DESCRIPTOR, DOMAIN_EXIT_FEATURES,
 };
 use isocountry::CountryCode;
 use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
-use sillad::{
-    dialer::{DialerExt, DynDialer, FailingDialer},
-    tcp::TcpDialer,
-};
+use sillad::dialer::{DialerExt, DynDialer, FailingDialer, MultiRaceDialer, RaceMemory};
 use sillad_conntest::ConnTestDialer;
 use sillad_sosistab3::{dialer::SosistabDialer, Cookie};
+use sillad_websocket::WsDialer;
 
 use smol_timeout2::TimeoutExt as _;
 
@@ -25,7 +23,7 @@ use crate::{
     auth::get_connect_token,
     broker::broker_client,
     client::{Config, CtxField},
-    vpn::smart_vpn_whitelist,
+    upstream_proxy::tcp_dialer,
 };
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -98,7 +96,6 @@ async fn get_dialer_inner(
                 .await?
                 .choose(&mut rand::thread_rng())
                 .context("could not resolve destination for direct exit connection")?;
-            smart_vpn_whitelist(ctx, dest_addr.ip());
             return Ok((
                 pubkey,
                 ExitDescriptor {
@@ -108,10 +105,11 @@ async fn get_dialer_inner(
                     city: "".to_string(),
                     load: 0.0,
                     expiry: 0,
+                    hybrid_kex: false,
                 },
                 ConnTestDialer {
                     ping_count: 1,
-                    inner: TcpDialer { dest_addr },
+                    inner: tcp_dialer(ctx, dest_addr),
                 }
                 .dynamic(),
             ));
@@ -148,6 +146,22 @@ async fn get_dialer_inner(
             }
         })
         .context("could not verify")?;
+    // hybrid key exchange support comes separately, so that older clients can still verify the exit list itself
+    let features = broker
+        .get_exit_features()
+        .await?
+        .map_err(|e| anyhow::anyhow!("broker refused to serve exit features: {e}"))?
+        .verify(DOMAIN_EXIT_FEATURES, |their_pk| {
+            if let Some(broker_pk) = &ctx.init().broker_keys {
+                hex::encode(their_pk.as_bytes()) == broker_pk.master
+            } else {
+                true
+            }
+        })
+        .context("could not verify exit features")?;
+    if UNIX_EPOCH + Duration::from_secs(features.expiry) < SystemTime::now() {
+        anyhow::bail!("exit features have expired");
+    }
     // filter for things that fit
     let (pubkey, exit) = if let Some(min) = exits
         .all_exits
@@ -181,15 +195,14 @@ async fn get_dialer_inner(
             .context("no exits that fit the criterion")?
     };
 
+    let mut exit = exit.clone();
+    exit.hybrid_kex = features.hybrid_kex.contains(pubkey);
     tracing::debug!(exit = debug(&exit), "narrowed down choice of exit");
-    smart_vpn_whitelist(ctx, exit.c2e_listen.ip());
 
     let exit_c2e = exit.c2e_listen;
     let direct_dialer = ConnTestDialer {
         ping_count: 2,
-        inner: TcpDialer {
-            dest_addr: exit_c2e,
-        },
+        inner: tcp_dialer(ctx, exit_c2e),
     };
 
     tracing::debug!(token = display(&conn_token), "CONN TOKEN");
@@ -214,7 +227,7 @@ async fn get_dialer_inner(
         crate::BridgeMode::ForceDirect => direct_dialer.dynamic(),
     };
 
-    Ok((*pubkey, exit.clone(), final_dialer))
+    Ok((*pubkey, exit, final_dialer))
 }
 
 // async fn reachability_test(
@@ -303,11 +316,7 @@ async fn get_dialer_inner(
 
 fn route_to_dialer(ctx: &AnyCtx<Config>, route: &RouteDescriptor) -> DynDialer {
     match route {
-        RouteDescriptor::Tcp(addr) => {
-            smart_vpn_whitelist(ctx, addr.ip());
-            let addr = *addr;
-            TcpDialer { dest_addr: addr }.dynamic()
-        }
+        RouteDescriptor::Tcp(addr) => tcp_dialer(ctx, *addr),
         RouteDescriptor::Sosistab3 { cookie, lower } => {
             let inner = route_to_dialer(ctx, lower);
             SosistabDialer {
@@ -316,11 +325,29 @@ fn route_to_dialer(ctx: &AnyCtx<Config>, route: &RouteDescriptor) -> DynDialer {
             }
             .dynamic()
         }
-        RouteDescriptor::Race(inside) => inside
-            .iter()
-            .map(|s| route_to_dialer(ctx, s))
-            .reduce(|a, b| a.race(b).dynamic())
-            .unwrap_or_else(|| FailingDialer.dynamic()),
+        RouteDescriptor::Race(inside) => {
+            // which branches won recently is remembered across rebuilds of the dialer, keyed by the route itself
+            static RACE_MEMORY: CtxField<RaceMemory> = |_| RaceMemory::default();
+            MultiRaceDialer::new_keyed(
+                inside.iter().map(|s| {
+                    let key = serde_json::to_string(s).unwrap_or_default();
+                    match s {
+                        // a top-level delay becomes the start delay of the branch, so that a recent winner can skip it
+                        RouteDescriptor::Delay {
+                            milliseconds,
+                            lower,
+                        } => (
+                            key,
+                            route_to_dialer(ctx, lower),
+                            Duration::from_millis((*milliseconds).into()),
+                        ),
+                        s => (key, route_to_dialer(ctx, s), Duration::ZERO),
+                    }
+                }),
+                ctx.get(RACE_MEMORY).clone(),
+            )
+            .dynamic()
+        }
         RouteDescriptor::Fallback(a) => a
             .iter()
             .map(|s| route_to_dialer(ctx, s))
@@ -364,5 +391,9 @@ fn route_to_dialer(ctx: &AnyCtx<Config>, route: &RouteDescriptor) -> DynDialer {
             )
             .dynamic()
         }
+        RouteDescriptor::Websocket { url, lower } => {
+            let lower = route_to_dialer(ctx, lower);
+            WsDialer::new(lower, url.clone()).dynamic()
+        }
     }
 }
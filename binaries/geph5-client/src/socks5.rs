@@ -1,11 +1,11 @@
-use crate::{client_inner::open_conn, taskpool::add_task};
+use crate::{client_inner::open_conn_accepted, taskpool::add_task};
 
 use anyctx::AnyCtx;
 
-use futures_util::AsyncReadExt as _;
+use futures_util::{AsyncReadExt as _, AsyncWriteExt as _};
 use nursery_macro::nursery;
+use picomux::{ResetCode, StreamReset};
 use sillad::listener::Listener as _;
-use smol::future::FutureExt as _;
 use socksv5::v5::{
     read_handshake, read_request, write_auth_method, write_request_status, SocksV5AuthMethod,
     SocksV5Host, SocksV5RequestStatus,
@@ -41,7 +41,19 @@ pub async fn socks5_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                         remote_addr = display(&remote_addr),
                         "socks5 request received"
                     );
-                    let stream = open_conn(ctx, "tcp", &remote_addr).await?;
+                    let stream = match open_conn_accepted(ctx, "tcp", &remote_addr).await {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            write_request_status(
+                                &mut write_client,
+                                failure_status(&err),
+                                request.host,
+                                port,
+                            )
+                            .await?;
+                            return Err(err);
+                        }
+                    };
                     write_request_status(
                         &mut write_client,
                         SocksV5RequestStatus::Success,
@@ -50,10 +62,19 @@ pub async fn socks5_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                     )
                     .await?;
                     tracing::trace!(remote_addr = display(&remote_addr), "connection opened");
-                    let (read_stream, write_stream) = stream.split();
-                    smol::io::copy(read_stream, write_client)
-                        .race(smol::io::copy(read_client, write_stream))
-                        .await?;
+                    let (read_stream, mut write_stream) = stream.split();
+                    // each direction closes on its own, so that half-closed connections keep working
+                    futures_util::future::try_join(
+                        async {
+                            smol::io::copy(read_stream, &mut write_client).await?;
+                            write_client.close().await
+                        },
+                        async {
+                            smol::io::copy(read_client, &mut write_stream).await?;
+                            write_stream.close().await
+                        },
+                    )
+                    .await?;
                     anyhow::Ok(())
                 });
                 if let Some(task_limit) = ctx.init().task_limit {
@@ -67,3 +88,17 @@ pub async fn socks5_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
         smol::future::pending().await
     }
 }
+
+/// Picks the SOCKS5 reply that best explains why we couldn't connect.
+fn failure_status(err: &anyhow::Error) -> SocksV5RequestStatus {
+    match StreamReset::find(err.as_ref()).map(|reset| reset.code) {
+        Some(ResetCode::NotAllowed) => SocksV5RequestStatus::ConnectionNotAllowed,
+        Some(ResetCode::NetworkUnreachable) => SocksV5RequestStatus::NetworkUnreachable,
+        Some(ResetCode::HostUnreachable) => SocksV5RequestStatus::HostUnreachable,
+        Some(ResetCode::ConnectionRefused) => SocksV5RequestStatus::ConnectionRefused,
+        Some(ResetCode::TimedOut) => SocksV5RequestStatus::TtlExpired,
+        Some(ResetCode::General | ResetCode::Unsupported) | None => {
+            SocksV5RequestStatus::ServerFailure
+        }
+    }
+}
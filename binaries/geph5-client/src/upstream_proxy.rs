@@ -0,0 +1,174 @@
+use std::net::SocketAddr;
+
+use anyctx::AnyCtx;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sillad::{
+    dialer::{Dialer, DialerExt, DynDialer},
+    tcp::{HappyEyeballsTcpDialer, TcpDialer, TcpOptions},
+    Pipe,
+};
+use sillad_proxy::{HttpConnectDialer, ProxyAuth, Socks5Dialer};
+
+use crate::{client::Config, vpn::smart_vpn_whitelist};
+
+/// An existing proxy that every connection the client itself makes, to bridges, exits, and the broker, must go through.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamProxy {
+    Socks5 {
+        addr: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    HttpConnect {
+        addr: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+}
+
+impl UpstreamProxy {
+    /// The `host:port` address of the proxy itself.
+    pub fn addr(&self) -> &str {
+        match self {
+            UpstreamProxy::Socks5 { addr, .. } | UpstreamProxy::HttpConnect { addr, .. } => addr,
+        }
+    }
+
+    /// Whitelists the proxy's own addresses from the VPN. Hostnames are resolved in the background.
+    pub fn whitelist(&self, ctx: &AnyCtx<Config>) {
+        if let Ok(addr) = self.addr().parse::<SocketAddr>() {
+            smart_vpn_whitelist(ctx, addr.ip());
+            return;
+        }
+        let dialer = ProxyServerDialer {
+            addr: self.addr().to_string(),
+            ctx: Some(ctx.clone()),
+        };
+        smolscale::spawn(async move {
+            if let Err(err) = dialer.resolve().await {
+                tracing::warn!(err = debug(err), "could not resolve upstream proxy");
+            }
+        })
+        .detach();
+    }
+
+    fn auth(&self) -> Option<ProxyAuth> {
+        match self {
+            UpstreamProxy::Socks5 {
+                username, password, ..
+            }
+            | UpstreamProxy::HttpConnect {
+                username, password, ..
+            } => username.as_ref().map(|username| ProxyAuth {
+                username: username.clone(),
+                password: password.clone().unwrap_or_default(),
+            }),
+        }
+    }
+
+    /// Returns a dialer that reaches `dest`, a `host:port` string, through the proxy.
+    pub fn dialer(&self, dest: String) -> DynDialer {
+        self.dialer_inner(None, dest)
+    }
+
+    fn dialer_inner(&self, ctx: Option<AnyCtx<Config>>, dest: String) -> DynDialer {
+        let inner = ProxyServerDialer {
+            addr: self.addr().to_string(),
+            ctx,
+        };
+        match self {
+            UpstreamProxy::Socks5 { .. } => Socks5Dialer {
+                inner,
+                dest,
+                auth: self.auth(),
+            }
+            .dynamic(),
+            UpstreamProxy::HttpConnect { .. } => HttpConnectDialer {
+                inner,
+                dest,
+                auth: self.auth(),
+            }
+            .dynamic(),
+        }
+    }
+
+    /// Converts to a proxy setting for reqwest-based HTTP clients.
+    pub fn reqwest_proxy(&self) -> anyhow::Result<reqwest::Proxy> {
+        let scheme = match self {
+            // socks5h makes the proxy resolve names, so that they don't leak through our own DNS
+            UpstreamProxy::Socks5 { .. } => "socks5h",
+            UpstreamProxy::HttpConnect { .. } => "http",
+        };
+        let mut url = reqwest::Url::parse(&format!("{scheme}://{}", self.addr()))?;
+        if let Some(auth) = self.auth() {
+            url.set_username(&auth.username)
+                .and_then(|_| url.set_password(Some(&auth.password)))
+                .map_err(|_| anyhow::anyhow!("cannot put proxy credentials into a URL"))?;
+        }
+        Ok(reqwest::Proxy::all(url)?)
+    }
+}
+
+/// Dials the proxy server itself, resolving its address afresh on every dial, and whitelisting what it resolves to from the VPN if given a context.
+struct ProxyServerDialer {
+    addr: String,
+    ctx: Option<AnyCtx<Config>>,
+}
+
+impl ProxyServerDialer {
+    async fn resolve(&self) -> std::io::Result<Vec<SocketAddr>> {
+        let addrs = smol::net::resolve(self.addr.as_str()).await?;
+        if let Some(ctx) = &self.ctx {
+            for addr in addrs.iter() {
+                smart_vpn_whitelist(ctx, addr.ip());
+            }
+        }
+        Ok(addrs)
+    }
+}
+
+#[async_trait]
+impl Dialer for ProxyServerDialer {
+    type P = Box<dyn Pipe>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let addrs = self.resolve().await?;
+        match &self.ctx {
+            Some(ctx) => {
+                HappyEyeballsTcpDialer(addrs)
+                    .with_options(tcp_options(ctx))
+                    .dial()
+                    .await
+            }
+            None => HappyEyeballsTcpDialer(addrs).dial().await,
+        }
+    }
+}
+
+/// Returns a dialer for a TCP connection to the given address, going through the upstream proxy if one is configured.
+///
+/// Only direct connections get the destination whitelisted from the VPN, since with a proxy, the proxy is the only thing we connect to.
+pub fn tcp_dialer(ctx: &AnyCtx<Config>, dest_addr: SocketAddr) -> DynDialer {
+    match &ctx.init().upstream_proxy {
+        Some(proxy) => proxy.dialer_inner(Some(ctx.clone()), dest_addr.to_string()),
+        None => {
+            smart_vpn_whitelist(ctx, dest_addr.ip());
+            TcpDialer::new(dest_addr)
+                .with_options(tcp_options(ctx))
+                .dynamic()
+        }
+    }
+}
+
+fn tcp_options(ctx: &AnyCtx<Config>) -> TcpOptions {
+    TcpOptions {
+        mark: ctx.init().socket_mark,
+        ..Default::default()
+    }
+}
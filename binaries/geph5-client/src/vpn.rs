@@ -33,7 +33,10 @@ mod macos;
 pub use macos::*;
 
 use crate::{
-    client::CtxField, client_inner::open_conn, spoof_dns::fake_dns_respond, taskpool::add_task,
+    client::CtxField,
+    client_inner::{open_conn, open_udp},
+    spoof_dns::fake_dns_respond,
+    taskpool::add_task,
     Config,
 };
 
@@ -139,11 +142,20 @@ pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                 let task = smolscale::spawn(async move {
                     let tunneled = open_conn(&ctx_clone, "tcp", &peer_addr.to_string()).await?;
                     tracing::trace!(peer_addr = display(peer_addr), "dialed through VPN");
-                    let (read_tunneled, write_tunneled) = tunneled.split();
-                    let (read_captured, write_captured) = captured.split();
-                    smol::io::copy(read_tunneled, write_captured)
-                        .race(smol::io::copy(read_captured, write_tunneled))
-                        .await?;
+                    let (read_tunneled, mut write_tunneled) = tunneled.split();
+                    let (read_captured, mut write_captured) = captured.split();
+                    // each direction closes on its own, so that half-closed connections keep working
+                    futures_util::future::try_join(
+                        async {
+                            smol::io::copy(read_tunneled, &mut write_captured).await?;
+                            write_captured.close().await
+                        },
+                        async {
+                            smol::io::copy(read_captured, &mut write_tunneled).await?;
+                            write_tunneled.close().await
+                        },
+                    )
+                    .await?;
                     anyhow::Ok(())
                 });
 
@@ -174,25 +186,16 @@ pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                             captured.send(&fake_dns_respond(&ctx_clone, &pkt)?).await?;
                         }
                     } else {
-                        let tunneled = open_conn(&ctx_clone, "udp", &peer_addr.to_string()).await?;
-                        let (mut read_tunneled, mut write_tunneled) = tunneled.split();
+                        let tunneled = open_udp(&ctx_clone, &peer_addr.to_string()).await?;
                         let up_loop = async {
                             loop {
                                 let to_up = captured.recv().await?;
-                                write_tunneled
-                                    .write_all(&(to_up.len() as u16).to_le_bytes())
-                                    .await?;
-                                write_tunneled.write_all(&to_up).await?;
-                                write_tunneled.flush().await?;
+                                tunneled.send(&to_up).await?;
                             }
                         };
                         let dn_loop = async {
                             loop {
-                                let mut len_buf = [0u8; 2];
-                                read_tunneled.read_exact(&mut len_buf).await?;
-                                let len = u16::from_le_bytes(len_buf) as usize;
-                                let mut buf = vec![0u8; len];
-                                read_tunneled.read_exact(&mut buf).await?;
+                                let buf = tunneled.recv().await?;
                                 captured.send(&buf).await?;
                             }
                         };
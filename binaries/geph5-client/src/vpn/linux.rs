@@ -11,13 +11,19 @@ use smol::{
     future::FutureExt as _,
     net::UdpSocket,
 };
+use smol_timeout2::TimeoutExt;
 use std::{
     net::{IpAddr, Ipv4Addr},
     process::Command,
     sync::LazyLock,
+    time::Duration,
 };
 
-use crate::{client_inner::open_conn, spoof_dns::fake_dns_respond, Config};
+use crate::{
+    client_inner::{open_conn, open_udp},
+    spoof_dns::fake_dns_respond,
+    Config,
+};
 
 const FAKE_LOCAL_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(100, 64, 89, 64));
 
@@ -83,14 +89,14 @@ pub(super) async fn packet_shuffle(
                 let ctx = ctx.clone();
                 smolscale::spawn(async move {
                     let buf = &buf[..n];
-                    let mut conn = open_conn(&ctx, "udp", "1.1.1.1:53").await?;
-                    conn.write_all(&(buf.len() as u16).to_le_bytes()).await?;
-                    conn.write_all(buf).await?;
-                    let mut len_buf = [0u8; 2];
-                    conn.read_exact(&mut len_buf).await?;
-                    let len = u16::from_le_bytes(len_buf) as usize;
-                    let mut buf = vec![0u8; len];
-                    conn.read_exact(&mut buf).await?;
+                    let conn = open_udp(&ctx, "1.1.1.1:53").await?;
+                    conn.send(buf).await?;
+                    // datagrams can get lost, so don't wait forever for the answer
+                    let buf = conn
+                        .recv()
+                        .timeout(Duration::from_secs(10))
+                        .await
+                        .context("DNS response timed out")??;
                     dns_proxy.send_to(&buf, src).await?;
                     anyhow::Ok(())
                 })
@@ -16,8 +16,10 @@ use reqwest::Method;
 use tap::Tap;
 
 use crate::{
+    drain::is_draining,
     ratelimit::{get_kbps, get_load},
     schedlag::SCHEDULER_LAG_SECS,
+    sessions::get_session_stats,
     tasklimit::get_task_count,
     watchdog::kick_watchdog,
     CONFIG_FILE, SIGNING_SECRET,
@@ -98,6 +100,15 @@ pub async fn broker_loop() -> anyhow::Result<()> {
             let client = BrokerClient(transport);
 
             loop {
+                if is_draining() {
+                    // the descriptor we last uploaded soon expires, so the broker stops sending new clients our way
+                    tracing::info!("draining, so no longer uploading our descriptor");
+                    // the watchdog watches the uploads, so keep it quiet until the drain is over
+                    loop {
+                        kick_watchdog();
+                        smol::Timer::after(Duration::from_secs(10)).await;
+                    }
+                }
                 let upload = async {
                     let free_exits = client
                         .get_free_exits()
@@ -139,6 +150,25 @@ pub async fn broker_loop() -> anyhow::Result<()> {
                             SCHEDULER_LAG_SECS.load(Ordering::Relaxed),
                         )
                         .await?;
+                    let session_stats = get_session_stats();
+                    client
+                        .set_stat(
+                            format!("{server_name}.sessions"),
+                            session_stats.sessions as _,
+                        )
+                        .await?;
+                    client
+                        .set_stat(
+                            format!("{server_name}.mux_open_streams"),
+                            session_stats.open_streams as _,
+                        )
+                        .await?;
+                    client
+                        .set_stat(
+                            format!("{server_name}.mux_queued_frames"),
+                            session_stats.queued_frames as _,
+                        )
+                        .await?;
 
                     let descriptor = ExitDescriptor {
                         c2e_listen: CONFIG_FILE
@@ -157,6 +187,7 @@ pub async fn broker_loop() -> anyhow::Result<()> {
                             .unwrap()
                             .as_secs()
                             + 30,
+                        hybrid_kex: true,
                     };
                     let to_upload = Mac::new(
                         Signed::new(descriptor, DOMAIN_EXIT_DESCRIPTOR, &SIGNING_SECRET),
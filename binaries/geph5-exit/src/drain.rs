@@ -0,0 +1,26 @@
+use std::{
+    sync::{LazyLock, OnceLock},
+    time::{Duration, Instant},
+};
+
+static DRAIN_DEADLINE: OnceLock<Instant> = OnceLock::new();
+static DRAIN_EVENT: LazyLock<async_event::Event> = LazyLock::new(async_event::Event::new);
+
+/// Starts draining the exit, giving open sessions until `grace` from now to wind down. Returns false if we were already draining.
+pub fn start_draining(grace: Duration) -> bool {
+    let started = DRAIN_DEADLINE.set(Instant::now() + grace).is_ok();
+    DRAIN_EVENT.notify_all();
+    started
+}
+
+/// Waits until the exit starts draining, returning the deadline by which it will exit.
+pub async fn wait_draining() -> Instant {
+    DRAIN_EVENT
+        .wait_until(|| DRAIN_DEADLINE.get().copied())
+        .await
+}
+
+/// Whether the exit has started draining.
+pub fn is_draining() -> bool {
+    DRAIN_DEADLINE.get().is_some()
+}
@@ -4,15 +4,23 @@ use futures_util::{AsyncReadExt, TryFutureExt};
 use geph5_broker_protocol::AccountLevel;
 use geph5_misc_rpc::{
     bridge::B2eMetadata,
-    exit::{ClientCryptHello, ClientExitCryptPipe, ClientHello, ExitHello, ExitHelloInner},
+    exit::{
+        hybrid_secret, kem_encapsulate, ClientCryptHello, ClientExitCryptPipe, ClientHello,
+        ExitHello, ExitHelloInner,
+    },
     read_prepend_length, write_prepend_length,
 };
 use mizaru2::{ClientToken, UnblindedSignature};
 use moka::future::Cache;
 use picomux::{LivenessConfig, PicoMux};
 
-use sillad::{listener::Listener, tcp::TcpListener, EitherPipe, Pipe};
-use smol::future::FutureExt as _;
+use sillad::{
+    listener::{EitherListener, Listener, ListenerExt},
+    proxy_protocol::ProxyProtocolListener,
+    tcp::TcpListener,
+    EitherPipe, Pipe,
+};
+use smol::{future::FutureExt as _, Timer};
 use std::{net::SocketAddr, sync::Arc, time::Duration};
 use stdcode::StdcodeSerializeExt;
 use tachyonix::Sender;
@@ -25,9 +33,11 @@ use crate::{
     asn::ip_to_asn_country,
     auth::verify_user,
     broker::{broker_loop, ACCEPT_FREE},
+    drain::{is_draining, wait_draining},
     ipv6::{configure_ipv6_routing, EyeballDialer},
     proxy::proxy_stream,
     ratelimit::{get_ratelimiter, RateLimiter},
+    sessions::register_session,
     tasklimit::new_task_until_death,
     CONFIG_FILE, SIGNING_SECRET,
 };
@@ -37,44 +47,88 @@ pub async fn listen_main() -> anyhow::Result<()> {
     let c2e = c2e_loop();
     let b2e = b2e_loop();
     let broker = broker_loop();
-    c2e.race(broker).race(b2e).await
+    // sessions are told to go away as soon as draining starts, and whatever is left once the deadline passes is cut off
+    let drain = async {
+        let deadline = wait_draining().await;
+        Timer::at(deadline).await;
+        tracing::info!("done draining");
+        anyhow::Ok(())
+    };
+    c2e.race(broker).race(b2e).race(drain).await
 }
 
 async fn c2e_loop() -> anyhow::Result<()> {
     let listener = TcpListener::bind(CONFIG_FILE.wait().c2e_listen).await?;
+    let listener = if CONFIG_FILE.wait().c2e_proxy_protocol {
+        EitherListener::Left(ProxyProtocolListener::new(listener))
+    } else {
+        EitherListener::Right(listener)
+    };
+    let listener = listener
+        .filter_async(|remote_addr| async move {
+            match c2e_admissible(remote_addr).await {
+                Ok(()) => true,
+                Err(err) => {
+                    tracing::warn!(err = debug(err), "rejected a direct connection");
+                    false
+                }
+            }
+        })
+        .max_concurrent(CONFIG_FILE.wait().c2e_max_concurrent)
+        .accept_rate(CONFIG_FILE.wait().c2e_accept_rate);
     let mut listener = sillad_conntest::ConnTestListener::new(listener);
     loop {
-        let c2e_raw = match listener.accept().await {
-            Ok(conn) => conn,
-            Err(err) => {
+        let c2e_raw = match async { Some(listener.accept().await) }
+            .or(async {
+                wait_draining().await;
+                None
+            })
+            .await
+        {
+            Some(Ok(conn)) => conn,
+            Some(Err(err)) => {
                 tracing::error!(err = debug(err), "error accepting");
                 continue;
             }
+            None => break,
         };
+        smolscale::spawn(handle_client(c2e_raw)).detach()
+    }
+    // every direct connection is its own session, so closing the listener turns new clients away without touching existing ones
+    drop(listener);
+    tracing::info!("draining, so no longer accepting direct connections");
+    smol::future::pending().await
+}
 
-        let test_addr = async {
-            let remote_addr: SocketAddr = c2e_raw.remote_addr().unwrap().parse()?;
-            if let SocketAddr::V4(remote_addr) = remote_addr {
-                let (asn, country) = ip_to_asn_country(*remote_addr.ip()).await?;
-                tracing::trace!(asn, country, remote_addr = display(remote_addr), "got ASN");
-                if CONFIG_FILE.wait().country_blacklist.contains(&country) {
-                    anyhow::bail!(
-                        "rejected connection from {remote_addr}/AS{asn} in blacklisted country {country}"
-                    )
-                }
-            }
-            anyhow::Ok(())
-        };
-        if let Err(err) = test_addr.await {
-            tracing::warn!(err = debug(err), "rejected a direct connection");
-            continue;
+/// Checks that a direct connection does not come from a blacklisted country.
+async fn c2e_admissible(remote_addr: Option<String>) -> anyhow::Result<()> {
+    let remote_addr: SocketAddr = remote_addr
+        .context("direct connection without a remote address")?
+        .parse()?;
+    if let SocketAddr::V4(remote_addr) = remote_addr {
+        let (asn, country) = ip_to_asn_country(*remote_addr.ip()).await?;
+        tracing::trace!(asn, country, remote_addr = display(remote_addr), "got ASN");
+        if CONFIG_FILE.wait().country_blacklist.contains(&country) {
+            anyhow::bail!(
+                "rejected connection from {remote_addr}/AS{asn} in blacklisted country {country}"
+            )
         }
-        smolscale::spawn(handle_client(c2e_raw)).detach()
     }
+    Ok(())
 }
 
 async fn b2e_loop() -> anyhow::Result<()> {
-    let mut listener = TcpListener::bind(CONFIG_FILE.wait().b2e_listen).await?;
+    let listener = TcpListener::bind(CONFIG_FILE.wait().b2e_listen).await?;
+    #[cfg(unix)]
+    let mut listener = if let Some(path) = &CONFIG_FILE.wait().b2e_listen_unix {
+        listener
+            .join(sillad::unix::UnixListener::bind(path).await?)
+            .dynamic()
+    } else {
+        listener.dynamic()
+    };
+    #[cfg(not(unix))]
+    let mut listener = listener.dynamic();
     let b2e_table: Cache<B2eMetadata, Sender<picomux::Stream>> = Cache::builder()
         .time_to_idle(Duration::from_secs(1200))
         .build();
@@ -101,6 +155,14 @@ async fn b2e_loop() -> anyhow::Result<()> {
             loop {
                 let lala = b2e_mux.accept().await?;
                 let b2e_metadata: B2eMetadata = stdcode::deserialize(lala.metadata())?;
+                // bridges keep carrying the sessions we already have, but get no new ones once we start draining
+                if is_draining() && !b2e_table.contains_key(&b2e_metadata) {
+                    tracing::debug!(
+                        bridge_addr = display(&bridge_addr),
+                        "refusing a new b2e session while draining"
+                    );
+                    continue;
+                }
                 tracing::trace!(
                     bridge_addr = display(&bridge_addr),
                     "accepting b2e with metadata"
@@ -130,22 +192,33 @@ async fn handle_client(mut client: impl Pipe) -> anyhow::Result<()> {
     let client_hello: ClientHello = stdcode::deserialize(&read_prepend_length(&mut client).await?)?;
 
     let keys: Option<([u8; 32], [u8; 32])>;
-    let exit_hello_inner: ExitHelloInner = match client_hello.crypt_hello {
+    let exit_hello_inner: ExitHelloInner = match &client_hello.crypt_hello {
         ClientCryptHello::SharedSecretChallenge(key) => {
             let real_ss = client.shared_secret().context("no shared secret")?;
-            let mac = blake3::keyed_hash(&key, real_ss);
+            let mac = blake3::keyed_hash(key, real_ss);
             keys = None;
             ExitHelloInner::SharedSecretResponse(mac)
         }
         ClientCryptHello::X25519(their_epk) => {
             let my_esk = EphemeralSecret::random_from_rng(rand::thread_rng());
             let my_epk = PublicKey::from(&my_esk);
-            let shared_secret = my_esk.diffie_hellman(&their_epk);
+            let shared_secret = my_esk.diffie_hellman(their_epk);
             let read_key = blake3::derive_key("c2e", shared_secret.as_bytes());
             let write_key = blake3::derive_key("e2c", shared_secret.as_bytes());
             keys = Some((read_key, write_key));
             ExitHelloInner::X25519(my_epk)
         }
+        ClientCryptHello::X25519MlKem768(their_epk, their_encap_key) => {
+            let my_esk = EphemeralSecret::random_from_rng(rand::thread_rng());
+            let my_epk = PublicKey::from(&my_esk);
+            let (ciphertext, kem_secret) = kem_encapsulate(their_encap_key)?;
+            let shared_secret =
+                hybrid_secret(my_esk.diffie_hellman(their_epk).as_bytes(), &kem_secret);
+            let read_key = blake3::derive_key("c2e", &shared_secret);
+            let write_key = blake3::derive_key("e2c", &shared_secret);
+            keys = Some((read_key, write_key));
+            ExitHelloInner::X25519MlKem768(my_epk, ciphertext)
+        }
     };
 
     let mut is_free = false;
@@ -178,30 +251,41 @@ async fn handle_client(mut client: impl Pipe) -> anyhow::Result<()> {
     };
 
     let (client_read, client_write) = client.split();
-    let mux = PicoMux::new(client_read, client_write);
+    let mux = Arc::new(PicoMux::new(client_read, client_write));
+    register_session(&mux);
 
     let mut sess_metadata = Arc::new(serde_json::Value::Null);
     let dialer = EyeballDialer::new();
-    loop {
-        let stream = mux.accept().await?;
-        let metadata = String::from_utf8_lossy(stream.metadata()).to_string();
-        if let Ok(new_sess_metadata) = serde_json::from_str::<serde_json::Value>(&metadata) {
-            sess_metadata = Arc::new(new_sess_metadata);
-            continue;
-        }
-        let sess_metadata = sess_metadata.clone();
-        let dialer = dialer.clone();
-        smolscale::spawn(
-            proxy_stream(
-                dialer,
-                sess_metadata.clone(),
-                ratelimit.clone(),
-                stream,
-                is_free,
+    // once we start draining, the client moves new streams to another session while these finish
+    let drain = async {
+        let deadline = wait_draining().await;
+        mux.shutdown_gracefully(deadline).await;
+        anyhow::Ok(())
+    };
+    async {
+        loop {
+            let stream = mux.accept().await?;
+            let metadata = String::from_utf8_lossy(stream.metadata()).to_string();
+            if let Ok(new_sess_metadata) = serde_json::from_str::<serde_json::Value>(&metadata) {
+                sess_metadata = Arc::new(new_sess_metadata);
+                continue;
+            }
+            let sess_metadata = sess_metadata.clone();
+            let dialer = dialer.clone();
+            smolscale::spawn(
+                proxy_stream(
+                    dialer,
+                    sess_metadata.clone(),
+                    ratelimit.clone(),
+                    stream,
+                    is_free,
+                )
+                .race(new_task_until_death(Duration::from_secs(1)))
+                .map_err(|e| tracing::trace!(err = debug(e), "stream died with")),
             )
-            .race(new_task_until_death(Duration::from_secs(1)))
-            .map_err(|e| tracing::trace!(err = debug(e), "stream died with")),
-        )
-        .detach();
+            .detach();
+        }
     }
+    .race(drain)
+    .await
 }
@@ -1,13 +1,28 @@
-use std::io::ErrorKind;
+use std::{
+    io::ErrorKind,
+    sync::{Arc, LazyLock},
+};
 
 use async_trait::async_trait;
 use futures_util::TryFutureExt;
 use geph5_misc_rpc::bridge::{B2eMetadata, ObfsProtocol};
-use sillad::listener::{DynListener, ListenerExt};
+use sillad::{
+    dialer::DialerExt,
+    listener::{DynListener, Listener, ListenerExt},
+    tcp::TcpDialer,
+    Pipe,
+};
 use sillad_conntest::ConnTestListener;
-use sillad_sosistab3::{listener::SosistabListener, Cookie};
+use sillad_sosistab3::{
+    listener::{CookieSet, SosistabListener},
+    replay::{FileReplayCache, MemoryReplayCache, ReplayCache},
+    Cookie,
+};
+use sillad_websocket::WsListener;
 use tachyonix::Receiver;
 
+use crate::CONFIG_FILE;
+
 use super::{handle_client, tls::dummy_tls_config};
 
 pub async fn b2e_process(
@@ -22,9 +37,7 @@ pub async fn b2e_process(
 
 fn create_listener(protocol: ObfsProtocol, bottom: ReceiverListener) -> DynListener {
     match protocol {
-        ObfsProtocol::Sosistab3(cookie) => {
-            SosistabListener::new(bottom, Cookie::new(&cookie)).dynamic()
-        }
+        ObfsProtocol::Sosistab3(cookie) => sosistab_listener(bottom, &cookie),
         ObfsProtocol::ConnTest(obfs_protocol) => {
             let inner = create_listener(*obfs_protocol, bottom);
             ConnTestListener::new(inner).dynamic()
@@ -36,11 +49,42 @@ fn create_listener(protocol: ObfsProtocol, bottom: ReceiverListener) -> DynListe
         }
         ObfsProtocol::Sosistab3New(cookie, obfs_protocol) => {
             let inner = create_listener(*obfs_protocol, bottom);
-            SosistabListener::new(inner, Cookie::new(&cookie)).dynamic()
+            sosistab_listener(inner, &cookie)
+        }
+        ObfsProtocol::Websocket(obfs_protocol) => {
+            let inner = create_listener(*obfs_protocol, bottom);
+            WsListener::new(inner).dynamic()
         }
     }
 }
 
+/// How many handshakes the shared replay cache file has room for.
+const REPLAY_CACHE_SLOTS: usize = 1 << 16;
+
+/// Every bridge relays the same cookie, so every sosistab3 listener checks handshakes against one cache.
+static REPLAY_CACHE: LazyLock<Arc<dyn ReplayCache>> =
+    LazyLock::new(|| match &CONFIG_FILE.wait().sosistab3_replay_cache {
+        Some(path) => Arc::new(
+            FileReplayCache::open(path, REPLAY_CACHE_SLOTS)
+                .expect("could not open the sosistab3 replay cache"),
+        ),
+        None => Arc::new(MemoryReplayCache::default()),
+    });
+
+fn sosistab_listener<P: Pipe>(inner: impl Listener<P = P>, cookie: &str) -> DynListener {
+    let fallback = CONFIG_FILE
+        .wait()
+        .sosistab3_fallback
+        .map(|dest_addr| TcpDialer { dest_addr }.dynamic());
+    SosistabListener::new_with_replay_cache(
+        inner,
+        CookieSet::new(Cookie::new(cookie)),
+        fallback,
+        REPLAY_CACHE.clone(),
+    )
+    .dynamic()
+}
+
 async fn b2e_inner(mut listener: impl sillad::listener::Listener) -> anyhow::Result<()> {
     loop {
         let client = listener.accept().await?;
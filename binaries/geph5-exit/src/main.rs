@@ -1,6 +1,8 @@
 mod asn;
 mod dns;
+mod drain;
 mod ipv6;
+mod sessions;
 mod tasklimit;
 mod watchdog;
 
@@ -17,6 +19,7 @@ use serde_with::{serde_as, DisplayFromStr};
 use std::{
     net::{IpAddr, SocketAddr},
     path::PathBuf,
+    time::Duration,
 };
 use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
 
@@ -45,7 +48,19 @@ struct ConfigFile {
     broker: Option<BrokerConfig>,
 
     c2e_listen: SocketAddr,
+    /// Expect every direct connection to start with a PROXY protocol header, as when the exit is behind a load balancer.
+    #[serde(default)]
+    c2e_proxy_protocol: bool,
     b2e_listen: SocketAddr,
+    /// Additionally accept b2e links on a Unix domain socket, e.g. from a bridge on the same host.
+    #[serde(default)]
+    b2e_listen_unix: Option<PathBuf>,
+    /// Where to send connections whose sosistab3 handshake fails, such as a local web server, so that probers see an ordinary service instead of a dropped connection.
+    #[serde(default)]
+    sosistab3_fallback: Option<SocketAddr>,
+    /// A file for remembering recent sosistab3 handshakes, so that exit processes on the same host sharing it all reject replayed ones.
+    #[serde(default)]
+    sosistab3_replay_cache: Option<PathBuf>,
     ip_addr: Option<IpAddr>,
 
     country: CountryCode,
@@ -54,6 +69,14 @@ struct ConfigFile {
     #[serde(default = "default_country_blacklist")]
     country_blacklist: Vec<String>,
 
+    /// The most direct client connections that may be open at once.
+    #[serde(default = "default_c2e_max_concurrent")]
+    c2e_max_concurrent: usize,
+
+    /// The most direct client connections accepted per second.
+    #[serde(default = "default_c2e_accept_rate")]
+    c2e_accept_rate: f64,
+
     #[serde(default = "default_free_ratelimit")]
     free_ratelimit: u32,
 
@@ -69,6 +92,10 @@ struct ConfigFile {
     #[serde(default = "default_task_limit")]
     task_limit: usize,
 
+    /// How long clients get to move their sessions elsewhere after we're told to shut down.
+    #[serde(default = "default_drain_secs")]
+    drain_secs: u64,
+
     #[serde_as(as = "DisplayFromStr")]
     #[serde(default)]
     ipv6_subnet: Ipv6Net,
@@ -90,6 +117,10 @@ fn default_task_limit() -> usize {
     1_000_000
 }
 
+fn default_drain_secs() -> u64 {
+    60
+}
+
 fn default_free_port_whitelist() -> Vec<u16> {
     vec![80, 443, 8080, 8443, 22, 53]
 }
@@ -98,6 +129,14 @@ fn default_country_blacklist() -> Vec<String> {
     vec![]
 }
 
+fn default_c2e_max_concurrent() -> usize {
+    100_000
+}
+
+fn default_c2e_accept_rate() -> f64 {
+    1000.0
+}
+
 #[derive(Deserialize)]
 struct BrokerConfig {
     url: String,
@@ -146,5 +185,15 @@ fn main() -> anyhow::Result<()> {
 
     CONFIG_FILE.set(config).ok().unwrap();
 
+    // the first signal tells every client to go elsewhere, while a second one ends things right away
+    ctrlc::set_handler(|| {
+        let grace = Duration::from_secs(CONFIG_FILE.wait().drain_secs);
+        if drain::start_draining(grace) {
+            tracing::info!(grace = debug(grace), "draining before shutting down");
+        } else {
+            std::process::exit(1);
+        }
+    })?;
+
     smol::future::block_on(smolscale::spawn(listen_main()))
 }
@@ -1,4 +1,5 @@
 use std::{
+    net::Shutdown,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -7,7 +8,11 @@ use anyhow::Context;
 
 use futures_util::{io::BufReader, AsyncReadExt, AsyncWriteExt};
 
-use smol::{future::FutureExt as _, net::UdpSocket};
+use picomux::{Priority, ResetCode};
+use smol::{
+    future::FutureExt as _,
+    net::{TcpStream, UdpSocket},
+};
 
 use crate::{
     allow::proxy_allowed,
@@ -18,6 +23,13 @@ use crate::{
 
 use smol_timeout2::TimeoutExt;
 
+/// A destination the exit managed to reach, ready for relaying.
+enum Upstream {
+    Tcp(TcpStream),
+    Udp { socket: UdpSocket, datagram: bool },
+    Dns { datagram: bool },
+}
+
 #[tracing::instrument(skip_all)]
 pub async fn proxy_stream(
     dialer: EyeballDialer,
@@ -26,59 +38,52 @@ pub async fn proxy_stream(
     stream: picomux::Stream,
     is_free: bool,
 ) -> anyhow::Result<()> {
-    let dest_host = String::from_utf8_lossy(stream.metadata());
-    let (protocol, dest_host): (&str, &str) = if dest_host.contains('$') {
-        dest_host.split_once('$').unwrap()
-    } else {
-        ("tcp", &dest_host)
-    };
+    let metadata = String::from_utf8_lossy(stream.metadata()).into_owned();
+    let (protocol, dest_host) = metadata.split_once('$').unwrap_or(("tcp", &metadata));
     let filter: FilterOptions =
         serde_json::from_value(sess_metadata["filter"].clone()).unwrap_or_default();
-    let dest_addrs = dns_resolve(dest_host, filter)
-        .await
-        .context("failed to resolve DNS")?;
-    if !dest_addrs.iter().all(|addr| proxy_allowed(*addr, is_free)) {
-        anyhow::bail!("Proxying to {} is not allowed", dest_host);
-    }
+    let upstream = match connect_upstream(&dialer, protocol, dest_host, filter, is_free).await {
+        Ok(upstream) => upstream,
+        Err((code, err)) => {
+            // tell the client why, so that it can tell the user
+            stream.reset(code, &format!("{err:#}"));
+            return Err(err);
+        }
+    };
 
-    match protocol {
-        "tcp" => {
-            let start = Instant::now();
-            let dest_tcp = dialer
-                .connect(dest_addrs.clone())
-                .timeout(Duration::from_secs(5))
-                .await
-                .context(format!("timeout in TCP dial to {:?}", dest_addrs))??;
-            tracing::trace!(
-                protocol,
-                dest_host = display(dest_host),
-                latency = debug(start.elapsed()),
-                "TCP established resolved"
-            );
+    // the client sends DNS and UDP ahead of bulk transfers, and so do we
+    if !matches!(upstream, Upstream::Tcp(_)) {
+        stream.set_priority(Priority::Interactive);
+    }
+    match upstream {
+        Upstream::Tcp(dest_tcp) => {
             let (read_stream, mut write_stream) = stream.split();
-            let (read_dest, mut write_dest) = dest_tcp.split();
-            smol::future::race(
-                ratelimit.io_copy(read_stream, &mut write_dest),
-                ratelimit.io_copy(read_dest, &mut write_stream),
-            )
-            .await?;
+            // each direction closes on its own, so that half-closed connections keep working
+            let upload = async {
+                ratelimit.io_copy(read_stream, dest_tcp.clone()).await?;
+                dest_tcp.shutdown(Shutdown::Write)?;
+                anyhow::Ok(())
+            };
+            let download = async {
+                ratelimit
+                    .io_copy(dest_tcp.clone(), &mut write_stream)
+                    .await?;
+                write_stream.close().await?;
+                anyhow::Ok(())
+            };
+            futures_util::future::try_join(upload, download).await?;
             Ok(())
         }
-        "udp" => {
-            let addr = *dest_addrs
-                .iter()
-                .find(|s| s.is_ipv4())
-                .context("UDP only supports ipv4 for now")?;
-            if addr.port() == 53 {
-                return proxy_dns(stream, filter).await;
-            }
-            if addr.port() == 443 {
-                anyhow::bail!("special-case banning QUIC to improve traffic management")
-            }
-            let udp_socket: UdpSocket = UdpSocket::bind("0.0.0.0:0")
-                .await
-                .context("UDP bind failed")?;
-            udp_socket.connect(addr).await?;
+        Upstream::Dns { datagram: true } => proxy_dns_datagram(stream, filter).await,
+        Upstream::Dns { datagram: false } => proxy_dns(stream, filter).await,
+        Upstream::Udp {
+            socket,
+            datagram: true,
+        } => proxy_udp_datagram(stream, socket, ratelimit).await,
+        Upstream::Udp {
+            socket: udp_socket,
+            datagram: false,
+        } => {
             let (read_stream, mut write_stream) = stream.split();
             let up_loop = async {
                 let mut read_stream = BufReader::new(read_stream);
@@ -121,9 +126,96 @@ pub async fn proxy_stream(
             };
             up_loop.race(dn_loop).await
         }
-        prot => {
-            anyhow::bail!("unknown protocol {prot}")
+    }
+}
+
+/// Resolves and connects to the destination, or explains why not with a reset code.
+async fn connect_upstream(
+    dialer: &EyeballDialer,
+    protocol: &str,
+    dest_host: &str,
+    filter: FilterOptions,
+    is_free: bool,
+) -> Result<Upstream, (ResetCode, anyhow::Error)> {
+    let dest_addrs = dns_resolve(dest_host, filter)
+        .await
+        .context("failed to resolve DNS")
+        .map_err(|e| (ResetCode::HostUnreachable, e))?;
+    if !dest_addrs.iter().all(|addr| proxy_allowed(*addr, is_free)) {
+        return Err((
+            ResetCode::NotAllowed,
+            anyhow::anyhow!("Proxying to {} is not allowed", dest_host),
+        ));
+    }
+
+    match protocol {
+        "tcp" => {
+            let start = Instant::now();
+            let dest_tcp = match dialer
+                .connect(dest_addrs.clone())
+                .timeout(Duration::from_secs(5))
+                .await
+            {
+                None => {
+                    return Err((
+                        ResetCode::TimedOut,
+                        anyhow::anyhow!("timeout in TCP dial to {:?}", dest_addrs),
+                    ))
+                }
+                Some(Err(err)) => {
+                    let refused = err.downcast_ref::<std::io::Error>().map(|e| e.kind())
+                        == Some(std::io::ErrorKind::ConnectionRefused);
+                    let code = if refused {
+                        ResetCode::ConnectionRefused
+                    } else {
+                        ResetCode::HostUnreachable
+                    };
+                    return Err((code, err.context(format!("TCP dial to {:?}", dest_addrs))));
+                }
+                Some(Ok(dest_tcp)) => dest_tcp,
+            };
+            tracing::trace!(
+                protocol,
+                dest_host = display(dest_host),
+                latency = debug(start.elapsed()),
+                "TCP established resolved"
+            );
+            Ok(Upstream::Tcp(dest_tcp))
+        }
+        // "udp" frames packets with a length prefix inside the stream, while "dgram" sends them as
+        // picomux datagrams, which newer clients use when they know the mux supports them
+        "udp" | "dgram" => {
+            let datagram = protocol == "dgram";
+            let addr = *dest_addrs.iter().find(|s| s.is_ipv4()).ok_or_else(|| {
+                (
+                    ResetCode::Unsupported,
+                    anyhow::anyhow!("UDP only supports ipv4 for now"),
+                )
+            })?;
+            if addr.port() == 53 {
+                return Ok(Upstream::Dns { datagram });
+            }
+            if addr.port() == 443 {
+                return Err((
+                    ResetCode::NotAllowed,
+                    anyhow::anyhow!("special-case banning QUIC to improve traffic management"),
+                ));
+            }
+            let socket = async {
+                let socket = UdpSocket::bind("0.0.0.0:0")
+                    .await
+                    .context("UDP bind failed")?;
+                socket.connect(addr).await?;
+                anyhow::Ok(socket)
+            }
+            .await
+            .map_err(|e| (ResetCode::General, e))?;
+            Ok(Upstream::Udp { socket, datagram })
         }
+        prot => Err((
+            ResetCode::Unsupported,
+            anyhow::anyhow!("unknown protocol {prot}"),
+        )),
     }
 }
 
@@ -149,3 +241,62 @@ async fn proxy_dns(stream: picomux::Stream, filter: FilterOptions) -> anyhow::Re
         .detach();
     }
 }
+
+async fn proxy_udp_datagram(
+    stream: picomux::Stream,
+    udp_socket: UdpSocket,
+    ratelimit: RateLimiter,
+) -> anyhow::Result<()> {
+    let up_loop = async {
+        loop {
+            let packet = stream
+                .recv_datagram()
+                .timeout(Duration::from_secs(60))
+                .await
+                .context("timeout in udp up")??;
+            ratelimit.wait(packet.len()).await;
+            udp_socket.send(&packet).await?;
+        }
+    };
+    let dn_loop = async {
+        let mut buf = [0u8; 8192];
+        loop {
+            let len = udp_socket
+                .recv(&mut buf)
+                .timeout(Duration::from_secs(60))
+                .await
+                .context("timeout in udp down")??;
+            ratelimit.wait(len).await;
+            // if the client is not keeping up, the packet is lost, just like on the open internet
+            stream.send_datagram(&buf[..len]);
+        }
+    };
+    up_loop.race(dn_loop).await
+}
+
+async fn proxy_dns_datagram(stream: picomux::Stream, filter: FilterOptions) -> anyhow::Result<()> {
+    let (send_response, recv_response) = smol::channel::bounded(100);
+    let up_loop = async {
+        loop {
+            let packet = stream
+                .recv_datagram()
+                .timeout(Duration::from_secs(60))
+                .await
+                .context("timeout in dns up")??;
+            let send_response = send_response.clone();
+            smolscale::spawn(async move {
+                let response = raw_dns_respond(packet, filter).await?;
+                let _ = send_response.try_send(response);
+                anyhow::Ok(())
+            })
+            .detach();
+        }
+    };
+    let dn_loop = async {
+        loop {
+            let response = recv_response.recv().await?;
+            stream.send_datagram(&response);
+        }
+    };
+    up_loop.race(dn_loop).await
+}
@@ -0,0 +1,34 @@
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+
+use picomux::PicoMux;
+
+static SESSIONS: LazyLock<Mutex<Vec<Weak<PicoMux>>>> = LazyLock::new(Default::default);
+
+/// Totals across every live client session, from [`get_session_stats`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SessionStats {
+    pub sessions: usize,
+    pub open_streams: usize,
+    pub queued_frames: usize,
+}
+
+/// Registers a client session's mux, so that its stats count until it's dropped.
+pub fn register_session(mux: &Arc<PicoMux>) {
+    SESSIONS.lock().unwrap().push(Arc::downgrade(mux));
+}
+
+/// Sums up the stats of every live client session, forgetting the ones that have ended.
+pub fn get_session_stats() -> SessionStats {
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.retain(|mux| mux.strong_count() > 0);
+    sessions
+        .iter()
+        .filter_map(Weak::upgrade)
+        .fold(SessionStats::default(), |mut total, mux| {
+            let stats = mux.stats();
+            total.sessions += 1;
+            total.open_streams += stats.open_streams;
+            total.queued_frames += stats.queued_frames;
+            total
+        })
+}
@@ -24,6 +24,18 @@ pub struct ExitDescriptor {
     pub load: f32,
     /// When does this descriptor expire?
     pub expiry: u64,
+    /// Whether the exit accepts hybrid X25519 + ML-KEM-768 key exchange. This is left out when unset, so that descriptors are signed exactly like before. Brokers never set it in an [ExitList], which older clients must still be able to verify, and serve it through [ExitFeatures] instead.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hybrid_kex: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Optional protocol features of the exits in the system, kept apart from [ExitList] so that clients that predate them can still verify it.
+pub struct ExitFeatures {
+    /// The exits that accept hybrid X25519 + ML-KEM-768 key exchange. Clients must never settle for a weaker one with them.
+    pub hybrid_kex: Vec<VerifyingKey>,
+    /// When does this list expire? Without this, an old list from before an exit gained a feature could be replayed to downgrade it.
+    pub expiry: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -38,6 +38,7 @@ pub trait BrokerProtocol {
 
     async fn get_exits(&self) -> Result<Signed<ExitList>, GenericError>;
     async fn get_free_exits(&self) -> Result<Signed<ExitList>, GenericError>;
+    async fn get_exit_features(&self) -> Result<Signed<ExitFeatures>, GenericError>;
     async fn get_routes(
         &self,
         token: ClientToken,
@@ -144,6 +145,8 @@ impl Default for Credential {
 
 pub const DOMAIN_EXIT_DESCRIPTOR: &str = "exit-descriptor";
 
+pub const DOMAIN_EXIT_FEATURES: &str = "exit-features";
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(transparent)]
 pub struct GenericError(pub String);
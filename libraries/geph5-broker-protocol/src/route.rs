@@ -29,6 +29,10 @@ pub enum RouteDescriptor {
         ping_count: u32,
         lower: Box<RouteDescriptor>,
     },
+    Websocket {
+        url: String,
+        lower: Box<RouteDescriptor>,
+    },
 
     #[serde(untagged)]
     Other(serde_json::Value),
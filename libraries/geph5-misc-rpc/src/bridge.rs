@@ -19,6 +19,7 @@ pub enum ObfsProtocol {
     ConnTest(Box<Self>),
     PlainTls(Box<Self>),
     Sosistab3New(String, Box<Self>),
+    Websocket(Box<Self>),
 }
 
 /// The RPC protocol that bridges expose, called by the broker.
@@ -1,12 +1,23 @@
-use std::pin::Pin;
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
 
 use async_task::Task;
 use bipe::{BipeReader, BipeWriter};
 use bytes::Bytes;
-use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+use chacha20poly1305::{
+    aead::{Aead, OsRng},
+    ChaCha20Poly1305, KeyInit,
+};
 use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use ml_kem::{kem::Decapsulate, kem::Encapsulate, EncodedSizeUser, KemCore, MlKem768};
 use pin_project::pin_project;
 use serde::{Deserialize, Serialize};
 use sillad::Pipe;
@@ -15,6 +26,12 @@ use tap::Tap;
 
 use crate::{read_prepend_length, write_prepend_length};
 
+/// We move to a new writing key after this many messages...
+const REKEY_AFTER_MESSAGES: u64 = 1 << 20;
+
+/// ...or after this long, whichever comes first.
+const REKEY_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
 /// ClientHello represents the initial message sent by the client to
 /// the exit node to negotiate the authentication/encryption system
 /// to use.
@@ -34,6 +51,8 @@ pub enum ClientCryptHello {
     SharedSecretChallenge([u8; 32]),
     /// An X25519 public key to be used to add a layer of encryption
     X25519(x25519_dalek::PublicKey),
+    /// An X25519 public key along with an ML-KEM-768 encapsulation key, for a hybrid key exchange that holds up even if X25519 is later broken. Exits that predate this variant cannot parse it, so clients only send it to exits whose broker-signed features list hybrid key exchange, and then never accept a plain X25519 answer.
+    X25519MlKem768(x25519_dalek::PublicKey, Bytes),
 }
 
 /// ExitHello represents the response of the exit node to the initial
@@ -58,9 +77,59 @@ pub enum ExitHelloInner {
     SharedSecretResponse(blake3::Hash),
     /// An X25519 public key to be used in the key exchange process
     X25519(x25519_dalek::PublicKey),
+    /// An X25519 public key along with an ML-KEM-768 ciphertext, answering [ClientCryptHello::X25519MlKem768]
+    X25519MlKem768(x25519_dalek::PublicKey, Bytes),
+}
+
+/// The client's secret half of an ML-KEM-768 keypair.
+pub struct KemSecret(<MlKem768 as KemCore>::DecapsulationKey);
+
+impl KemSecret {
+    /// Generates a keypair, returning the secret half along with the encoded encapsulation key to send to the exit.
+    pub fn generate() -> (Self, Bytes) {
+        let (dk, ek) = MlKem768::generate(&mut OsRng);
+        (Self(dk), ek.as_bytes().to_vec().into())
+    }
+
+    /// Recovers the shared secret from the exit's ciphertext.
+    pub fn decapsulate(&self, ciphertext: &[u8]) -> anyhow::Result<[u8; 32]> {
+        let ciphertext = ciphertext
+            .try_into()
+            .context("bad ML-KEM ciphertext length")?;
+        let secret = self
+            .0
+            .decapsulate(ciphertext)
+            .ok()
+            .context("cannot decapsulate")?;
+        Ok(secret.into())
+    }
+}
+
+/// Encapsulates a fresh shared secret to the client's encoded encapsulation key, returning the ciphertext along with the secret.
+pub fn kem_encapsulate(encap_key: &[u8]) -> anyhow::Result<(Bytes, [u8; 32])> {
+    let encap_key = <MlKem768 as KemCore>::EncapsulationKey::from_bytes(
+        encap_key
+            .try_into()
+            .context("bad ML-KEM encapsulation key length")?,
+    );
+    let (ciphertext, secret) = encap_key
+        .encapsulate(&mut OsRng)
+        .ok()
+        .context("cannot encapsulate")?;
+    Ok((ciphertext.to_vec().into(), secret.into()))
+}
+
+/// Combines the X25519 and ML-KEM shared secrets into one from which the pipe keys are derived. It stays secret as long as either input does.
+pub fn hybrid_secret(x25519: &[u8; 32], kem: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_derive_key("geph5 client-exit hybrid");
+    hasher.update(x25519);
+    hasher.update(kem);
+    *hasher.finalize().as_bytes()
 }
 
 /// ClientExitCryptPipe is a sillad::Pipe implementation representing an end-to-end encrypted connection between the client and the exit.
+///
+/// Each direction periodically ratchets forward to a new key. Since peers that predate this would choke on messages under an unknown key, each side first sends an empty message, which old peers ignore, as its very first message. After that, an empty message means "every message after this one uses the next key", and a side only ever sends one if it got the other's initial empty message.
 #[pin_project]
 pub struct ClientExitCryptPipe {
     #[pin]
@@ -110,23 +179,45 @@ impl AsyncWrite for ClientExitCryptPipe {
 impl ClientExitCryptPipe {
     /// Creates a new pipe, given read and write keys
     pub fn new(pipe: impl Pipe, read_key: [u8; 32], write_key: [u8; 32]) -> Self {
+        Self::new_with_rekey_after(pipe, read_key, write_key, REKEY_AFTER_MESSAGES)
+    }
+
+    fn new_with_rekey_after(
+        pipe: impl Pipe,
+        read_key: [u8; 32],
+        write_key: [u8; 32],
+        rekey_after: u64,
+    ) -> Self {
         let addr = pipe.remote_addr().map(|s| s.to_string());
+        let peer_rekeys = Arc::new(AtomicBool::new(false));
         let (mut pipe_read, mut pipe_write) = pipe.split();
         let (mut write_incoming, read_incoming) = bipe::bipe(32768);
         let (write_outgoing, mut read_outgoing) = bipe::bipe(32768);
 
+        let peer_rekeys_read = peer_rekeys.clone();
         let _read_task = smolscale::spawn(async move {
-            let read_aead = ChaCha20Poly1305::new_from_slice(&read_key).unwrap();
+            let mut read_key = read_key;
+            let mut read_aead = ChaCha20Poly1305::new_from_slice(&read_key).unwrap();
             let fallible = async {
-                for read_nonce in 0u64.. {
+                let mut read_nonce = 0u64;
+                for index in 0u64.. {
                     let msg = read_prepend_length(&mut pipe_read).await?;
-                    let read_nonce = [0; 12]
+                    let nonce = [0; 12]
                         .tap_mut(|nonce| nonce[..8].copy_from_slice(&read_nonce.to_le_bytes()));
+                    read_nonce += 1;
                     let plaintext = read_aead
-                        .decrypt(&read_nonce.into(), msg.as_slice())
+                        .decrypt(&nonce.into(), msg.as_slice())
                         .ok()
                         .context("cannot decrypt")?;
-                    write_incoming.write_all(&plaintext).await?;
+                    if !plaintext.is_empty() {
+                        write_incoming.write_all(&plaintext).await?;
+                    } else if index == 0 {
+                        peer_rekeys_read.store(true, Ordering::Relaxed);
+                    } else if peer_rekeys_read.load(Ordering::Relaxed) {
+                        read_key = ratchet(&read_key);
+                        read_aead = ChaCha20Poly1305::new_from_slice(&read_key).unwrap();
+                        read_nonce = 0;
+                    }
                 }
                 anyhow::Ok(())
             };
@@ -137,16 +228,33 @@ impl ClientExitCryptPipe {
 
         let _write_task = smolscale::spawn(async move {
             let fallible = async {
-                let write_aead = ChaCha20Poly1305::new_from_slice(&write_key).unwrap();
+                let mut write_key = write_key;
+                let mut write_aead = ChaCha20Poly1305::new_from_slice(&write_key).unwrap();
+                let mut write_nonce = 0u64;
+                let mut last_rekey = Instant::now();
+                // the empty hello that tells the other side we can rekey
+                let hello = seal(&write_aead, &mut write_nonce, &[]);
+                write_prepend_length(&hello, &mut pipe_write).await?;
                 let mut buf = [0; 8192];
-                for write_nonce in 0u64.. {
-                    let write_nonce = [0; 12]
-                        .tap_mut(|nonce| nonce[..8].copy_from_slice(&write_nonce.to_le_bytes()));
+                loop {
                     let n = read_outgoing.read(&mut buf).await?;
-                    let ciphertext = write_aead.encrypt(&write_nonce.into(), &buf[..n]).unwrap();
+                    if n == 0 {
+                        // an empty message would now mean something else, so we just stop
+                        return anyhow::Ok(());
+                    }
+                    let ciphertext = seal(&write_aead, &mut write_nonce, &buf[..n]);
                     write_prepend_length(&ciphertext, &mut pipe_write).await?;
+                    if peer_rekeys.load(Ordering::Relaxed)
+                        && (write_nonce >= rekey_after || last_rekey.elapsed() >= REKEY_INTERVAL)
+                    {
+                        let rekey = seal(&write_aead, &mut write_nonce, &[]);
+                        write_prepend_length(&rekey, &mut pipe_write).await?;
+                        write_key = ratchet(&write_key);
+                        write_aead = ChaCha20Poly1305::new_from_slice(&write_key).unwrap();
+                        write_nonce = 0;
+                        last_rekey = Instant::now();
+                    }
                 }
-                anyhow::Ok(())
             };
             if let Err(_err) = fallible.await {
                 // todo handle error
@@ -163,6 +271,18 @@ impl ClientExitCryptPipe {
     }
 }
 
+/// Encrypts one message, consuming a nonce.
+fn seal(aead: &ChaCha20Poly1305, nonce: &mut u64, plaintext: &[u8]) -> Vec<u8> {
+    let nonce_bytes = [0; 12].tap_mut(|n| n[..8].copy_from_slice(&nonce.to_le_bytes()));
+    *nonce += 1;
+    aead.encrypt(&nonce_bytes.into(), plaintext).unwrap()
+}
+
+/// One step of the key ratchet. Being a one-way hash, it keeps the keys of earlier messages out of reach of anyone who later learns the current one.
+fn ratchet(key: &[u8; 32]) -> [u8; 32] {
+    blake3::derive_key("geph5 client-exit rekey", key)
+}
+
 impl Pipe for ClientExitCryptPipe {
     fn protocol(&self) -> &str {
         "plain"
@@ -172,3 +292,51 @@ impl Pipe for ClientExitCryptPipe {
         self.addr.as_deref()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sillad::{dialer::Dialer, listener::Listener, mem::MemListener};
+
+    use super::*;
+
+    #[test]
+    fn crypt_pipe_survives_rekeying() {
+        smolscale::block_on(async {
+            let mut listener = MemListener::new();
+            let client = listener.dialer().dial().await.unwrap();
+            let server = listener.accept().await.unwrap();
+            let (c2e, e2c) = ([1u8; 32], [2u8; 32]);
+            let mut client = ClientExitCryptPipe::new_with_rekey_after(client, e2c, c2e, 3);
+            let mut server = ClientExitCryptPipe::new_with_rekey_after(server, c2e, e2c, 3);
+
+            // make sure each side has heard the other's hello before the bulk of the data
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(b"pong").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+
+            let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+            let to_send = data.clone();
+            let writer = smolscale::spawn(async move {
+                for chunk in to_send.chunks(1000) {
+                    client.write_all(chunk).await.unwrap();
+                }
+                client
+            });
+            let mut received = vec![0u8; data.len()];
+            server.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, data);
+            writer.await;
+        })
+    }
+
+    #[test]
+    fn hybrid_secrets_agree() {
+        let (secret, encap_key) = KemSecret::generate();
+        let (ciphertext, exit_secret) = kem_encapsulate(&encap_key).unwrap();
+        assert_eq!(secret.decapsulate(&ciphertext).unwrap(), exit_secret);
+        assert!(kem_encapsulate(&encap_key[1..]).is_err());
+        assert!(secret.decapsulate(&ciphertext[1..]).is_err());
+    }
+}
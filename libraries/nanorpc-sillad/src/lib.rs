@@ -16,6 +16,7 @@ impl<D: Dialer> RpcTransport for DialerTransport<D> {
         let mut conn = self.0.dial().await?;
         conn.write_all(format!("{}\n", serde_json::to_string(&req)?).as_bytes())
             .await?;
+        conn.flush().await?;
         let mut conn = BufReader::new(conn);
         let mut line = String::new();
         conn.read_line(&mut line).await?;
@@ -47,6 +48,7 @@ pub async fn rpc_serve(
                                     format!("{}\n", serde_json::to_string(&resp)?).as_bytes(),
                                 )
                                 .await?;
+                            write.flush().await?;
                         }
                     })
                     .detach();
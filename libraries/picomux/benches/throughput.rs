@@ -0,0 +1,154 @@
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_util::{io::Cursor, AsyncRead, AsyncReadExt, AsyncWriteExt};
+use picomux::{
+    bench::{Frame, FrameReader, CMD_PSH, HEADER_LEN},
+    PicoMux,
+};
+
+/// How much goes through the mux per iteration, split evenly between the streams.
+const TRANSFER: usize = 4 << 20;
+
+/// Pushes data through a pair of muxes connected in memory, over the given number of streams at
+/// once, writing it in chunks of the given size.
+fn transfer(streams: usize, chunk: usize, iters: u64) -> Duration {
+    smolscale::block_on(async move {
+        let (a_write, b_read) = bipe::bipe(1 << 16);
+        let (b_write, a_read) = bipe::bipe(1 << 16);
+        let mux_a = PicoMux::new(a_read, a_write);
+        let mux_b = PicoMux::new(b_read, b_write);
+        let mut pairs = vec![];
+        for _ in 0..streams {
+            let stream_a = mux_a.open(b"").await.unwrap();
+            let stream_b = mux_b.accept().await.unwrap();
+            pairs.push((stream_a, stream_b));
+        }
+        let per_stream = TRANSFER / streams / chunk * chunk;
+
+        let start = Instant::now();
+        for _ in 0..iters {
+            futures_util::future::join_all(pairs.iter_mut().map(|(stream_a, stream_b)| {
+                let writer = async move {
+                    let data = vec![0x42u8; chunk];
+                    for _ in 0..per_stream / chunk {
+                        stream_a.write_all(&data).await.unwrap();
+                    }
+                    stream_a.flush().await.unwrap();
+                };
+                let reader = async move {
+                    let mut sink = vec![0u8; per_stream];
+                    stream_b.read_exact(&mut sink).await.unwrap();
+                };
+                futures_lite::future::zip(writer, reader)
+            }))
+            .await;
+        }
+        start.elapsed()
+    })
+}
+
+/// The baseline: pushes the same data through the in-memory pipe the muxes run over, with no mux
+/// in between, writing it in chunks of the given size.
+fn transfer_bare(chunk: usize, iters: u64) -> Duration {
+    smolscale::block_on(async move {
+        let (mut write, mut read) = bipe::bipe(1 << 16);
+        let total = TRANSFER / chunk * chunk;
+
+        let start = Instant::now();
+        for _ in 0..iters {
+            let writer = async {
+                let data = vec![0x42u8; chunk];
+                for _ in 0..total / chunk {
+                    write.write_all(&data).await.unwrap();
+                }
+                write.flush().await.unwrap();
+            };
+            let reader = async {
+                let mut sink = vec![0u8; total];
+                read.read_exact(&mut sink).await.unwrap();
+            };
+            futures_lite::future::zip(writer, reader).await;
+        }
+        start.elapsed()
+    })
+}
+
+/// Lays out enough frames with bodies of the given size to add up to [`TRANSFER`], as they would
+/// arrive on the wire.
+fn wire_frames(body_len: usize) -> Vec<u8> {
+    let frame = Frame::with_body(1, CMD_PSH, Bytes::from(vec![0x42u8; body_len]));
+    let mut wire = vec![];
+    for _ in 0..TRANSFER / body_len {
+        wire.extend_from_slice(&frame.header_bytes());
+        wire.extend_from_slice(&frame.body);
+    }
+    wire
+}
+
+/// The baseline: how frames used to be read, with two reads and a fresh allocation per frame.
+async fn read_per_frame(mut rdr: impl AsyncRead + Unpin) -> std::io::Result<Bytes> {
+    let mut header = [0u8; HEADER_LEN];
+    rdr.read_exact(&mut header).await?;
+    let mut body = vec![0; u16::from_ne_bytes([header[2], header[3]]) as usize];
+    rdr.read_exact(&mut body).await?;
+    Ok(body.into())
+}
+
+fn frame_decoding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_decoding");
+    group.throughput(Throughput::Bytes(TRANSFER as u64));
+    for body_len in [1400, 16384] {
+        let wire = wire_frames(body_len);
+        let frames = TRANSFER / body_len;
+        group.bench_with_input(BenchmarkId::new("per_frame", body_len), &wire, |b, wire| {
+            b.iter(|| {
+                futures_lite::future::block_on(async {
+                    let mut rdr = Cursor::new(wire.as_slice());
+                    for _ in 0..frames {
+                        read_per_frame(&mut rdr).await.unwrap();
+                    }
+                })
+            })
+        });
+        group.bench_with_input(
+            BenchmarkId::new("shared_buffer", body_len),
+            &wire,
+            |b, wire| {
+                b.iter(|| {
+                    futures_lite::future::block_on(async {
+                        let mut rdr = FrameReader::new(Cursor::new(wire.as_slice()));
+                        for _ in 0..frames {
+                            rdr.read().await.unwrap();
+                        }
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn mux_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("picomux");
+    group.throughput(Throughput::Bytes(TRANSFER as u64));
+    for chunk in [1400, 16384] {
+        group.bench_with_input(BenchmarkId::new("bare_pipe", chunk), &chunk, |b, &chunk| {
+            b.iter_custom(|iters| transfer_bare(chunk, iters))
+        });
+    }
+    for streams in [1, 16] {
+        for chunk in [1400, 16384] {
+            group.bench_with_input(
+                BenchmarkId::new(format!("{streams}_streams"), chunk),
+                &chunk,
+                |b, &chunk| b.iter_custom(|iters| transfer(streams, chunk, iters)),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, frame_decoding, mux_throughput);
+criterion_main!(benches);
@@ -21,6 +21,7 @@ type Inner = DashMap<
 #[derive(Clone)]
 pub struct BufferTable {
     inner: Arc<Inner>,
+    removed: Arc<async_event::Event>,
 }
 
 impl BufferTable {
@@ -29,6 +30,7 @@ impl BufferTable {
             inner: Arc::new(DashMap::with_hasher(
                 BuildHasherDefault::<AHasher>::default(),
             )),
+            removed: Default::default(),
         }
     }
 
@@ -36,6 +38,29 @@ impl BufferTable {
         self.inner.contains_key(&id)
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Waits until every stream's buffer is gone.
+    pub async fn wait_empty(&self) {
+        self.removed
+            .wait_until(|| self.is_empty().then_some(()))
+            .await
+    }
+
+    /// How many frames the given stream may still send before it has to wait for the peer.
+    pub fn send_window(&self, stream_id: u32) -> usize {
+        self.inner
+            .get(&stream_id)
+            .map(|inner| inner.1.permits())
+            .unwrap_or_default()
+    }
+
     pub fn create_entry(&self, stream_id: u32) -> BufferReceive {
         let (send_incoming, recv_incoming) = async_channel::unbounded::<(Frame, Instant)>();
         let send_more = SharedSemaphore::new(false, INIT_WINDOW);
@@ -45,6 +70,7 @@ impl BufferTable {
             recv: recv_incoming,
 
             inner: self.inner.clone(),
+            removed: self.removed.clone(),
 
             queue_delay: None,
         }
@@ -96,6 +122,7 @@ pub struct BufferReceive {
     id: u32,
     recv: async_channel::Receiver<(Frame, Instant)>,
     inner: Arc<Inner>,
+    removed: Arc<async_event::Event>,
 
     queue_delay: Option<Duration>,
 }
@@ -118,5 +145,6 @@ impl BufferReceive {
 impl Drop for BufferReceive {
     fn drop(&mut self) {
         self.inner.remove(&self.id);
+        self.removed.notify_all();
     }
 }
@@ -0,0 +1,60 @@
+use std::ops::{BitAnd, BitOr};
+
+/// Optional parts of the wire protocol. Each end offers its features in its PINGs, starting with
+/// the one it sends as soon as the mux starts, and a feature is only used once both ends offer it.
+///
+/// New frame types should come with a new feature rather than a new [`crate::frame::Header`]
+/// version, since peers drop frames they haven't negotiated instead of failing on them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Features(u32);
+
+impl Features {
+    /// Unreliable datagrams on a stream's flow.
+    pub const DATAGRAMS: Self = Self(1);
+    /// Refusing streams with a reason, and acknowledging accepted ones.
+    pub const RESETS: Self = Self(1 << 1);
+    /// Announcing a graceful shutdown.
+    pub const GOAWAY: Self = Self(1 << 2);
+    /// FIN closing only the direction it was sent in, rather than the whole stream. Only used
+    /// along with [`Features::RESETS`], since it takes an RST to close a half-closed stream for good.
+    pub const HALF_CLOSE: Self = Self(1 << 3);
+
+    /// No optional features, which is what a peer from before negotiation existed offers.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Every optional feature this version understands.
+    pub const fn all() -> Self {
+        Self(Self::DATAGRAMS.0 | Self::RESETS.0 | Self::GOAWAY.0 | Self::HALF_CLOSE.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Drops the features that can't be used without others that are missing.
+    pub(crate) const fn consistent(self) -> Self {
+        if self.contains(Self::RESETS) {
+            self
+        } else {
+            Self(self.0 & !Self::HALF_CLOSE.0)
+        }
+    }
+}
+
+impl BitOr for Features {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Features {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
@@ -1,8 +1,12 @@
+use std::io::IoSlice;
+
 use bytemuck::{Pod, Zeroable};
-use bytes::Bytes;
-use futures_util::{AsyncRead, AsyncReadExt};
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use serde::{Deserialize, Serialize};
 
+use crate::Features;
+
 #[derive(Clone, Debug)]
 pub struct Frame {
     pub header: Header,
@@ -23,8 +27,14 @@ impl Frame {
         }
     }
 
-    /// Create an frame with the given stream ID, command, and body
+    /// Create a frame with the given stream ID, command, and body, copying the body. Meant for
+    /// small control frames; data should go through [`Frame::with_body`].
     pub fn new(stream_id: u32, command: u8, body: &[u8]) -> Self {
+        Self::with_body(stream_id, command, Bytes::copy_from_slice(body))
+    }
+
+    /// Create a frame with the given stream ID, command, and body, without copying the body.
+    pub fn with_body(stream_id: u32, command: u8, body: Bytes) -> Self {
         Self {
             header: Header {
                 version: 1,
@@ -32,31 +42,104 @@ impl Frame {
                 body_len: body.len() as _,
                 stream_id,
             },
-            body: Bytes::copy_from_slice(body),
+            body,
         }
     }
 
-    /// Read a frame from an async reader.
-    pub async fn read(mut rdr: impl AsyncRead + Unpin) -> std::io::Result<Self> {
-        let mut header_buf = [0; std::mem::size_of::<Header>()];
-        rdr.read_exact(&mut header_buf).await?;
-        let header: Header = bytemuck::cast(header_buf);
-        let len = header.body_len as usize;
-        let mut body = vec![0; len];
-        rdr.read_exact(&mut body).await?;
-        Ok(Self {
-            header,
-            body: body.into(),
-        })
+    /// The wire representation of the header. The body follows it directly.
+    pub fn header_bytes(&self) -> [u8; HEADER_LEN] {
+        bytemuck::cast(self.header)
+    }
+
+    /// How many bytes the frame takes up on the wire.
+    pub fn wire_len(&self) -> usize {
+        HEADER_LEN + self.body.len()
+    }
+}
+
+/// Reads frames out of a connection through one shared buffer. Frame bodies are slices of that
+/// buffer rather than allocations of their own, and the buffer gets reused in place once every
+/// body sliced out of it has been dropped.
+pub struct FrameReader<R> {
+    inner: R,
+    /// Everything past `filled` is spare room that has been zeroed, or holds stale bytes from
+    /// earlier reads, so reads can go straight into it.
+    buf: BytesMut,
+    filled: usize,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::with_capacity(READ_CHUNK),
+            filled: 0,
+        }
     }
 
-    /// The bytes representation of the frame.
-    pub fn bytes(&self) -> Bytes {
-        let mut buf = vec![0; self.body.len() + std::mem::size_of::<Header>()];
-        buf[..std::mem::size_of::<Header>()].copy_from_slice(bytemuck::bytes_of(&self.header));
-        buf[std::mem::size_of::<Header>()..].copy_from_slice(&self.body);
-        buf.into()
+    /// Reads the next frame.
+    pub async fn read(&mut self) -> std::io::Result<Frame> {
+        self.fill(HEADER_LEN).await?;
+        let header: Header = bytemuck::pod_read_unaligned(&self.buf[..HEADER_LEN]);
+        self.fill(HEADER_LEN + header.body_len as usize).await?;
+        self.buf.advance(HEADER_LEN);
+        let body = self.buf.split_to(header.body_len as usize).freeze();
+        self.filled -= HEADER_LEN + body.len();
+        Ok(Frame { header, body })
+    }
+
+    /// Reads until at least `want` bytes are buffered.
+    async fn fill(&mut self, want: usize) -> std::io::Result<()> {
+        while self.filled < want {
+            if self.buf.len() < want {
+                // zeroing the new room costs a memset per chunk, cheap while it's still in cache
+                self.buf.resize(want + READ_CHUNK, 0);
+            }
+            let n = self.inner.read(&mut self.buf[self.filled..]).await?;
+            self.filled += n;
+            if n == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "connection closed in the middle of a frame",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes a batch of frames, handing each header and body to the writer where they are instead
+/// of copying them into one buffer. Returns how many bytes were written.
+pub async fn write_frames(
+    mut write: impl AsyncWrite + Unpin,
+    frames: &[Frame],
+) -> std::io::Result<usize> {
+    assert!(frames.len() <= MAX_WRITE_BATCH);
+    let mut headers = [[0u8; HEADER_LEN]; MAX_WRITE_BATCH];
+    for (header, frame) in headers.iter_mut().zip(frames) {
+        *header = frame.header_bytes();
+    }
+    let mut slices = [IoSlice::new(&[]); 2 * MAX_WRITE_BATCH];
+    let mut count = 0;
+    for (header, frame) in headers.iter().zip(frames) {
+        slices[count] = IoSlice::new(header);
+        count += 1;
+        // an empty slice would leave a zero-length write at the end
+        if !frame.body.is_empty() {
+            slices[count] = IoSlice::new(&frame.body);
+            count += 1;
+        }
     }
+    let total = frames.iter().map(Frame::wire_len).sum();
+    let mut remaining = &mut slices[..count];
+    while !remaining.is_empty() {
+        let n = write.write_vectored(remaining).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut remaining, n);
+    }
+    Ok(total)
 }
 
 #[derive(Clone, Copy, Pod, PartialEq, Zeroable, Debug)]
@@ -68,16 +151,111 @@ pub struct Header {
     pub stream_id: u32,
 }
 
+pub const HEADER_LEN: usize = std::mem::size_of::<Header>();
+
+/// How much [`FrameReader`] asks the connection for at a time. Small enough that the room it
+/// zeroes for each read is still in cache when the read fills it.
+const READ_CHUNK: usize = 16384;
+
+/// The most frames [`write_frames`] takes at once.
+pub const MAX_WRITE_BATCH: usize = 16;
+
 pub const CMD_SYN: u8 = 0;
 pub const CMD_FIN: u8 = 1;
 pub const CMD_PSH: u8 = 2;
 pub const CMD_NOP: u8 = 3;
 pub const CMD_MORE: u8 = 4;
+pub const CMD_DGRAM: u8 = 5;
+pub const CMD_RST: u8 = 6;
+pub const CMD_ACK: u8 = 7;
+pub const CMD_GOAWAY: u8 = 8;
 
 pub const CMD_PING: u8 = 0xa0;
 pub const CMD_PONG: u8 = 0xa1;
 
+/// The body of a PING. Each optional feature is a separate flag, so that peers skip the flags they
+/// don't know and read missing ones as unsupported.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PingInfo {
     pub next_ping_in_ms: u32,
+    /// Whether the sender understands CMD_DGRAM. Older peers leave this out, and ignore it when we send it.
+    #[serde(default)]
+    pub datagrams: bool,
+    /// Whether the sender understands CMD_RST and CMD_ACK.
+    #[serde(default)]
+    pub resets: bool,
+    /// Whether the sender understands CMD_GOAWAY.
+    #[serde(default)]
+    pub goaway: bool,
+    /// Whether the sender treats CMD_FIN as closing only the direction it was sent in. Older peers
+    /// close the whole stream on FIN.
+    #[serde(default)]
+    pub half_close: bool,
+}
+
+impl PingInfo {
+    pub fn new(next_ping_in_ms: u32, features: Features) -> Self {
+        Self {
+            next_ping_in_ms,
+            datagrams: features.contains(Features::DATAGRAMS),
+            resets: features.contains(Features::RESETS),
+            goaway: features.contains(Features::GOAWAY),
+            half_close: features.contains(Features::HALF_CLOSE),
+        }
+    }
+
+    /// The features the sender offers.
+    pub fn features(&self) -> Features {
+        [
+            (self.datagrams, Features::DATAGRAMS),
+            (self.resets, Features::RESETS),
+            (self.goaway, Features::GOAWAY),
+            (self.half_close, Features::HALF_CLOSE),
+        ]
+        .into_iter()
+        .filter(|(offered, _)| *offered)
+        .fold(Features::empty(), |acc, (_, feature)| acc | feature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out at most a few bytes per read, so frames straddle reads.
+    struct Trickle(futures_lite::io::Cursor<Vec<u8>>);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let len = buf.len().min(3);
+            std::pin::Pin::new(&mut self.0).poll_read(cx, &mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        futures_lite::future::block_on(async {
+            let frames = vec![
+                Frame::new_empty(1, CMD_SYN),
+                Frame::new(1, CMD_PSH, b"hello"),
+                Frame::with_body(2, CMD_PSH, Bytes::from(vec![7u8; 65535])),
+                Frame::new_empty(1, CMD_FIN),
+            ];
+            let mut wire = vec![];
+            let written = write_frames(&mut wire, &frames).await.unwrap();
+            assert_eq!(written, wire.len());
+
+            let mut reader = FrameReader::new(Trickle(futures_lite::io::Cursor::new(wire)));
+            for frame in &frames {
+                let read = reader.read().await.unwrap();
+                assert_eq!(read.header, frame.header);
+                assert_eq!(read.body, frame.body);
+            }
+            assert!(reader.read().await.is_err());
+        })
+    }
 }
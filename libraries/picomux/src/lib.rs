@@ -1,17 +1,29 @@
 mod bdp;
 mod buffer_table;
+mod features;
 mod frame;
 mod outgoing;
+mod reset;
+mod stats;
+
+/// Internals that only the benchmarks reach into, to compare frame decoding against reading
+/// frames one by one. Not part of the API.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::frame::{Frame, FrameReader, CMD_PSH, HEADER_LEN};
+}
 
 use std::{
+    collections::VecDeque,
     convert::Infallible,
     fmt::Debug,
     io::ErrorKind,
     ops::Deref,
     pin::Pin,
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, OnceLock,
     },
     task::Poll,
     time::{Duration, Instant},
@@ -25,19 +37,27 @@ use atomic_float::AtomicF64;
 use bdp::BwEstimate;
 use buffer_table::BufferTable;
 use bytes::Bytes;
-use frame::{Frame, CMD_FIN, CMD_MORE, CMD_NOP, CMD_PING, CMD_PONG, CMD_PSH, CMD_SYN};
-use futures_lite::{Future, FutureExt as LiteExt};
-use futures_util::{
-    future::Shared, io::BufReader, AsyncRead, AsyncWrite, AsyncWriteExt, FutureExt,
+use dashmap::DashMap;
+pub use features::Features;
+use frame::{
+    Frame, FrameReader, CMD_ACK, CMD_DGRAM, CMD_FIN, CMD_GOAWAY, CMD_MORE, CMD_NOP, CMD_PING,
+    CMD_PONG, CMD_PSH, CMD_RST, CMD_SYN,
 };
+use futures_lite::{Future, FutureExt as LiteExt};
+use futures_util::{future::Shared, AsyncRead, AsyncWrite, AsyncWriteExt, FutureExt};
 
 use async_io::Timer;
 use outgoing::Outgoing;
+pub use outgoing::Priority;
 use parking_lot::Mutex;
 use pin_project::pin_project;
 use rand::Rng;
+use reset::Verdict;
+pub use reset::{ResetCode, StreamReset};
 use smol_timeout2::TimeoutExt;
 use smolscale::reaper::TaskReaper;
+use stats::StreamCounters;
+pub use stats::{MuxStats, StreamStats};
 use tachyonix::{Receiver, Sender};
 use tap::Tap;
 
@@ -46,6 +66,10 @@ use crate::frame::{Header, PingInfo};
 const INIT_WINDOW: usize = 10;
 const MAX_WINDOW: usize = 1500;
 const MSS: usize = 8192;
+/// How many received datagrams a stream holds before dropping new ones.
+const DGRAM_BUFFER: usize = 100;
+/// How many ping round-trip times [`PicoMux::stats`] remembers.
+const PING_HISTORY: usize = 16;
 
 #[derive(Clone, Copy, Debug)]
 pub struct LivenessConfig {
@@ -64,13 +88,57 @@ impl Default for LivenessConfig {
 
 pub struct PicoMux {
     task: Shared<Task<Arc<std::io::Result<Infallible>>>>,
-    send_open_req: Sender<(Bytes, oneshot::Sender<Stream>)>,
+    send_open_req: Sender<(Bytes, Priority, oneshot::Sender<Stream>)>,
 
     recv_accepted: async_channel::Receiver<Stream>,
     send_liveness: async_channel::Sender<LivenessConfig>,
     liveness: LivenessConfig,
 
-    last_ping: Arc<Mutex<Option<Duration>>>,
+    state: Arc<MuxState>,
+    buffer_table: BufferTable,
+    enqueue: Box<dyn Fn(Frame) + Send + Sync + 'static>,
+    outgoing_stats: Box<dyn Fn() -> (usize, u64) + Send + Sync + 'static>,
+}
+
+/// State shared between a [`PicoMux`], its background task, and its streams.
+#[derive(Default)]
+struct MuxState {
+    /// Round-trip times of the latest pings, oldest first.
+    pings: Mutex<VecDeque<Duration>>,
+    bytes_in: AtomicU64,
+    /// The latest bandwidth estimate, which new streams start from.
+    bw_estimate: AtomicF64,
+
+    /// What we offer the peer.
+    features: Features,
+    /// What the peer offered in its first PING.
+    peer_features: OnceLock<Features>,
+    negotiated_event: async_event::Event,
+
+    /// Set once we've started shutting down gracefully.
+    going_away: AtomicBool,
+    /// Set to the peer's deadline once it sends GOAWAY.
+    peer_going_away: OnceLock<Instant>,
+    goaway_event: async_event::Event,
+}
+
+impl MuxState {
+    /// The features both ends offered. Empty until the peer's first PING arrives.
+    fn negotiated(&self) -> Features {
+        (self.features & self.peer_features.get().copied().unwrap_or_default()).consistent()
+    }
+
+    fn supports(&self, feature: Features) -> bool {
+        self.negotiated().contains(feature)
+    }
+}
+
+/// Which directions of a stream have been closed with a FIN.
+#[derive(Default)]
+struct Closing {
+    fin_sent: AtomicBool,
+    fin_received: AtomicBool,
+    event: async_event::Event,
 }
 
 impl PicoMux {
@@ -78,21 +146,40 @@ impl PicoMux {
     pub fn new(
         read: impl AsyncRead + 'static + Send + Unpin,
         write: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> Self {
+        Self::with_features(read, write, Features::all())
+    }
+
+    /// Creates a new picomux like [`PicoMux::new`], but only offering the peer the given optional
+    /// features. This is useful for rolling features out gradually.
+    pub fn with_features(
+        read: impl AsyncRead + 'static + Send + Unpin,
+        write: impl AsyncWrite + Send + Unpin + 'static,
+        features: Features,
     ) -> Self {
         let (send_open_req, recv_open_req) = tachyonix::channel(1);
         let (send_accepted, recv_accepted) = async_channel::bounded(100);
         let (send_liveness, recv_liveness) = async_channel::unbounded();
         let liveness = LivenessConfig::default();
         send_liveness.try_send(liveness).unwrap();
-        let last_ping = Arc::new(Mutex::new(None));
+        let state = Arc::new(MuxState {
+            bw_estimate: AtomicF64::new(1_000_000.0),
+            features,
+            ..Default::default()
+        });
+        let outgoing = Outgoing::new(write);
+        let enqueue = Box::new(outgoing.weak_enqueuer());
+        let outgoing_stats = Box::new(outgoing.stats_reader());
+        let buffer_table = BufferTable::new();
         let task = smolscale::spawn(
             picomux_inner(
                 read,
-                write,
+                outgoing,
+                buffer_table.clone(),
                 send_accepted,
                 recv_open_req,
                 recv_liveness,
-                last_ping.clone(),
+                state.clone(),
             )
             .map(Arc::new),
         )
@@ -105,7 +192,10 @@ impl PicoMux {
             send_liveness,
             liveness,
 
-            last_ping,
+            state,
+            buffer_table,
+            enqueue,
+            outgoing_stats,
         }
     }
 
@@ -141,11 +231,107 @@ impl PicoMux {
 
     /// Reads the latency from the last successful ping.
     pub fn last_latency(&self) -> Option<Duration> {
-        *self.last_ping.lock()
+        self.state.pings.lock().back().copied()
+    }
+
+    /// Takes a snapshot of the mux's traffic counters and health.
+    pub fn stats(&self) -> MuxStats {
+        let (queued_frames, bytes_out) = (self.outgoing_stats)();
+        MuxStats {
+            open_streams: self.buffer_table.len(),
+            bytes_in: self.state.bytes_in.load(Ordering::Relaxed),
+            bytes_out,
+            queued_frames,
+            bw_estimate: self.state.bw_estimate.load(Ordering::Relaxed),
+            ping_history: self.state.pings.lock().iter().copied().collect(),
+        }
+    }
+
+    /// Returns the optional features both ends agreed on. This is empty until the peer's first
+    /// PING arrives, and stays empty for peers from before negotiation existed.
+    pub fn features(&self) -> Features {
+        self.state.negotiated()
+    }
+
+    /// Waits until the peer has said which features it offers, returning the ones both ends agreed on.
+    pub async fn wait_negotiated(&self) -> std::io::Result<Features> {
+        async {
+            self.state
+                .negotiated_event
+                .wait_until(|| self.state.peer_features.get().map(|_| ()))
+                .await;
+            Ok(self.state.negotiated())
+        }
+        .race(self.wait_error())
+        .await
+    }
+
+    /// Returns whether datagrams were negotiated. Until they are, [`Stream::send_datagram`] drops
+    /// everything, so callers should fall back to framing over streams.
+    pub fn peer_supports_datagrams(&self) -> bool {
+        self.state.supports(Features::DATAGRAMS)
+    }
+
+    /// Returns whether stream resets and acknowledgements were negotiated. Until they are,
+    /// [`Stream::reset`] just closes the stream, and [`Stream::wait_accepted`] returns right away.
+    pub fn peer_supports_resets(&self) -> bool {
+        self.state.supports(Features::RESETS)
+    }
+
+    /// Tells the peer to stop opening streams on this mux, then waits until the streams already
+    /// open have all finished, or until the deadline passes. The caller should drop the mux
+    /// afterwards. New streams can't be opened on this side either once this is called.
+    pub async fn shutdown_gracefully(&self, deadline: Instant) {
+        self.state.going_away.store(true, Ordering::SeqCst);
+        if self.state.supports(Features::GOAWAY) {
+            let remaining_ms = deadline
+                .saturating_duration_since(Instant::now())
+                .as_millis();
+            (self.enqueue)(Frame::new(
+                0,
+                CMD_GOAWAY,
+                &(remaining_ms.min(u32::MAX as u128) as u32).to_le_bytes(),
+            ));
+        }
+        self.buffer_table
+            .wait_empty()
+            .or(async {
+                Timer::at(deadline).await;
+            })
+            .or(async {
+                let _ = self.wait_error::<()>().await;
+            })
+            .await
+    }
+
+    /// Waits until the peer sends GOAWAY, returning the deadline by which it will close the mux.
+    /// Streams already open keep working until then, but new ones should go elsewhere.
+    pub async fn wait_peer_goaway(&self) -> Instant {
+        self.state
+            .goaway_event
+            .wait_until(|| self.state.peer_going_away.get().copied())
+            .await
     }
 
     /// Opens a new stream to the peer, putting the given metadata in the stream.
     pub async fn open(&self, metadata: &[u8]) -> std::io::Result<Stream> {
+        self.open_with_priority(metadata, Priority::default()).await
+    }
+
+    /// Opens a new stream to the peer, like [`PicoMux::open`], but with a given sending priority.
+    pub async fn open_with_priority(
+        &self,
+        metadata: &[u8],
+        priority: Priority,
+    ) -> std::io::Result<Stream> {
+        if self.state.going_away.load(Ordering::SeqCst)
+            || self.state.peer_going_away.get().is_some()
+        {
+            return Err(std::io::Error::new(
+                ErrorKind::NotConnected,
+                "mux is going away",
+            ));
+        }
         {
             tracing::debug!("forcing a ping based on open");
             let _ = self.send_liveness.try_send(self.liveness);
@@ -153,7 +339,7 @@ impl PicoMux {
         let (send, recv) = oneshot::channel();
         let _ = self
             .send_open_req
-            .send((Bytes::copy_from_slice(metadata), send))
+            .send((Bytes::copy_from_slice(metadata), priority, send))
             .await;
         async {
             if let Ok(val) = recv.await {
@@ -183,49 +369,126 @@ static MUX_ID_CTR: AtomicU64 = AtomicU64::new(0);
 #[tracing::instrument(skip_all, fields(mux_id=MUX_ID_CTR.fetch_add(1, Ordering::Relaxed)))]
 async fn picomux_inner(
     read: impl AsyncRead + 'static + Send + Unpin,
-    write: impl AsyncWrite + Send + Unpin + 'static,
+    outgoing: Outgoing,
+    buffer_table: BufferTable,
     send_accepted: async_channel::Sender<Stream>,
-    mut recv_open_req: Receiver<(Bytes, oneshot::Sender<Stream>)>,
+    mut recv_open_req: Receiver<(Bytes, Priority, oneshot::Sender<Stream>)>,
     recv_liveness: async_channel::Receiver<LivenessConfig>,
-    last_ping: Arc<Mutex<Option<Duration>>>,
+    state: Arc<MuxState>,
 ) -> Result<Infallible, std::io::Error> {
     let reaper = TaskReaper::new();
-    let mut inner_read = BufReader::with_capacity(MSS * 4, read);
+    let mut inner_read = FrameReader::new(read);
 
-    let outgoing = Outgoing::new(write);
     let (send_pong, recv_pong) = async_channel::unbounded();
-    let buffer_table = BufferTable::new();
+    let datagram_table: Arc<DashMap<u32, async_channel::Sender<Bytes>>> = Default::default();
 
-    let last_bw_estimate = Arc::new(AtomicF64::new(1_000_000.0));
-
-    let create_stream = |stream_id, metadata: Bytes| {
+    let create_stream = |stream_id, metadata: Bytes, priority, incoming: bool| {
         let mut buffer_recv = buffer_table.create_entry(stream_id);
+        outgoing.register(stream_id, priority);
+        let (send_datagram, recv_datagram) = async_channel::bounded(DGRAM_BUFFER);
+        datagram_table.insert(stream_id, send_datagram);
+        let verdict = Arc::new(Verdict::default());
+        let closing = Arc::new(Closing::default());
+        let counters = Arc::new(StreamCounters::new(INIT_WINDOW));
+        let (send_dropped, recv_dropped) = oneshot::channel::<()>();
+        let send_fin = {
+            let outgoing = outgoing.clone();
+            let closing = closing.clone();
+            move || {
+                if !closing.fin_sent.swap(true, Ordering::SeqCst) {
+                    tracing::debug!(stream_id, "enqueuing FIN to the other side");
+                    outgoing.enqueue(Frame::new_empty(stream_id, CMD_FIN));
+                }
+            }
+        };
+        // the accepting side acknowledges the stream the first time it reads or writes it
+        let send_ack: Option<Box<dyn FnOnce() + Send + Sync>> = if incoming {
+            let enqueue = outgoing.weak_enqueuer();
+            let state = state.clone();
+            Some(Box::new(move || {
+                if state.supports(Features::RESETS) {
+                    enqueue(Frame::new_empty(stream_id, CMD_ACK));
+                }
+            }))
+        } else {
+            None
+        };
         let (mut write_incoming, read_incoming) = bipe::bipe(MSS * 2);
         let (write_outgoing, mut read_outgoing) = bipe::bipe(MSS * 2);
         let stream = Stream {
             write_outgoing,
             read_incoming,
             metadata,
+            set_priority: Box::new(outgoing.priority_setter(stream_id)),
+            send_datagram: Box::new(outgoing.datagram_sender(stream_id)),
+            recv_datagram,
+            verdict: verdict.clone(),
+            state: state.clone(),
+            send_ack,
+            send_reset: Box::new({
+                let enqueue = outgoing.weak_enqueuer();
+                move |reset: StreamReset| {
+                    enqueue(Frame::with_body(stream_id, CMD_RST, reset.encode().into()))
+                }
+            }),
             on_write: Box::new(|_| {}),
             on_read: Box::new(|_| {}),
+            counters: counters.clone(),
+            send_window: Box::new({
+                let buffer_table = buffer_table.clone();
+                move || buffer_table.send_window(stream_id)
+            }),
+            _dropped: send_dropped,
         };
 
         // jelly bean movers
         let outgoing_task = {
             let outgoing = outgoing.clone();
-            let last_bw_estimate = last_bw_estimate.clone();
+            let verdict = verdict.clone();
+            let closing = closing.clone();
+            let state = state.clone();
+            let counters = counters.clone();
             async move {
                 let mut remote_window = INIT_WINDOW;
                 let mut target_remote_window = MAX_WINDOW;
 
-                let mut bw_estimate = BwEstimate::new(last_bw_estimate.load(Ordering::Relaxed));
+                let mut bw_estimate = BwEstimate::new(state.bw_estimate.load(Ordering::Relaxed));
                 loop {
                     let min_quantum = (target_remote_window / 10).clamp(1, 500);
                     let frame = buffer_recv.recv().await;
-                    if frame.header.command == CMD_FIN {
-                        anyhow::bail!("received remote FIN");
+                    match frame.header.command {
+                        CMD_FIN => {
+                            if !state.supports(Features::HALF_CLOSE) {
+                                anyhow::bail!("received remote FIN");
+                            }
+                            // the peer is done writing, but may still be reading
+                            write_incoming
+                                .close()
+                                .await
+                                .context("could not close incoming")?;
+                            closing.fin_received.store(true, Ordering::SeqCst);
+                            closing.event.notify_all();
+                            continue;
+                        }
+                        CMD_RST if closing.fin_received.load(Ordering::SeqCst) => {
+                            // after a FIN this only means the peer stopped reading, so our reads
+                            // still end cleanly
+                            anyhow::bail!("peer closed the stream");
+                        }
+                        CMD_RST => {
+                            let reset = StreamReset::decode(&frame.body);
+                            tracing::debug!(stream_id, reset = debug(&reset), "RST received");
+                            verdict.reset(reset);
+                            anyhow::bail!("received remote RST");
+                        }
+                        CMD_ACK => {
+                            verdict.accept();
+                            continue;
+                        }
+                        _ => {}
                     }
                     let queue_delay = buffer_recv.queue_delay().unwrap();
+                    counters.queue_delay.lock().replace(queue_delay);
                     tracing::trace!(
                         stream_id,
                         queue_delay = debug(queue_delay),
@@ -234,6 +497,9 @@ async fn picomux_inner(
                         "queue delay measured"
                     );
                     bw_estimate.sample(frame.body.len());
+                    counters
+                        .bytes_in
+                        .fetch_add(frame.body.len() as u64, Ordering::Relaxed);
                     write_incoming
                         .write_all(&frame.body)
                         .await
@@ -242,7 +508,7 @@ async fn picomux_inner(
 
                     // assume the delay is 500ms
                     let estimate = bw_estimate.read();
-                    last_bw_estimate.store(estimate, Ordering::Relaxed);
+                    state.bw_estimate.store(estimate, Ordering::Relaxed);
                     target_remote_window =
                         ((estimate / MSS as f64 / 2.0) as usize).clamp(INIT_WINDOW, MAX_WINDOW);
                     tracing::debug!(
@@ -267,6 +533,7 @@ async fn picomux_inner(
                         );
                         remote_window += quantum;
                     }
+                    counters.recv_window.store(remote_window, Ordering::Relaxed);
                 }
             }
         };
@@ -274,12 +541,29 @@ async fn picomux_inner(
         let incoming_task = {
             let buffer_table = buffer_table.clone();
             let outgoing = outgoing.clone();
+            let closing = closing.clone();
+            let send_fin = send_fin.clone();
+            let counters = counters.clone();
             async move {
                 loop {
-                    let body = async_io_bufpool::pooled_read(&mut read_outgoing, 8192)
+                    let Some(body) = async_io_bufpool::pooled_read(&mut read_outgoing, 8192)
                         .await
                         .context("could not read_outgoing")?
-                        .context("EOF on read_outgoing")?;
+                    else {
+                        send_fin();
+                        // keep delivering what the peer sends until it's done too, or until
+                        // nobody is left to read it
+                        closing
+                            .event
+                            .wait_until(|| {
+                                closing.fin_received.load(Ordering::SeqCst).then_some(())
+                            })
+                            .or(async {
+                                let _ = recv_dropped.await;
+                            })
+                            .await;
+                        return Ok(());
+                    };
 
                     tracing::trace!(
                         stream_id,
@@ -296,6 +580,9 @@ async fn picomux_inner(
                         body,
                     };
                     buffer_table.wait_send_window(stream_id).await;
+                    counters
+                        .bytes_out
+                        .fetch_add(frame.body.len() as u64, Ordering::Relaxed);
                     outgoing.send(frame).await?;
                 }
             }
@@ -303,18 +590,29 @@ async fn picomux_inner(
 
         {
             let outgoing = outgoing.clone();
+            let datagram_table = datagram_table.clone();
+            let state = state.clone();
             reaper.attach(smolscale::spawn(async move {
                 scopeguard::defer!({
-                    tracing::debug!(stream_id, "enqueuing FIN to the other side");
-                    outgoing.enqueue(Frame {
-                        header: Header {
-                            version: 1,
-                            command: CMD_FIN,
-                            body_len: 0,
+                    datagram_table.remove(&stream_id);
+                    verdict.close();
+                    send_fin();
+                    // a half-closing peer would otherwise keep writing into a stream nobody reads.
+                    // it comes after our FIN, so the peer doesn't treat it as a refusal
+                    if state.supports(Features::HALF_CLOSE)
+                        && !closing.fin_received.load(Ordering::SeqCst)
+                        && verdict.get_reset().is_none()
+                    {
+                        let reset = StreamReset {
+                            code: ResetCode::General,
+                            reason: "stream closed".into(),
+                        };
+                        outgoing.enqueue(Frame::with_body(
                             stream_id,
-                        },
-                        body: Bytes::new(),
-                    });
+                            CMD_RST,
+                            reset.encode().into(),
+                        ));
+                    }
                 });
                 let _: anyhow::Result<()> =
                     incoming_task.race(outgoing_task).await.inspect_err(|e| {
@@ -331,7 +629,7 @@ async fn picomux_inner(
     // receive open requests
     let open_req_loop = async {
         loop {
-            let (metadata, request) = recv_open_req.recv().await.map_err(|_e| {
+            let (metadata, priority, request) = recv_open_req.recv().await.map_err(|_e| {
                 std::io::Error::new(ErrorKind::BrokenPipe, "open request channel died")
             })?;
             let stream_id = {
@@ -344,7 +642,7 @@ async fn picomux_inner(
                 f.body = metadata.clone();
                 f.header.body_len = metadata.len() as _;
             }));
-            let stream = create_stream(stream_id, metadata);
+            let stream = create_stream(stream_id, metadata, priority, false);
 
             let _ = request.send(stream);
         }
@@ -366,9 +664,10 @@ async fn picomux_inner(
             .await
             {
                 lc = Some(info);
-                let ping_body = serde_json::to_vec(&PingInfo {
-                    next_ping_in_ms: info.ping_interval.as_millis() as _,
-                })
+                let ping_body = serde_json::to_vec(&PingInfo::new(
+                    info.ping_interval.as_millis() as _,
+                    state.features,
+                ))
                 .unwrap();
                 outgoing.enqueue(Frame {
                     header: Header {
@@ -387,7 +686,11 @@ async fn picomux_inner(
                     ));
                 }
                 tracing::info!(latency = debug(start.elapsed()), "PONG received");
-                last_ping.lock().replace(start.elapsed());
+                let mut pings = state.pings.lock();
+                if pings.len() == PING_HISTORY {
+                    pings.pop_front();
+                }
+                pings.push_back(start.elapsed());
             } else {
                 return futures_util::future::pending().await;
             }
@@ -398,7 +701,10 @@ async fn picomux_inner(
         .race(ping_loop)
         .race(async {
             loop {
-                let frame = Frame::read(&mut inner_read).await?;
+                let frame = inner_read.read().await?;
+                state
+                    .bytes_in
+                    .fetch_add(frame.wire_len() as u64, Ordering::Relaxed);
                 let stream_id = frame.header.stream_id;
                 tracing::trace!(
                     command = frame.header.command,
@@ -406,6 +712,22 @@ async fn picomux_inner(
                     body_len = frame.header.body_len,
                     "got incoming frame"
                 );
+                let needed = match frame.header.command {
+                    CMD_DGRAM => Features::DATAGRAMS,
+                    CMD_RST | CMD_ACK => Features::RESETS,
+                    CMD_GOAWAY => Features::GOAWAY,
+                    _ => Features::empty(),
+                };
+                if frame.header.version != 1 || !state.supports(needed) {
+                    // the peer is newer than us, or confused, but not necessarily broken
+                    tracing::debug!(
+                        version = frame.header.version,
+                        command = frame.header.command,
+                        stream_id,
+                        "skipping a frame we didn't negotiate"
+                    );
+                    continue;
+                }
                 match frame.header.command {
                     CMD_SYN => {
                         if buffer_table.contains_id(stream_id) {
@@ -414,7 +736,8 @@ async fn picomux_inner(
                                 "duplicate SYN",
                             ));
                         }
-                        let stream = create_stream(stream_id, frame.body.clone());
+                        let stream =
+                            create_stream(stream_id, frame.body.clone(), Priority::default(), true);
                         if let Err(err) = send_accepted.try_send(stream) {
                             match err {
                                 async_channel::TrySendError::Full(_) => {
@@ -440,12 +763,20 @@ async fn picomux_inner(
                         );
                         buffer_table.incr_send_window(stream_id, window_increase);
                     }
-                    CMD_PSH | CMD_FIN => {
+                    CMD_PSH | CMD_FIN | CMD_RST | CMD_ACK => {
                         if frame.header.command == CMD_FIN {
                             tracing::debug!(stream_id, "FIN received");
                         }
                         buffer_table.send_to(stream_id, frame);
                     }
+                    CMD_DGRAM => {
+                        let accepted = datagram_table
+                            .get(&stream_id)
+                            .is_some_and(|send| send.try_send(frame.body).is_ok());
+                        if !accepted {
+                            tracing::trace!(stream_id, "dropping incoming datagram");
+                        }
+                    }
 
                     CMD_NOP => {}
                     CMD_PING => {
@@ -458,19 +789,34 @@ async fn picomux_inner(
                             })?;
                         tracing::debug!(
                             next_ping_in_ms = ping_info.next_ping_in_ms,
+                            features = debug(ping_info.features()),
                             "responding to a PING"
                         );
+                        if state.peer_features.set(ping_info.features()).is_ok() {
+                            tracing::debug!(
+                                negotiated = debug(state.negotiated()),
+                                "features negotiated"
+                            );
+                            state.negotiated_event.notify_all();
+                        }
 
                         outgoing.enqueue(Frame::new_empty(0, CMD_PONG))
                     }
+                    CMD_GOAWAY => {
+                        let remaining_ms =
+                            u32::from_le_bytes((&frame.body[..]).try_into().ok().ok_or_else(
+                                || std::io::Error::new(ErrorKind::InvalidData, "corrupt GOAWAY"),
+                            )?);
+                        tracing::debug!(remaining_ms, "GOAWAY received");
+                        let deadline = Instant::now() + Duration::from_millis(remaining_ms as u64);
+                        let _ = state.peer_going_away.set(deadline);
+                        state.goaway_event.notify_all();
+                    }
                     CMD_PONG => {
                         let _ = send_pong.send(()).await;
                     }
                     other => {
-                        return Err(std::io::Error::new(
-                            ErrorKind::InvalidData,
-                            format!("invalid command {other}"),
-                        ));
+                        tracing::debug!(command = other, stream_id, "skipping an unknown command");
                     }
                 }
             }
@@ -478,6 +824,9 @@ async fn picomux_inner(
         .await
 }
 
+/// Queues a datagram on a stream, returning whether it was queued rather than dropped.
+type DatagramSender = Box<dyn Fn(&[u8]) -> bool + Send + Sync + 'static>;
+
 #[pin_project]
 pub struct Stream {
     #[pin]
@@ -485,8 +834,19 @@ pub struct Stream {
     #[pin]
     write_outgoing: bipe::BipeWriter,
     metadata: Bytes,
+    set_priority: Box<dyn Fn(Priority) + Send + Sync + 'static>,
+    send_datagram: DatagramSender,
+    recv_datagram: async_channel::Receiver<Bytes>,
+    verdict: Arc<Verdict>,
+    state: Arc<MuxState>,
+    send_ack: Option<Box<dyn FnOnce() + Send + Sync + 'static>>,
+    send_reset: Box<dyn Fn(StreamReset) + Send + Sync + 'static>,
     on_write: Box<dyn Fn(usize) + Send + Sync + 'static>,
     on_read: Box<dyn Fn(usize) + Send + Sync + 'static>,
+    counters: Arc<StreamCounters>,
+    send_window: Box<dyn Fn() -> usize + Send + Sync + 'static>,
+    // tells the stream's task once nobody can read from the stream anymore
+    _dropped: oneshot::Sender<()>,
 }
 
 impl Debug for Stream {
@@ -500,6 +860,61 @@ impl Stream {
         &self.metadata
     }
 
+    /// Sets the priority with which this stream's data is sent, relative to other streams in the same mux.
+    pub fn set_priority(&self, priority: Priority) {
+        (self.set_priority)(priority);
+    }
+
+    /// Sends an unreliable datagram on this stream's flow, returning whether it was queued. Datagrams
+    /// are dropped rather than buffered when the stream is backlogged, and always dropped until
+    /// [`PicoMux::peer_supports_datagrams`] says the peer can take them.
+    pub fn send_datagram(&self, body: &[u8]) -> bool {
+        if !self.state.supports(Features::DATAGRAMS) {
+            return false;
+        }
+        let queued = (self.send_datagram)(body);
+        if queued {
+            (self.on_write)(body.len());
+        }
+        queued
+    }
+
+    /// Receives the next datagram the peer sent on this stream's flow. Fails once the stream is closed.
+    pub async fn recv_datagram(&self) -> std::io::Result<Bytes> {
+        let body = self
+            .recv_datagram
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "stream closed"))?;
+        (self.on_read)(body.len());
+        Ok(body)
+    }
+
+    /// Waits until the peer first reads or writes a stream we opened, failing with a [`StreamReset`] if it
+    /// refuses the stream instead. Returns right away if the peer is too old to say either way.
+    pub async fn wait_accepted(&self) -> std::io::Result<()> {
+        if !self.state.supports(Features::RESETS) {
+            return Ok(());
+        }
+        self.verdict.wait().await
+    }
+
+    /// Refuses or tears down the stream, telling the peer why. Reads on the other end then fail
+    /// with a [`StreamReset`]. Peers that don't understand resets just see the stream close.
+    pub fn reset(self, code: ResetCode, reason: &str) {
+        if self.state.supports(Features::RESETS) {
+            (self.send_reset)(StreamReset {
+                code,
+                reason: reason.to_string(),
+            });
+        }
+    }
+
+    /// Takes a snapshot of the stream's traffic counters and flow-control state.
+    pub fn stats(&self) -> StreamStats {
+        self.counters.snapshot((self.send_window)())
+    }
+
     pub fn set_on_write(&mut self, on_write: impl Fn(usize) + Send + Sync + 'static) {
         self.on_write = Box::new(on_write);
     }
@@ -511,16 +926,26 @@ impl Stream {
 
 impl AsyncRead for Stream {
     fn poll_read(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut [u8],
     ) -> std::task::Poll<std::io::Result<usize>> {
+        if let Some(send_ack) = self.as_mut().project().send_ack.take() {
+            send_ack();
+        }
         if fastrand::f32() < 0.1 {
             cx.waker().wake_by_ref();
             Poll::Pending
         } else {
             let this = self.project();
             let r = this.read_incoming.poll_read(cx, buf);
+            if let Poll::Ready(Ok(0)) = r {
+                if let Some(reset) = this.verdict.get_reset() {
+                    if !buf.is_empty() {
+                        return Poll::Ready(Err(reset.clone().into()));
+                    }
+                }
+            }
             if r.is_ready() {
                 (this.on_read)(buf.len());
             }
@@ -532,16 +957,22 @@ impl AsyncRead for Stream {
 impl AsyncWrite for Stream {
     #[tracing::instrument(name = "picomux_stream_write", skip(self, cx, buf))]
     fn poll_write(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
         tracing::trace!(buf_len = buf.len(), "about to poll write");
+        if let Some(send_ack) = self.as_mut().project().send_ack.take() {
+            send_ack();
+        }
         // if fastrand::f32() < 0.1 {
         //     cx.waker().wake_by_ref();
         //     Poll::Pending
         // } else {
         let this = self.project();
+        if let Some(err) = this.verdict.write_error(cx.waker()) {
+            return Poll::Ready(Err(err));
+        }
         let r = this.write_outgoing.poll_write(cx, buf);
         if r.is_ready() {
             (this.on_write)(buf.len());
@@ -557,6 +988,8 @@ impl AsyncWrite for Stream {
         self.project().write_outgoing.poll_flush(cx)
     }
 
+    /// Closes only our direction of the stream. If the peer supports half-closing, reads keep
+    /// working until it closes its direction too.
     fn poll_close(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
@@ -615,4 +1048,224 @@ mod tests {
             a_proc.race(b_proc).await
         })
     }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_datagram() {
+        smolscale::block_on(async move {
+            let (picomux_a, picomux_b) = setup_picomux_pair().await;
+
+            let stream_a = picomux_a.open(b"").await.unwrap();
+            let stream_b = picomux_b.accept().await.unwrap();
+            picomux_a.wait_negotiated().await.unwrap();
+            picomux_b.wait_negotiated().await.unwrap();
+
+            assert!(stream_a.send_datagram(b"ping"));
+            assert_eq!(&stream_b.recv_datagram().await.unwrap()[..], b"ping");
+            assert!(stream_b.send_datagram(b"pong"));
+            assert_eq!(&stream_a.recv_datagram().await.unwrap()[..], b"pong");
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_reset() {
+        smolscale::block_on(async move {
+            let (picomux_a, picomux_b) = setup_picomux_pair().await;
+            picomux_a.wait_negotiated().await.unwrap();
+            picomux_b.wait_negotiated().await.unwrap();
+
+            let mut accepted_a = picomux_a.open(b"accepted").await.unwrap();
+            let mut accepted_b = picomux_b.accept().await.unwrap();
+            accepted_a.write_all(b"hi").await.unwrap();
+            accepted_a.flush().await.unwrap();
+            let mut buf = [0u8; 2];
+            accepted_b.read_exact(&mut buf).await.unwrap();
+            accepted_a.wait_accepted().await.unwrap();
+
+            let mut refused_a = picomux_a.open(b"refused").await.unwrap();
+            let refused_b = picomux_b.accept().await.unwrap();
+            refused_b.reset(ResetCode::NotAllowed, "nope");
+            let expected = StreamReset {
+                code: ResetCode::NotAllowed,
+                reason: "nope".into(),
+            };
+            let err = refused_a.wait_accepted().await.unwrap_err();
+            assert_eq!(StreamReset::find(&err), Some(&expected));
+            let err = refused_a.read(&mut buf).await.unwrap_err();
+            assert_eq!(StreamReset::find(&err), Some(&expected));
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_goaway() {
+        smolscale::block_on(async move {
+            let (picomux_a, picomux_b) = setup_picomux_pair().await;
+            picomux_a.wait_negotiated().await.unwrap();
+            picomux_b.wait_negotiated().await.unwrap();
+            let stream_a = picomux_a.open(b"").await.unwrap();
+            let stream_b = picomux_b.accept().await.unwrap();
+
+            let shutdown = picomux_a.shutdown_gracefully(Instant::now() + Duration::from_secs(60));
+            let peer = async {
+                let deadline = picomux_b.wait_peer_goaway().await;
+                assert!(deadline > Instant::now());
+                assert!(picomux_a.open(b"").await.is_err());
+                assert!(picomux_b.open(b"").await.is_err());
+                // the shutdown finishes once the last stream does
+                drop(stream_a);
+                drop(stream_b);
+                futures_util::future::pending().await
+            };
+            shutdown.race(peer).await
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_half_close() {
+        smolscale::block_on(async move {
+            let (picomux_a, picomux_b) = setup_picomux_pair().await;
+            picomux_a.wait_negotiated().await.unwrap();
+            picomux_b.wait_negotiated().await.unwrap();
+            let mut stream_a = picomux_a.open(b"").await.unwrap();
+            let mut stream_b = picomux_b.accept().await.unwrap();
+
+            stream_a.write_all(b"request").await.unwrap();
+            stream_a.close().await.unwrap();
+            let mut request = vec![];
+            stream_b.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"request");
+
+            // b can still answer after a is done writing
+            stream_b.write_all(b"response").await.unwrap();
+            drop(stream_b);
+            let mut response = vec![];
+            stream_a.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"response");
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_reset_after_fin() {
+        smolscale::block_on(async move {
+            let (picomux_a, picomux_b) = setup_picomux_pair().await;
+            picomux_a.wait_negotiated().await.unwrap();
+            picomux_b.wait_negotiated().await.unwrap();
+            let mut stream_a = picomux_a.open(b"").await.unwrap();
+            let stream_b = picomux_b.accept().await.unwrap();
+
+            // b's FIN and RST go out together, and a, now half-closed, must see both
+            drop(stream_b);
+            let mut rest = vec![];
+            stream_a.read_to_end(&mut rest).await.unwrap();
+            let write_fails = async {
+                loop {
+                    if stream_a.write_all(&[0u8; 1000]).await.is_err()
+                        || stream_a.flush().await.is_err()
+                    {
+                        break;
+                    }
+                }
+            };
+            write_fails
+                .or(async {
+                    smol::Timer::after(Duration::from_secs(10)).await;
+                    panic!("writing after the peer dropped its stream never failed")
+                })
+                .await;
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_stats() {
+        smolscale::block_on(async move {
+            let (picomux_a, picomux_b) = setup_picomux_pair().await;
+            let mut stream_a = picomux_a.open(b"").await.unwrap();
+            let mut stream_b = picomux_b.accept().await.unwrap();
+
+            stream_a.write_all(&[0u8; 1000]).await.unwrap();
+            stream_a.flush().await.unwrap();
+            let mut buf = [0u8; 1000];
+            stream_b.read_exact(&mut buf).await.unwrap();
+
+            assert_eq!(stream_a.stats().bytes_out, 1000);
+            assert_eq!(stream_b.stats().bytes_in, 1000);
+            assert!(stream_b.stats().queue_delay.is_some());
+            assert_eq!(picomux_a.stats().open_streams, 1);
+            assert!(picomux_b.stats().bytes_in >= 1000);
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_mixed_versions() {
+        smolscale::block_on(async move {
+            // b offers nothing, just like a peer from before negotiation existed
+            let (a_write, b_read) = bipe::bipe(1);
+            let (b_write, a_read) = bipe::bipe(1);
+            let picomux_a = PicoMux::new(a_read, a_write);
+            let picomux_b = PicoMux::with_features(b_read, b_write, Features::empty());
+            assert_eq!(
+                picomux_a.wait_negotiated().await.unwrap(),
+                Features::empty()
+            );
+            assert_eq!(
+                picomux_b.wait_negotiated().await.unwrap(),
+                Features::empty()
+            );
+
+            // frames the peer didn't negotiate are skipped instead of killing the mux
+            (picomux_a.enqueue)(Frame::new(0, CMD_DGRAM, b"stray"));
+            (picomux_a.enqueue)(Frame::new_empty(0, 0x42));
+
+            let mut stream_a = picomux_a.open(b"").await.unwrap();
+            let mut stream_b = picomux_b.accept().await.unwrap();
+            assert!(!stream_a.send_datagram(b"dropped"));
+            stream_a.wait_accepted().await.unwrap();
+            stream_a.write_all(b"still works").await.unwrap();
+            stream_a.flush().await.unwrap();
+            let mut buf = [0u8; 11];
+            stream_b.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"still works");
+
+            // without resets, refusing a stream just closes it
+            stream_b.reset(ResetCode::NotAllowed, "nope");
+            let mut rest = vec![];
+            stream_a.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            assert!(picomux_a.is_alive() && picomux_b.is_alive());
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_half_close_needs_resets() {
+        smolscale::block_on(async move {
+            let (a_write, b_read) = bipe::bipe(1);
+            let (b_write, a_read) = bipe::bipe(1);
+            let picomux_a = PicoMux::new(a_read, a_write);
+            let picomux_b = PicoMux::with_features(b_read, b_write, Features::HALF_CLOSE);
+            assert_eq!(
+                picomux_a.wait_negotiated().await.unwrap(),
+                Features::empty()
+            );
+            assert_eq!(
+                picomux_b.wait_negotiated().await.unwrap(),
+                Features::empty()
+            );
+
+            // without resets to end it, closing one direction closes the whole stream, as before
+            let mut stream_a = picomux_a.open(b"").await.unwrap();
+            let mut stream_b = picomux_b.accept().await.unwrap();
+            stream_a.close().await.unwrap();
+            let mut rest = vec![];
+            stream_b.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            assert!(picomux_a.is_alive() && picomux_b.is_alive());
+        })
+    }
 }
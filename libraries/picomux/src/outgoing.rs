@@ -1,9 +1,43 @@
-use std::sync::{Arc, OnceLock};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+};
 
-use crossbeam_queue::SegQueue;
 use futures_lite::{AsyncWrite, AsyncWriteExt};
+use parking_lot::Mutex;
 
-use crate::frame::Frame;
+use crate::frame::{write_frames, Frame, CMD_DGRAM, CMD_FIN, CMD_PSH, CMD_RST, MAX_WRITE_BATCH};
+
+/// How many bytes a stream may send each time the round-robin comes around to it.
+const QUANTUM: usize = 16384;
+
+/// How many frames a single stream may have queued before [`Outgoing::send`] blocks.
+const MAX_STREAM_QUEUE: usize = 10;
+
+/// The scheduling class of a stream. Classes are served in strict order, so any queued data in a
+/// higher class goes out before data in a lower one. Streams within the same class share the
+/// connection fairly through deficit round-robin.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Latency-sensitive traffic, like DNS or small request/response exchanges.
+    Interactive,
+    /// The default class.
+    #[default]
+    Normal,
+    /// Large transfers that should yield to everything else.
+    Bulk,
+}
+
+impl Priority {
+    const ALL: [Self; 3] = [Self::Interactive, Self::Normal, Self::Bulk];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
 
 /// A writer for outgoing data.
 #[derive(Clone)]
@@ -29,17 +63,88 @@ impl Outgoing {
         }
     }
 
-    /// Send a frame to the outgoing writer, returning once it's fully written.
+    /// Registers a stream with the scheduler, so that its data frames are sent with the given priority.
+    pub fn register(&self, stream_id: u32, priority: Priority) {
+        self.inner.sched.lock().register(stream_id, priority);
+    }
+
+    /// Returns a function that changes the priority of an already-registered stream. It does not
+    /// keep the writer alive, and does nothing once the stream or the writer is gone.
+    pub fn priority_setter(&self, stream_id: u32) -> impl Fn(Priority) + Send + Sync + 'static {
+        let inner = Arc::downgrade(&self.inner);
+        move |priority| {
+            if let Some(inner) = inner.upgrade() {
+                inner.sched.lock().set_priority(stream_id, priority);
+            }
+        }
+    }
+
+    /// Returns a function that queues an unreliable datagram on the given stream, returning false
+    /// if it was dropped because the stream is backlogged, closed, or the datagram is too big.
+    pub fn datagram_sender(
+        &self,
+        stream_id: u32,
+    ) -> impl Fn(&[u8]) -> bool + Send + Sync + 'static {
+        let inner = Arc::downgrade(&self.inner);
+        move |body| {
+            let Some(inner) = inner.upgrade() else {
+                return false;
+            };
+            if body.len() > u16::MAX as usize {
+                return false;
+            }
+            let queued = inner
+                .sched
+                .lock()
+                .try_push_datagram(Frame::new(stream_id, CMD_DGRAM, body));
+            if queued {
+                inner.grow_signal.notify_one();
+            }
+            queued
+        }
+    }
+
+    /// Returns a function that enqueues frames like [`Outgoing::enqueue`], without keeping the
+    /// writer alive.
+    pub fn weak_enqueuer(&self) -> impl Fn(Frame) + Send + Sync + 'static {
+        let inner = Arc::downgrade(&self.inner);
+        move |frame| {
+            if let Some(inner) = inner.upgrade() {
+                inner.sched.lock().push(frame);
+                inner.grow_signal.notify_one();
+            }
+        }
+    }
+
+    /// Returns a function that reads how many frames are queued and how many bytes have been
+    /// written so far, without keeping the writer alive. Both read as zero once the writer is gone.
+    pub fn stats_reader(&self) -> impl Fn() -> (usize, u64) + Send + Sync + 'static {
+        let inner = Arc::downgrade(&self.inner);
+        move || {
+            inner
+                .upgrade()
+                .map(|inner| {
+                    (
+                        inner.sched.lock().queued_frames(),
+                        inner.bytes_written.load(Ordering::Relaxed),
+                    )
+                })
+                .unwrap_or_default()
+        }
+    }
+
+    /// Send a frame to the outgoing writer, returning once its stream has room for more.
     pub async fn send(&self, outgoing: Frame) -> anyhow::Result<()> {
+        let stream_id = outgoing.header.stream_id;
         self.enqueue(outgoing);
-        // wait until the queue is empty
+        // wait until the stream's queue drains
         self.inner
             .shrink_signal
             .wait_until(|| {
                 if let Some(err) = self.err.get() {
                     return Some(Err(anyhow::anyhow!("{:?}", err)));
                 }
-                if self.inner.queue.len() < 10 {
+                if self.inner.sched.lock().stream_queue_len(stream_id) < MAX_STREAM_QUEUE {
                     Some(anyhow::Ok(()))
                 } else {
                     None
@@ -57,16 +162,171 @@ impl Outgoing {
             body_len = outgoing.header.body_len,
             "sending outgoing frame"
         );
-        self.inner.queue.push(outgoing);
+        self.inner.sched.lock().push(outgoing);
         self.inner.grow_signal.notify_one();
     }
 }
 
 #[derive(Default)]
 struct Inner {
-    queue: SegQueue<Frame>,
+    sched: Mutex<Scheduler>,
     grow_signal: async_event::Event,
     shrink_signal: async_event::Event,
+    bytes_written: AtomicU64,
+}
+
+/// Decides which frame goes out next.
+///
+/// Control frames (SYN, MORE, PING, etc) skip the line entirely. Stream data (PSH and the
+/// trailing RST or FIN, which must not overtake the data before it) goes into a per-stream
+/// queue, and the queues are drained by priority class, then by deficit round-robin within each
+/// class. Datagrams share their stream's queue, but are dropped rather than queued once it is
+/// backlogged.
+#[derive(Default)]
+struct Scheduler {
+    control: VecDeque<Frame>,
+    streams: HashMap<u32, StreamQueue>,
+    /// Streams with queued frames, one round-robin ring per priority class.
+    active: [VecDeque<u32>; 3],
+}
+
+#[derive(Default)]
+struct StreamQueue {
+    frames: VecDeque<Frame>,
+    priority: Priority,
+    deficit: usize,
+}
+
+impl Scheduler {
+    fn register(&mut self, stream_id: u32, priority: Priority) {
+        self.streams.entry(stream_id).or_default().priority = priority;
+    }
+
+    fn set_priority(&mut self, stream_id: u32, priority: Priority) {
+        let Some(queue) = self.streams.get_mut(&stream_id) else {
+            return;
+        };
+        let old = std::mem::replace(&mut queue.priority, priority);
+        if old == priority || queue.frames.is_empty() {
+            return;
+        }
+        queue.deficit = 0;
+        let old_ring = &mut self.active[old.index()];
+        let was_front = old_ring.front() == Some(&stream_id);
+        old_ring.retain(|id| *id != stream_id);
+        if was_front {
+            grant_front(&self.active[old.index()], &mut self.streams);
+        }
+        let new_ring = &mut self.active[priority.index()];
+        new_ring.push_back(stream_id);
+        if new_ring.len() == 1 {
+            grant_front(new_ring, &mut self.streams);
+        }
+    }
+
+    fn stream_queue_len(&self, stream_id: u32) -> usize {
+        self.streams
+            .get(&stream_id)
+            .map(|queue| queue.frames.len())
+            .unwrap_or_default()
+    }
+
+    fn queued_frames(&self) -> usize {
+        self.control.len()
+            + self
+                .streams
+                .values()
+                .map(|queue| queue.frames.len())
+                .sum::<usize>()
+    }
+
+    fn push(&mut self, frame: Frame) {
+        let cmd = frame.header.command;
+        if frame.header.stream_id == 0 || (cmd != CMD_PSH && cmd != CMD_FIN && cmd != CMD_RST) {
+            self.control.push_back(frame);
+            return;
+        }
+        self.streams.entry(frame.header.stream_id).or_default();
+        self.push_stream(frame);
+    }
+
+    fn try_push_datagram(&mut self, frame: Frame) -> bool {
+        match self.streams.get(&frame.header.stream_id) {
+            Some(queue) if queue.frames.len() < MAX_STREAM_QUEUE => {
+                self.push_stream(frame);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn push_stream(&mut self, frame: Frame) {
+        let stream_id = frame.header.stream_id;
+        let queue = self
+            .streams
+            .get_mut(&stream_id)
+            .expect("pushing to an unregistered stream");
+        queue.frames.push_back(frame);
+        if queue.frames.len() == 1 {
+            let ring = &mut self.active[queue.priority.index()];
+            ring.push_back(stream_id);
+            if ring.len() == 1 {
+                grant_front(ring, &mut self.streams);
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Frame> {
+        if let Some(frame) = self.control.pop_front() {
+            return Some(frame);
+        }
+        for priority in Priority::ALL {
+            let ring = &mut self.active[priority.index()];
+            while let Some(&stream_id) = ring.front() {
+                let queue = self
+                    .streams
+                    .get_mut(&stream_id)
+                    .expect("active stream missing from the scheduler");
+                let cost = queue
+                    .frames
+                    .front()
+                    .map(Frame::wire_len)
+                    .unwrap_or_default();
+                if queue.deficit < cost {
+                    // used up its turn, so the next stream gets one
+                    ring.rotate_left(1);
+                    grant_front(ring, &mut self.streams);
+                    continue;
+                }
+                queue.deficit -= cost;
+                let frame = queue.frames.pop_front()?;
+                if frame.header.command == CMD_FIN {
+                    // a stray datagram behind the FIN goes, but an RST must still reach the peer
+                    queue.frames.retain(|f| f.header.command == CMD_RST);
+                }
+                let is_last = frame.header.command == CMD_RST
+                    || (frame.header.command == CMD_FIN && queue.frames.is_empty());
+                if queue.frames.is_empty() || is_last {
+                    queue.deficit = 0;
+                    ring.pop_front();
+                    if is_last {
+                        // anything queued behind the RST goes with it
+                        self.streams.remove(&stream_id);
+                    }
+                    grant_front(ring, &mut self.streams);
+                }
+                return Some(frame);
+            }
+        }
+        None
+    }
+}
+
+/// Starts the turn of whichever stream is now at the front of the ring.
+fn grant_front(ring: &VecDeque<u32>, streams: &mut HashMap<u32, StreamQueue>) {
+    if let Some(queue) = ring.front().and_then(|id| streams.get_mut(id)) {
+        queue.deficit += QUANTUM;
+    }
 }
 
 async fn outgoing_loop(
@@ -74,9 +334,108 @@ async fn outgoing_loop(
     inner: Arc<Inner>,
 ) -> anyhow::Result<()> {
     scopeguard::defer!(inner.shrink_signal.notify_all());
+    let mut batch = Vec::with_capacity(MAX_WRITE_BATCH);
     loop {
-        let next = inner.grow_signal.wait_until(|| inner.queue.pop()).await;
+        {
+            let mut sched = inner.sched.lock();
+            while batch.len() < MAX_WRITE_BATCH {
+                match sched.pop() {
+                    Some(next) => batch.push(next),
+                    None => break,
+                }
+            }
+        }
+        if batch.is_empty() {
+            // the writer may be buffering, so whatever it holds must go out before we go idle
+            write.flush().await?;
+            let next = inner
+                .grow_signal
+                .wait_until(|| inner.sched.lock().pop())
+                .await;
+            batch.push(next);
+        }
         inner.shrink_signal.notify_all();
-        write.write_all(&next.bytes()).await?;
+        let written = write_frames(&mut write, &batch).await?;
+        batch.clear();
+        inner
+            .bytes_written
+            .fetch_add(written as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::CMD_MORE;
+
+    /// A data frame that costs exactly one quantum on the wire.
+    fn psh(stream_id: u32) -> Frame {
+        Frame::new(stream_id, CMD_PSH, &[0u8; QUANTUM - 8])
+    }
+
+    #[test]
+    fn interactive_overtakes_bulk() {
+        let mut sched = Scheduler::default();
+        sched.register(1, Priority::Bulk);
+        sched.register(2, Priority::Interactive);
+        for _ in 0..5 {
+            sched.push(psh(1));
+        }
+        sched.push(psh(2));
+        sched.push(Frame::new(3, CMD_MORE, &10u16.to_le_bytes()));
+
+        let order: Vec<u32> = std::iter::from_fn(|| sched.pop())
+            .map(|f| f.header.stream_id)
+            .collect();
+        assert_eq!(order, vec![3, 2, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn round_robin_within_class() {
+        let mut sched = Scheduler::default();
+        sched.register(1, Priority::Normal);
+        sched.register(2, Priority::Normal);
+        for _ in 0..4 {
+            sched.push(psh(1));
+        }
+        for _ in 0..2 {
+            sched.push(psh(2));
+        }
+        sched.push(Frame::new_empty(1, CMD_FIN));
+
+        let order: Vec<u32> = std::iter::from_fn(|| sched.pop())
+            .map(|f| f.header.stream_id)
+            .collect();
+        assert_eq!(order, vec![1, 2, 1, 2, 1, 1, 1]);
+        assert!(!sched.streams.contains_key(&1));
+    }
+
+    #[test]
+    fn rst_follows_fin() {
+        let mut sched = Scheduler::default();
+        sched.register(1, Priority::Normal);
+        sched.push(psh(1));
+        sched.push(Frame::new_empty(1, CMD_FIN));
+        assert!(sched.try_push_datagram(Frame::new(1, CMD_DGRAM, b"stray")));
+        sched.push(Frame::new_empty(1, CMD_RST));
+
+        let order: Vec<u8> = std::iter::from_fn(|| sched.pop())
+            .map(|f| f.header.command)
+            .collect();
+        assert_eq!(order, vec![CMD_PSH, CMD_FIN, CMD_RST]);
+        assert!(!sched.streams.contains_key(&1));
+    }
+
+    #[test]
+    fn datagrams_drop_when_backlogged() {
+        let mut sched = Scheduler::default();
+        assert!(!sched.try_push_datagram(Frame::new(1, CMD_DGRAM, b"unregistered")));
+        sched.register(1, Priority::Normal);
+        for _ in 0..MAX_STREAM_QUEUE {
+            assert!(sched.try_push_datagram(Frame::new(1, CMD_DGRAM, b"hello")));
+        }
+        assert!(!sched.try_push_datagram(Frame::new(1, CMD_DGRAM, b"hello")));
+        assert!(sched.pop().is_some());
+        assert!(sched.try_push_datagram(Frame::new(1, CMD_DGRAM, b"hello")));
     }
 }
@@ -0,0 +1,199 @@
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    task::Waker,
+};
+
+use futures_util::task::AtomicWaker;
+
+/// Why the peer refused or tore down a stream. The codes follow SOCKS5 reply codes, so that
+/// proxies can pass them on directly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ResetCode {
+    /// Anything not covered by a more specific code.
+    General,
+    /// Policy forbids connecting to this destination.
+    NotAllowed,
+    /// The destination network cannot be reached.
+    NetworkUnreachable,
+    /// The destination could not be resolved or reached.
+    HostUnreachable,
+    /// The destination actively refused the connection.
+    ConnectionRefused,
+    /// Connecting to the destination took too long.
+    TimedOut,
+    /// The peer does not support what the stream asked for, like an unknown protocol.
+    Unsupported,
+}
+
+impl ResetCode {
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            ResetCode::General => 1,
+            ResetCode::NotAllowed => 2,
+            ResetCode::NetworkUnreachable => 3,
+            ResetCode::HostUnreachable => 4,
+            ResetCode::ConnectionRefused => 5,
+            ResetCode::TimedOut => 6,
+            ResetCode::Unsupported => 7,
+        }
+    }
+
+    pub(crate) fn from_byte(b: u8) -> Self {
+        match b {
+            2 => ResetCode::NotAllowed,
+            3 => ResetCode::NetworkUnreachable,
+            4 => ResetCode::HostUnreachable,
+            5 => ResetCode::ConnectionRefused,
+            6 => ResetCode::TimedOut,
+            7 => ResetCode::Unsupported,
+            _ => ResetCode::General,
+        }
+    }
+}
+
+/// The error that reads on a [`crate::Stream`] return after the peer resets it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamReset {
+    pub code: ResetCode,
+    pub reason: String,
+}
+
+impl Display for StreamReset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stream reset by peer ({:?}): {}", self.code, self.reason)
+    }
+}
+
+impl std::error::Error for StreamReset {}
+
+impl From<StreamReset> for std::io::Error {
+    fn from(value: StreamReset) -> Self {
+        std::io::Error::new(std::io::ErrorKind::ConnectionReset, value)
+    }
+}
+
+impl StreamReset {
+    /// Finds a stream reset anywhere in an error's chain, including inside [`std::io::Error`]s,
+    /// which hide their payload from [`std::error::Error::source`].
+    pub fn find<'a>(err: &'a (dyn std::error::Error + 'static)) -> Option<&'a StreamReset> {
+        let mut next = Some(err);
+        while let Some(err) = next {
+            if let Some(reset) = err.downcast_ref::<StreamReset>() {
+                return Some(reset);
+            }
+            if let Some(inner) = err
+                .downcast_ref::<std::io::Error>()
+                .and_then(|err| err.get_ref())
+            {
+                if let Some(reset) = Self::find(inner) {
+                    return Some(reset);
+                }
+            }
+            next = err.source();
+        }
+        None
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut body = vec![self.code.to_byte()];
+        // the frame length is a u16, and a reason is meant to be short anyway
+        let reason = self.reason.as_bytes();
+        body.extend_from_slice(&reason[..reason.len().min(1024)]);
+        body
+    }
+
+    pub(crate) fn decode(body: &[u8]) -> Self {
+        Self {
+            code: ResetCode::from_byte(body.first().copied().unwrap_or_default()),
+            reason: String::from_utf8_lossy(body.get(1..).unwrap_or_default()).into_owned(),
+        }
+    }
+}
+
+/// Tracks whether the peer has accepted, reset, or closed a stream.
+#[derive(Default)]
+pub(crate) struct Verdict {
+    accepted: AtomicBool,
+    closed: AtomicBool,
+    reset: OnceLock<StreamReset>,
+    event: async_event::Event,
+    writer: AtomicWaker,
+}
+
+impl Verdict {
+    pub fn accept(&self) {
+        self.accepted.store(true, Ordering::SeqCst);
+        self.event.notify_all();
+    }
+
+    pub fn reset(&self, reset: StreamReset) {
+        let _ = self.reset.set(reset);
+        self.event.notify_all();
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.event.notify_all();
+        self.writer.wake();
+    }
+
+    /// Returns why writes can no longer go anywhere once the stream is closed, and otherwise
+    /// arranges for the writer to be woken when it is. Nothing drains the pipe after that, so a
+    /// write into a full one would otherwise wait forever.
+    pub fn write_error(&self, waker: &Waker) -> Option<std::io::Error> {
+        self.writer.register(waker);
+        if !self.closed.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(match self.reset.get() {
+            Some(reset) => reset.clone().into(),
+            None => std::io::Error::new(std::io::ErrorKind::BrokenPipe, "stream closed"),
+        })
+    }
+
+    pub fn get_reset(&self) -> Option<&StreamReset> {
+        self.reset.get()
+    }
+
+    /// Waits until the peer accepts the stream, failing if it resets or closes it first.
+    pub async fn wait(&self) -> std::io::Result<()> {
+        self.event
+            .wait_until(|| {
+                if let Some(reset) = self.reset.get() {
+                    Some(Err(reset.clone().into()))
+                } else if self.accepted.load(Ordering::SeqCst) {
+                    Some(Ok(()))
+                } else if self.closed.load(Ordering::SeqCst) {
+                    Some(Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "stream closed before it was accepted",
+                    )))
+                } else {
+                    None
+                }
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_survives_io_error() {
+        let reset = StreamReset {
+            code: ResetCode::NotAllowed,
+            reason: "blocked port".into(),
+        };
+        assert_eq!(StreamReset::decode(&reset.encode()), reset);
+
+        let err: std::io::Error = reset.clone().into();
+        let err = anyhow::Error::from(err).context("could not open");
+        assert_eq!(StreamReset::find(err.as_ref()), Some(&reset));
+    }
+}
@@ -0,0 +1,72 @@
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+/// A snapshot of how a whole mux is doing, from [`crate::PicoMux::stats`].
+#[derive(Clone, Debug)]
+pub struct MuxStats {
+    /// Streams that haven't fully closed yet.
+    pub open_streams: usize,
+    /// Bytes read from the underlying connection, headers included.
+    pub bytes_in: u64,
+    /// Bytes written to the underlying connection, headers included.
+    pub bytes_out: u64,
+    /// Frames waiting for their turn to be written.
+    pub queued_frames: usize,
+    /// The latest receive bandwidth estimate, in bytes per second, which sizes new streams' windows.
+    pub bw_estimate: f64,
+    /// Round-trip times of the most recent pings, oldest first.
+    pub ping_history: Vec<Duration>,
+}
+
+/// A snapshot of how one stream is doing, from [`crate::Stream::stats`].
+#[derive(Clone, Copy, Debug)]
+pub struct StreamStats {
+    /// Stream data received from the peer.
+    pub bytes_in: u64,
+    /// Stream data sent to the peer.
+    pub bytes_out: u64,
+    /// How many more frames we may send before the peer grants more credit.
+    pub send_window: usize,
+    /// How many more frames the peer may send before we grant it more credit.
+    pub recv_window: usize,
+    /// How long the latest frame from the peer waited in the stream's buffer.
+    pub queue_delay: Option<Duration>,
+    /// How long ago the stream was opened.
+    pub age: Duration,
+}
+
+/// The counters behind [`StreamStats`], which the stream's task updates as it moves data.
+pub(crate) struct StreamCounters {
+    pub created: Instant,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub recv_window: AtomicUsize,
+    pub queue_delay: Mutex<Option<Duration>>,
+}
+
+impl StreamCounters {
+    pub fn new(recv_window: usize) -> Self {
+        Self {
+            created: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            recv_window: AtomicUsize::new(recv_window),
+            queue_delay: Mutex::new(None),
+        }
+    }
+
+    pub fn snapshot(&self, send_window: usize) -> StreamStats {
+        StreamStats {
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            send_window,
+            recv_window: self.recv_window.load(Ordering::Relaxed),
+            queue_delay: *self.queue_delay.lock(),
+            age: self.created.elapsed(),
+        }
+    }
+}
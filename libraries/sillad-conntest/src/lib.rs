@@ -30,6 +30,7 @@ impl<D: Dialer> Dialer for ConnTestDialer<D> {
             let mut buf = vec![0u8; size as usize];
             rand::rng().fill_bytes(&mut buf);
             pipe.write_all(&buf).await?;
+            pipe.flush().await?;
             // Read back the echoed payload.
             let mut echo = vec![0u8; size as usize];
             pipe.read_exact(&mut echo).await?;
@@ -50,6 +51,7 @@ impl<D: Dialer> Dialer for ConnTestDialer<D> {
         }
         // Termination message: a 0 length indicates end of testing.
         pipe.write_all(&[0u8; 2]).await?;
+        pipe.flush().await?;
         Ok(pipe)
     }
 }
@@ -92,6 +94,7 @@ impl<L: Listener> ConnTestListener<L> {
                             let mut payload = vec![0u8; size as usize];
                             conn.read_exact(&mut payload).await?;
                             conn.write_all(&payload).await?;
+                            conn.flush().await?;
                         }
                     };
                     inner
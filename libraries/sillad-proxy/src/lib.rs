@@ -0,0 +1,91 @@
+mod http_connect;
+mod socks5;
+
+use std::pin::Pin;
+
+pub use http_connect::HttpConnectDialer;
+use pin_project::pin_project;
+use sillad::Pipe;
+pub use socks5::Socks5Dialer;
+
+use futures_util::{AsyncRead, AsyncWrite};
+
+/// Username and password credentials for an upstream proxy.
+#[derive(Clone, Debug)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// ProxyPipe is a connection tunneled through an upstream proxy. After the proxy handshake, it is the lower pipe, except that it reports the final destination as its remote address.
+#[pin_project]
+pub struct ProxyPipe<P: Pipe> {
+    #[pin]
+    inner: P,
+    dest: String,
+    protocol: &'static str,
+}
+
+impl<P: Pipe> AsyncRead for ProxyPipe<P> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().inner.poll_read(cx, buf)
+    }
+}
+
+impl<P: Pipe> AsyncWrite for ProxyPipe<P> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<P: Pipe> Pipe for ProxyPipe<P> {
+    fn protocol(&self) -> &str {
+        self.protocol
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        Some(&self.dest)
+    }
+}
+
+/// Splits a `host:port` destination, where the host may be a bracketed IPv6 address.
+fn split_host_port(dest: &str) -> std::io::Result<(&str, u16)> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("proxy destination {dest:?} is not of the form host:port"),
+        )
+    };
+    let (host, port) = dest.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host, port))
+}
@@ -0,0 +1,251 @@
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use sillad::{dialer::Dialer, Pipe};
+
+use crate::{split_host_port, ProxyAuth, ProxyPipe};
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Socks5Dialer reaches `dest` (a `host:port` string) by asking a SOCKS5 proxy, reached through the inner dialer, to connect there. Domain names are resolved by the proxy.
+pub struct Socks5Dialer<D: Dialer> {
+    pub inner: D,
+    pub dest: String,
+    pub auth: Option<ProxyAuth>,
+}
+
+#[async_trait]
+impl<D: Dialer> Dialer for Socks5Dialer<D> {
+    type P = ProxyPipe<D::P>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let (host, port) = split_host_port(&self.dest)?;
+        let mut conn = self.inner.dial().await?;
+        handshake(&mut conn, host, port, self.auth.as_ref())
+            .await
+            .inspect_err(|e| {
+                tracing::warn!(err = debug(e), dest = self.dest, "SOCKS5 handshake failed")
+            })?;
+        Ok(ProxyPipe {
+            inner: conn,
+            dest: self.dest.clone(),
+            protocol: "socks5",
+        })
+    }
+}
+
+async fn handshake(
+    conn: &mut impl Pipe,
+    host: &str,
+    port: u16,
+    auth: Option<&ProxyAuth>,
+) -> std::io::Result<()> {
+    // method negotiation
+    if auth.is_some() {
+        conn.write_all(&[VERSION, 2, METHOD_NO_AUTH, METHOD_PASSWORD])
+            .await?;
+    } else {
+        conn.write_all(&[VERSION, 1, METHOD_NO_AUTH]).await?;
+    }
+    let mut reply = [0u8; 2];
+    conn.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(protocol_error("proxy does not speak SOCKS5"));
+    }
+    match (reply[1], auth) {
+        (METHOD_NO_AUTH, _) => {}
+        (METHOD_PASSWORD, Some(auth)) => password_auth(conn, auth).await?,
+        (METHOD_UNACCEPTABLE, _) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "SOCKS5 proxy accepted none of our authentication methods",
+            ))
+        }
+        _ => {
+            return Err(protocol_error(
+                "SOCKS5 proxy chose a method we did not offer",
+            ))
+        }
+    }
+
+    // the connect request itself
+    let mut request = vec![VERSION, CMD_CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len()).map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "domain name too long")
+            })?;
+            request.push(ATYP_DOMAIN);
+            request.push(len);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    conn.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    conn.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(protocol_error("malformed SOCKS5 connect reply"));
+    }
+    if reply[1] != 0 {
+        return Err(reply_error(reply[1]));
+    }
+    // we don't care about the bound address, but it has to be consumed
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            conn.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => return Err(protocol_error("unknown address type in SOCKS5 reply")),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    conn.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// Username/password authentication, as in RFC 1929.
+async fn password_auth(conn: &mut impl Pipe, auth: &ProxyAuth) -> std::io::Result<()> {
+    let too_long = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "SOCKS5 username or password longer than 255 bytes",
+        )
+    };
+    let username_len = u8::try_from(auth.username.len()).map_err(|_| too_long())?;
+    let password_len = u8::try_from(auth.password.len()).map_err(|_| too_long())?;
+    let mut request = vec![1, username_len];
+    request.extend_from_slice(auth.username.as_bytes());
+    request.push(password_len);
+    request.extend_from_slice(auth.password.as_bytes());
+    conn.write_all(&request).await?;
+
+    let mut reply = [0u8; 2];
+    conn.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "SOCKS5 proxy rejected our credentials",
+        ));
+    }
+    Ok(())
+}
+
+fn reply_error(code: u8) -> std::io::Error {
+    let (kind, reason) = match code {
+        2 => (
+            std::io::ErrorKind::PermissionDenied,
+            "connection not allowed by ruleset",
+        ),
+        3 => (
+            std::io::ErrorKind::NetworkUnreachable,
+            "network unreachable",
+        ),
+        4 => (std::io::ErrorKind::HostUnreachable, "host unreachable"),
+        5 => (std::io::ErrorKind::ConnectionRefused, "connection refused"),
+        6 => (std::io::ErrorKind::TimedOut, "TTL expired"),
+        _ => (std::io::ErrorKind::Other, "general failure"),
+    };
+    std::io::Error::new(kind, format!("SOCKS5 proxy replied {code}: {reason}"))
+}
+
+fn protocol_error(msg: &'static str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use sillad::{listener::Listener, mem::MemListener};
+
+    use super::*;
+
+    #[test]
+    fn socks5_connect_with_auth() {
+        async_io::block_on(async {
+            let mut listener = MemListener::new();
+            let dialer = Socks5Dialer {
+                inner: listener.dialer(),
+                dest: "example.com:443".into(),
+                auth: Some(ProxyAuth {
+                    username: "alice".into(),
+                    password: "hunter2".into(),
+                }),
+            };
+            let proxy = async {
+                let mut conn = listener.accept().await.unwrap();
+                let mut greeting = [0u8; 4];
+                conn.read_exact(&mut greeting).await.unwrap();
+                assert_eq!(greeting, [5, 2, METHOD_NO_AUTH, METHOD_PASSWORD]);
+                conn.write_all(&[5, METHOD_PASSWORD]).await.unwrap();
+
+                let mut auth = [0u8; 1 + 1 + 5 + 1 + 7];
+                conn.read_exact(&mut auth).await.unwrap();
+                assert_eq!(&auth, b"\x01\x05alice\x07hunter2");
+                conn.write_all(&[1, 0]).await.unwrap();
+
+                let mut request = [0u8; 4 + 1 + 11 + 2];
+                conn.read_exact(&mut request).await.unwrap();
+                assert_eq!(&request, b"\x05\x01\x00\x03\x0bexample.com\x01\xbb");
+                conn.write_all(&[5, 0, 0, ATYP_IPV4, 10, 0, 0, 1, 0, 80])
+                    .await
+                    .unwrap();
+                conn.write_all(b"payload").await.unwrap();
+            };
+            let (mut pipe, _) =
+                futures_lite::future::zip(async { dialer.dial().await.unwrap() }, proxy).await;
+            assert_eq!(pipe.remote_addr(), Some("example.com:443"));
+            let mut buf = [0u8; 7];
+            pipe.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"payload");
+        })
+    }
+
+    #[test]
+    fn socks5_connect_refused() {
+        async_io::block_on(async {
+            let mut listener = MemListener::new();
+            let dialer = Socks5Dialer {
+                inner: listener.dialer(),
+                dest: "[::1]:22".into(),
+                auth: None,
+            };
+            let proxy = async {
+                let mut conn = listener.accept().await.unwrap();
+                let mut greeting = [0u8; 3];
+                conn.read_exact(&mut greeting).await.unwrap();
+                conn.write_all(&[5, METHOD_NO_AUTH]).await.unwrap();
+                let mut request = [0u8; 4 + 16 + 2];
+                conn.read_exact(&mut request).await.unwrap();
+                assert_eq!(request[3], ATYP_IPV6);
+                conn.write_all(&[5, 5, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                conn
+            };
+            let (res, _conn) = futures_lite::future::zip(dialer.dial(), proxy).await;
+            assert_eq!(
+                res.err().unwrap().kind(),
+                std::io::ErrorKind::ConnectionRefused
+            );
+        })
+    }
+}
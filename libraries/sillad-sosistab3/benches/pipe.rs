@@ -0,0 +1,67 @@
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use sillad::{dialer::Dialer, listener::Listener, mem::MemListener};
+use sillad_sosistab3::{dialer::SosistabDialer, listener::SosistabListener, Cookie};
+
+/// How much goes through the pipe per iteration.
+const TRANSFER: usize = 4 << 20;
+
+/// Pushes data through a pair of sosistab3 pipes connected in memory, writing it in chunks of the given size.
+fn transfer(cookie: &'static str, chunk: usize, iters: u64) -> Duration {
+    smolscale::block_on(async move {
+        let cookie = Cookie::new(cookie);
+        let lower = MemListener::new();
+        let dialer = SosistabDialer {
+            inner: lower.dialer(),
+            cookie,
+        };
+        let mut listener = SosistabListener::new(lower, cookie);
+        let (client, server) = futures_lite::future::zip(dialer.dial(), listener.accept()).await;
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        let data = vec![0x42u8; chunk];
+        let mut sink = vec![0u8; TRANSFER];
+
+        let start = Instant::now();
+        for _ in 0..iters {
+            let writer = async {
+                for _ in 0..TRANSFER / chunk {
+                    client.write_all(&data).await.unwrap();
+                }
+                client.flush().await.unwrap();
+            };
+            let reader = async {
+                server
+                    .read_exact(&mut sink[..TRANSFER / chunk * chunk])
+                    .await
+                    .unwrap();
+            };
+            futures_lite::future::zip(writer, reader).await;
+        }
+        start.elapsed()
+    })
+}
+
+fn pipe_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sosistab3_pipe");
+    group.throughput(Throughput::Bytes(TRANSFER as u64));
+    let cookies = [
+        ("plain", "bench"),
+        (
+            "obfs_lengths",
+            r#"bench---{"obfs_lengths":true,"obfs_timing":false}"#,
+        ),
+    ];
+    for (name, cookie) in cookies {
+        for chunk in [1400, 16384] {
+            group.bench_with_input(BenchmarkId::new(name, chunk), &chunk, |b, &chunk| {
+                b.iter_custom(|iters| transfer(cookie, chunk, iters))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pipe_throughput);
+criterion_main!(benches);
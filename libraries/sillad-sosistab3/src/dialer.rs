@@ -5,11 +5,16 @@ use std::{
 
 use async_trait::async_trait;
 use futures_util::{AsyncReadExt, AsyncWriteExt};
-use rand::{Rng, RngCore};
 use sillad::dialer::Dialer;
-use tap::Tap;
 
-use crate::{handshake::Handshake, state::State, Cookie, SosistabPipe};
+use crate::{
+    handshake::{
+        find_hidden, hidden_padding, hybrid_secret, kem_decapsulate, kem_keypair, padding_len,
+        random_padding, Handshake, KEM_CIPHERTEXT_LEN,
+    },
+    state::State,
+    Cookie, SosistabPipe,
+};
 
 pub struct SosistabDialer<D: Dialer> {
     pub inner: D,
@@ -21,13 +26,28 @@ impl<D: Dialer> Dialer for SosistabDialer<D> {
     type P = SosistabPipe<D::P>;
     #[tracing::instrument(skip(self))]
     async fn dial(&self) -> std::io::Result<Self::P> {
+        self.dial_inner(true).await
+    }
+}
+
+impl<D: Dialer> SosistabDialer<D> {
+    /// Dials, offering the server an ML-KEM key for a hybrid key exchange if `offer_kem` is set. Servers that don't know about hybrid key exchange just take the offer for padding.
+    pub(crate) async fn dial_inner(&self, offer_kem: bool) -> std::io::Result<SosistabPipe<D::P>> {
         let mut lower = self.inner.dial().await?;
         // send the upstream handshake
         let eph_sk = x25519_dalek::EphemeralSecret::random_from_rng(rand::thread_rng());
         let eph_pk: x25519_dalek::PublicKey = (&eph_sk).into();
-        // we generate a whole lot of random padding
-        let padding_len: u64 = rand::thread_rng().gen_range(0..=1024);
-        let padding = vec![0; padding_len as usize].tap_mut(|v| rand::thread_rng().fill_bytes(v));
+        // we generate a whole lot of random padding, possibly with our KEM key hidden at the start. its length doesn't depend on whether we offer the key
+        let (kem_sk, padding) = if offer_kem {
+            let (kem_sk, kem_pk) = kem_keypair();
+            (
+                Some(kem_sk),
+                hidden_padding(&kem_pk, self.cookie, false, padding_len(false)),
+            )
+        } else {
+            (None, random_padding(padding_len(false)))
+        };
+        let padding_len = padding.len() as u64;
         let padding_hash = blake3::hash(&padding);
         // generate the handshake
         let my_handshake = Handshake {
@@ -84,16 +104,21 @@ impl<D: Dialer> Dialer for SosistabDialer<D> {
             their_padding_len = their_handshake.padding_len,
             "their handshake received"
         );
-        // we are ready for the shared secret
-        let state = State::new(
-            eph_sk.diffie_hellman(&their_handshake.eph_pk).as_bytes(),
-            false,
-            self.cookie.params,
-        );
+        // we are ready for the shared secret, which is hybrid if the server took up our offer
+        let mut shared_secret = *eph_sk.diffie_hellman(&their_handshake.eph_pk).as_bytes();
+        let kem_secret = kem_sk.and_then(|kem_sk| {
+            let ct = find_hidden(&buff, KEM_CIPHERTEXT_LEN, self.cookie, true)?;
+            kem_decapsulate(&kem_sk, &ct)
+        });
+        if let Some(kem_secret) = kem_secret.as_ref() {
+            shared_secret = hybrid_secret(&shared_secret, kem_secret);
+        }
+        let state = State::new(&shared_secret, false, self.cookie.params);
         tracing::debug!(
             cookie = debug(self.cookie),
             padding_len,
             padding_hash = debug(padding_hash),
+            hybrid = kem_secret.is_some(),
             "shared secret generated"
         );
         Ok(SosistabPipe::new(lower, state))
@@ -1,12 +1,23 @@
 use arrayref::array_ref;
 use chacha20poly1305::{
-    aead::{AeadInPlace, OsRng},
+    aead::{rand_core::RngCore, Aead, AeadInPlace, OsRng},
     AeadCore,
 };
 use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use ml_kem::{kem::Decapsulate, kem::Encapsulate, EncodedSizeUser, KemCore, MlKem768};
+use rand::Rng;
 
 use crate::Cookie;
 
+/// The length of an encoded ML-KEM-768 encapsulation key.
+pub const KEM_KEY_LEN: usize = 1184;
+
+/// The length of an ML-KEM-768 ciphertext.
+pub const KEM_CIPHERTEXT_LEN: usize = 1088;
+
+/// The decapsulation key a client keeps while waiting for the server's handshake.
+pub type KemSecret = <MlKem768 as KemCore>::DecapsulationKey;
+
 /// A initial handshake message, which must be encrypted with the cookie before being sent.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Handshake {
@@ -80,6 +91,88 @@ impl Handshake {
     }
 }
 
+/// Generates an ML-KEM-768 keypair, returning the secret half along with the encoded public half.
+pub fn kem_keypair() -> (KemSecret, Vec<u8>) {
+    let (dk, ek) = MlKem768::generate(&mut OsRng);
+    (dk, ek.as_bytes().to_vec())
+}
+
+/// Encapsulates a fresh secret to an encoded encapsulation key, returning the ciphertext along with the secret.
+pub fn kem_encapsulate(ek: &[u8]) -> Option<(Vec<u8>, [u8; 32])> {
+    let ek = <MlKem768 as KemCore>::EncapsulationKey::from_bytes(ek.try_into().ok()?);
+    let (ct, secret) = ek.encapsulate(&mut OsRng).ok()?;
+    Some((ct.to_vec(), secret.into()))
+}
+
+/// Recovers the secret from a ciphertext made by [kem_encapsulate].
+pub fn kem_decapsulate(dk: &KemSecret, ct: &[u8]) -> Option<[u8; 32]> {
+    let secret = dk.decapsulate(ct.try_into().ok()?).ok()?;
+    Some(secret.into())
+}
+
+/// Combines the X25519 and ML-KEM shared secrets, so that the result stays secret as long as either of them does.
+pub fn hybrid_secret(x25519: &[u8; 32], kem: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_derive_key("sosistab3 hybrid secret");
+    hasher.update(x25519);
+    hasher.update(kem);
+    *hasher.finalize().as_bytes()
+}
+
+/// How much padding may be added on top of what it takes to hide a KEM key or ciphertext.
+const MAX_EXTRA_PADDING: usize = 1024;
+
+/// The length of a payload once [hidden_padding] has sealed it.
+const fn sealed_len(payload_len: usize) -> usize {
+    12 + payload_len + 16
+}
+
+/// Picks how long the padding of a handshake should be. The length is uniform over a range starting at the size of a sealed KEM key for clients, or of a sealed KEM ciphertext for servers, whether or not there is one to hide, so that the length never gives away a hybrid key exchange.
+pub fn padding_len(is_server: bool) -> usize {
+    let min = sealed_len(if is_server {
+        KEM_CIPHERTEXT_LEN
+    } else {
+        KEM_KEY_LEN
+    });
+    rand::thread_rng().gen_range(min..=min + MAX_EXTRA_PADDING)
+}
+
+/// Builds `len` bytes of random handshake padding.
+pub fn random_padding(len: usize) -> Vec<u8> {
+    let mut toret = vec![0u8; len];
+    OsRng.fill_bytes(&mut toret);
+    toret
+}
+
+/// Builds `len` bytes of handshake padding that starts with a hidden payload, encrypted with the cookie so that it looks just like the random padding that older versions send. Those check the padding against its hash, but otherwise ignore it.
+pub fn hidden_padding(payload: &[u8], cookie: Cookie, is_server: bool, len: usize) -> Vec<u8> {
+    assert!(
+        len >= sealed_len(payload.len()),
+        "padding too short to hide the payload"
+    );
+    let aead = ChaCha20Poly1305::new_from_slice(&padding_key(cookie, is_server)).unwrap();
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut toret = nonce.to_vec();
+    toret.extend_from_slice(&aead.encrypt(&nonce, payload).unwrap());
+    toret.extend_from_slice(&random_padding(len - toret.len()));
+    toret
+}
+
+/// Finds a hidden payload of the given length in handshake padding, if the other side put one there.
+pub fn find_hidden(
+    padding: &[u8],
+    payload_len: usize,
+    cookie: Cookie,
+    is_server: bool,
+) -> Option<Vec<u8>> {
+    let sealed = padding.get(12..sealed_len(payload_len))?;
+    let aead = ChaCha20Poly1305::new_from_slice(&padding_key(cookie, is_server)).unwrap();
+    aead.decrypt(padding[..12].into(), sealed).ok()
+}
+
+fn padding_key(cookie: Cookie, is_server: bool) -> [u8; 32] {
+    blake3::derive_key("sosistab3 hidden padding", &cookie.derive_key(is_server))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -137,4 +230,38 @@ mod tests {
         assert_eq!(handshake.padding_len, handshake_from_bytes.padding_len);
         assert_eq!(handshake.padding_hash, handshake_from_bytes.padding_hash);
     }
+
+    #[test]
+    fn hybrid_handshake_secrets_agree() {
+        let cookie = Cookie::random();
+        let (dk, ek) = kem_keypair();
+        let offer = hidden_padding(&ek, cookie, false, padding_len(false));
+        assert_eq!(find_hidden(&offer, KEM_KEY_LEN, cookie, false), Some(ek));
+        // neither random padding nor the other direction's key reveals anything
+        assert!(find_hidden(&offer, KEM_KEY_LEN, cookie, true).is_none());
+        assert!(find_hidden(&[0u8; 2000], KEM_KEY_LEN, cookie, false).is_none());
+        assert!(find_hidden(&offer[..500], KEM_KEY_LEN, cookie, false).is_none());
+
+        let ek = find_hidden(&offer, KEM_KEY_LEN, cookie, false).unwrap();
+        let (ct, server_secret) = kem_encapsulate(&ek).unwrap();
+        assert_eq!(ct.len(), KEM_CIPHERTEXT_LEN);
+        let client_secret = kem_decapsulate(&dk, &ct).unwrap();
+        assert_eq!(client_secret, server_secret);
+    }
+
+    #[test]
+    fn padding_len_hides_kem() {
+        for _ in 0..1000 {
+            let client = padding_len(false);
+            assert!((sealed_len(KEM_KEY_LEN)..=sealed_len(KEM_KEY_LEN) + 1024).contains(&client));
+            let server = padding_len(true);
+            assert!(
+                (sealed_len(KEM_CIPHERTEXT_LEN)..=sealed_len(KEM_CIPHERTEXT_LEN) + 1024)
+                    .contains(&server)
+            );
+        }
+        let cookie = Cookie::random();
+        let (_, ek) = kem_keypair();
+        assert_eq!(hidden_padding(&ek, cookie, false, 2000).len(), 2000);
+    }
 }
@@ -1,35 +1,51 @@
 use std::{
     collections::VecDeque,
     fmt::Debug,
-    io::{ErrorKind, Read, Write},
+    io::{ErrorKind, IoSlice, Read, Write},
+    pin::Pin,
     task::Poll,
 };
 
-use futures_util::{AsyncRead, AsyncWrite};
+use async_task::Task;
+use bipe::BipeWriter;
+use futures_util::{io::ReadHalf, AsyncRead, AsyncReadExt, AsyncWrite, Future};
 use pin_project::pin_project;
 
+use ring::WriteRing;
 use serde::{Deserialize, Serialize};
 use sillad::Pipe;
 use state::State;
 
+pub use padding::PaddingProfile;
+
 mod dedup;
 pub mod dialer;
 mod handshake;
 pub mod listener;
+mod padding;
+pub mod replay;
+mod ring;
 mod state;
+mod timing;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Cookie {
     key: [u8; 32],
     params: ObfsParams,
 }
 
-#[derive(Clone, Copy, Default, Deserialize, Serialize, Debug)]
+#[derive(Clone, Copy, Default, Deserialize, Serialize, Debug, PartialEq, Eq)]
 pub struct ObfsParams {
     // whether or not to pad write lengths
     pub obfs_lengths: bool,
+    // how to pad write lengths
+    #[serde(default)]
+    pub padding: PaddingProfile,
     // whether or not to add delays
     pub obfs_timing: bool,
+    // whether or not to send chaff while idle, only used together with obfs_timing
+    #[serde(default)]
+    pub obfs_chaff: bool,
 }
 
 impl Debug for Cookie {
@@ -80,29 +96,55 @@ impl Cookie {
     }
 }
 
+/// How much room for encrypted records a pipe starts out with.
+const WRITE_RING_CAPACITY: usize = 65536;
+
 /// An established sosistab3 connection.
+///
+/// Like any buffered writer, it may hold on to some of what was written until the next write or flush, so callers should flush when they stop writing.
 #[pin_project]
 pub struct SosistabPipe<P: Pipe> {
     #[pin]
-    lower: P,
+    lower: Lower<P>,
     state: State,
 
     read_buf: VecDeque<u8>,
     read_closed: bool,
     raw_read_buf: Vec<u8>,
 
-    to_write_buf: Vec<u8>,
+    write_ring: WriteRing,
 }
 
 impl<P: Pipe> SosistabPipe<P> {
     fn new(lower: P, state: State) -> Self {
+        let params = state.obfs_params();
+        let lower = if params.obfs_timing {
+            // the shaper needs to write on its own schedule, so it gets the write half and its own copy of the state
+            let remote_addr = lower.remote_addr().map(|s| s.to_string());
+            let (read, write) = lower.split();
+            let (plain_write, plain_read) = bipe::bipe(32768);
+            let shaper = smolscale::spawn(timing::shaper_loop(
+                plain_read,
+                state.clone(),
+                write,
+                params.obfs_chaff,
+            ));
+            Lower::Shaped {
+                read,
+                plain_write,
+                shaper: Some(shaper),
+                remote_addr,
+            }
+        } else {
+            Lower::Direct(lower)
+        };
         Self {
             lower,
             state,
             read_buf: Default::default(),
             read_closed: false,
             raw_read_buf: Default::default(),
-            to_write_buf: Default::default(),
+            write_ring: WriteRing::new(WRITE_RING_CAPACITY),
         }
     }
 }
@@ -110,70 +152,96 @@ impl<P: Pipe> SosistabPipe<P> {
 impl<P: Pipe> AsyncWrite for SosistabPipe<P> {
     #[tracing::instrument(name = "sosistab_write", skip(self, cx, buf))]
     fn poll_write(
-        self: std::pin::Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
-        // This implementation here is technically incorrect, if the caller doesn't poll the *same* buffer until completion.
-        // But it seems like it's not possible to be technically correct without spawning a background thread and introducing an extra copy, and this is pretty hot code.
-
-        let mut this = self.project();
-        if this.to_write_buf.is_empty() {
-            this.state.encrypt(buf, this.to_write_buf);
+        let this = self.project();
+        let mut lower = match this.lower.project() {
+            LowerProj::Direct(lower) => lower,
+            LowerProj::Shaped { plain_write, .. } => return plain_write.poll_write(cx, buf),
+        };
+        // records left over from earlier writes go first, and until they're gone we take nothing new
+        futures_util::ready!(poll_drain(lower.as_mut(), this.write_ring, cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
         }
-        loop {
-            tracing::trace!(bytes_to_write = this.to_write_buf.len(), "polling write");
-            let res = futures_util::ready!(this.lower.as_mut().poll_write(cx, this.to_write_buf));
-            match res {
-                Ok(n) => {
-                    tracing::trace!(
-                        bytes_to_write = this.to_write_buf.len(),
-                        just_wrote = n,
-                        plain_n = buf.len(),
-                        "successfully wrote"
-                    );
-                    this.to_write_buf.drain(..n);
-                    if this.to_write_buf.is_empty() {
-                        tracing::trace!(
-                            bytes_to_write = this.to_write_buf.len(),
-                            just_wrote = n,
-                            "returning Ready from write"
-                        );
-                        return Poll::Ready(Ok(buf.len()));
-                    }
-                }
-                Err(err) => return Poll::Ready(Err(err)),
-            }
+        this.state.encrypt_hello(this.write_ring);
+        this.state.encrypt(buf, this.write_ring);
+        // the data is ours now, so whatever doesn't go out right away waits for the next write or flush
+        if let Poll::Ready(Err(err)) = poll_drain(lower, this.write_ring, cx) {
+            tracing::debug!(err = debug(err), "write failed after buffering");
         }
+        tracing::trace!(
+            plain_n = buf.len(),
+            buffered = this.write_ring.len(),
+            "returning Ready from write"
+        );
+        Poll::Ready(Ok(buf.len()))
     }
 
     fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        let mut lower = match this.lower.project() {
+            LowerProj::Direct(lower) => lower,
+            // the shaper decides when to send, so there is nothing to wait for
+            LowerProj::Shaped { plain_write, .. } => return plain_write.poll_flush(cx),
+        };
+        futures_util::ready!(poll_drain(lower.as_mut(), this.write_ring, cx))?;
+        lower.poll_flush(cx)
+    }
+
+    fn poll_close(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<std::io::Result<()>> {
-        let mut this = self.project();
-        if !this.to_write_buf.is_empty() {
-            match futures_util::ready!(this.lower.as_mut().poll_write(cx, this.to_write_buf)) {
-                Ok(n) => {
-                    this.to_write_buf.drain(..n);
-                    if !this.to_write_buf.is_empty() {
-                        return Poll::Pending;
+        let this = self.project();
+        match this.lower.project() {
+            LowerProj::Direct(mut lower) => {
+                futures_util::ready!(poll_drain(lower.as_mut(), this.write_ring, cx))?;
+                lower.poll_close(cx)
+            }
+            LowerProj::Shaped {
+                plain_write,
+                shaper,
+                ..
+            } => {
+                // closing only returns once the shaper has sent everything and closed the lower pipe
+                futures_util::ready!(plain_write.poll_close(cx))?;
+                match shaper.as_mut() {
+                    Some(task) => {
+                        let res = futures_util::ready!(std::pin::Pin::new(task).poll(cx));
+                        *shaper = None;
+                        Poll::Ready(res)
                     }
-                }
-                Err(err) => {
-                    return Poll::Ready(Err(err));
+                    None => Poll::Ready(Ok(())),
                 }
             }
         }
-        this.lower.poll_flush(cx)
     }
+}
 
-    fn poll_close(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> Poll<std::io::Result<()>> {
-        self.project().lower.poll_close(cx)
+/// Writes queued records to the lower pipe until there are none left, with vectored writes since the queue may wrap around.
+fn poll_drain(
+    mut lower: Pin<&mut impl AsyncWrite>,
+    ring: &mut WriteRing,
+    cx: &mut std::task::Context<'_>,
+) -> Poll<std::io::Result<()>> {
+    while !ring.is_empty() {
+        let (first, second) = ring.as_slices();
+        let n = futures_util::ready!(lower
+            .as_mut()
+            .poll_write_vectored(cx, &[IoSlice::new(first), IoSlice::new(second)]))?;
+        if n == 0 {
+            return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+        }
+        tracing::trace!(just_wrote = n, left = ring.len() - n, "drained records");
+        ring.consume(n);
     }
+    Poll::Ready(Ok(()))
 }
 
 impl<P: Pipe> AsyncRead for SosistabPipe<P> {
@@ -206,21 +274,25 @@ impl<P: Pipe> AsyncRead for SosistabPipe<P> {
                             "read returned from lower"
                         );
                         // attempt to decrypt in order to fill the read_buf. we decrypt as many fragments as possible until we cannot decrypt anymore. at that point, we would need more fresh data to decrypt more.
+                        let mut decrypted = 0;
                         loop {
-                            match this.state.decrypt(this.raw_read_buf, &mut this.read_buf) {
+                            match this
+                                .state
+                                .decrypt(&this.raw_read_buf[decrypted..], &mut this.read_buf)
+                            {
                                 Ok(result) => {
+                                    decrypted += result;
                                     tracing::trace!(
                                         n,
-                                        raw_read_len = this.raw_read_buf.len(),
+                                        raw_read_len = this.raw_read_buf.len() - decrypted,
                                         buf_len = this.read_buf.len(),
                                         "decryption is successful"
                                     );
-                                    this.raw_read_buf.drain(..result);
                                 }
                                 Err(err) => {
                                     tracing::trace!(
                                         n,
-                                        raw_read_len = this.raw_read_buf.len(),
+                                        raw_read_len = this.raw_read_buf.len() - decrypted,
                                         buf_len = this.read_buf.len(),
                                         "could not decrypt yet due to {:?}",
                                         err
@@ -232,6 +304,8 @@ impl<P: Pipe> AsyncRead for SosistabPipe<P> {
                                 }
                             }
                         }
+                        // dropping everything decrypted at once, rather than record by record, keeps this linear in the amount read
+                        this.raw_read_buf.drain(..decrypted);
                     }
                 }
             }
@@ -245,10 +319,97 @@ impl<P: Pipe> Pipe for SosistabPipe<P> {
     }
 
     fn remote_addr(&self) -> Option<&str> {
-        self.lower.remote_addr()
+        match &self.lower {
+            Lower::Direct(lower) => lower.remote_addr(),
+            Lower::Shaped { remote_addr, .. } => remote_addr.as_deref(),
+        }
     }
 
     fn shared_secret(&self) -> Option<&[u8]> {
         Some(self.state.shared_secret())
     }
 }
+
+/// The pipe underneath a [SosistabPipe].
+#[pin_project(project = LowerProj)]
+enum Lower<P: Pipe> {
+    /// Records are written to the pipe as soon as they are produced.
+    Direct(#[pin] P),
+    /// Writes go to a background task that shapes their timing, which owns the write half of the pipe.
+    Shaped {
+        #[pin]
+        read: ReadHalf<P>,
+        #[pin]
+        plain_write: BipeWriter,
+        shaper: Option<Task<std::io::Result<()>>>,
+        remote_addr: Option<String>,
+    },
+}
+
+impl<P: Pipe> AsyncRead for Lower<P> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.project() {
+            LowerProj::Direct(lower) => lower.poll_read(cx, buf),
+            LowerProj::Shaped { read, .. } => read.poll_read(cx, buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{AsyncReadExt, AsyncWriteExt};
+    use sillad::mem::{FaultConfig, FaultyPipe, MemPipe};
+
+    use super::*;
+
+    #[test]
+    fn writes_survive_fickle_callers() {
+        smolscale::block_on(async {
+            let (client, server) = MemPipe::pair("fickle");
+            // a slow lower pipe that only ever takes part of what it's given
+            let client = FaultyPipe::new(
+                client,
+                FaultConfig {
+                    bandwidth: Some(5_000_000),
+                    partial_writes: true,
+                    ..Default::default()
+                },
+            );
+            let params = ObfsParams {
+                obfs_lengths: true,
+                ..Default::default()
+            };
+            let mut client = SosistabPipe::new(client, State::new(b"secret", false, params));
+            let mut server = SosistabPipe::new(server, State::new(b"secret", true, params));
+
+            let data: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+            let writer = async {
+                let mut sent = 0;
+                while sent < data.len() {
+                    // offer a different amount of data every time we get polled, which a correct writer must put up with
+                    let mut attempt = 0;
+                    let n = futures_util::future::poll_fn(|cx| {
+                        attempt += 1;
+                        let len = (data.len() - sent).min(1 + (attempt * 7919 + sent) % 20000);
+                        Pin::new(&mut client).poll_write(cx, &data[sent..][..len])
+                    })
+                    .await
+                    .unwrap();
+                    sent += n;
+                }
+                client.flush().await.unwrap();
+            };
+            let reader = async {
+                let mut received = vec![0u8; data.len()];
+                server.read_exact(&mut received).await.unwrap();
+                received
+            };
+            let ((), received) = futures_lite::future::zip(writer, reader).await;
+            assert!(received == data);
+        })
+    }
+}
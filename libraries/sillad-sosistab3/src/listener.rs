@@ -1,6 +1,6 @@
 use std::{
     io::ErrorKind,
-    sync::Mutex,
+    sync::{Arc, RwLock},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
@@ -8,138 +8,192 @@ use async_executor::Executor;
 
 use async_task::Task;
 use async_trait::async_trait;
+use futures_lite::FutureExt;
 use futures_util::{AsyncReadExt, AsyncWriteExt};
 
-use rand::{Rng, RngCore};
-use sillad::{listener::Listener, Pipe};
+use sillad::{
+    dialer::{Dialer, DynDialer},
+    listener::Listener,
+    Pipe,
+};
 use tachyonix::{Receiver, Sender};
-use tap::Tap;
 
-use crate::{dedup::Dedup, handshake::Handshake, state::State, Cookie, SosistabPipe};
+use crate::{
+    handshake::{
+        find_hidden, hidden_padding, hybrid_secret, kem_encapsulate, padding_len, random_padding,
+        Handshake, KEM_KEY_LEN,
+    },
+    replay::{MemoryReplayCache, ReplayCache},
+    state::State,
+    Cookie, SosistabPipe,
+};
+
+/// How long to tolerate replayed handshakes and clock skew.
+pub(crate) const WAIT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a connection may go silent partway through its handshake before it is handed to the fallback. Without a fallback, there is nothing to hand it to, so we wait for as long as the lower pipe does.
+const HANDSHAKE_STALL: Duration = Duration::from_secs(5);
+
+/// The cookies that a [SosistabListener] accepts, each until an optional expiry time.
+///
+/// Clones are handles to the same set, so cookies can be added and removed while listeners are using it. Rotating a cookie is a matter of adding the new one, then giving the old one an expiry far enough out for everybody to pick up the new one.
+#[derive(Clone, Default)]
+pub struct CookieSet {
+    inner: Arc<RwLock<Vec<CookieEntry>>>,
+}
+
+/// A cookie along with when it expires, if ever.
+type CookieEntry = (Cookie, Option<SystemTime>);
+
+impl CookieSet {
+    /// Creates a set containing a single cookie that never expires.
+    pub fn new(cookie: Cookie) -> Self {
+        let set = Self::default();
+        set.add(cookie, None);
+        set
+    }
+
+    /// Adds a cookie, accepted until `expiry` if given. Adding a cookie that's already there just changes its expiry.
+    pub fn add(&self, cookie: Cookie, expiry: Option<SystemTime>) {
+        let mut inner = self.inner.write().unwrap();
+        inner.retain(|(existing, _)| *existing != cookie);
+        inner.push((cookie, expiry));
+    }
+
+    /// Stops accepting a cookie.
+    pub fn remove(&self, cookie: &Cookie) {
+        self.inner
+            .write()
+            .unwrap()
+            .retain(|(existing, _)| existing != cookie);
+    }
+
+    /// The cookies that have not expired yet, most recently added first.
+    fn live(&self) -> Vec<Cookie> {
+        let now = SystemTime::now();
+        let mut inner = self.inner.write().unwrap();
+        inner.retain(|(_, expiry)| expiry.is_none_or(|expiry| expiry > now));
+        inner.iter().rev().map(|(cookie, _)| *cookie).collect()
+    }
+}
 
 /// A sosistab3 listener.
 pub struct SosistabListener<P: Pipe> {
     recv_pipe: Receiver<SosistabPipe<P>>,
+    cookies: CookieSet,
     _task: Task<std::io::Result<()>>,
 }
 
 impl<P: Pipe> SosistabListener<P> {
     /// Listens to incoming sosistab3 pipes by wrapping an existing sillad Listener.
     pub fn new(listener: impl Listener<P = P>, cookie: Cookie) -> Self {
+        Self::new_with_cookies(listener, CookieSet::new(cookie), None)
+    }
+
+    /// Like [SosistabListener::new], but connections that fail the handshake are not dropped. Instead, everything they sent is passed on to a connection made with the `fallback` dialer, such as one to a local web server, and the two are spliced together. To an active prober, the port then looks like whatever service is behind `fallback`.
+    pub fn new_with_fallback(
+        listener: impl Listener<P = P>,
+        cookie: Cookie,
+        fallback: DynDialer,
+    ) -> Self {
+        Self::new_with_cookies(listener, CookieSet::new(cookie), Some(fallback))
+    }
+
+    /// Creates a listener that accepts any of the given cookies, with an optional fallback as in [SosistabListener::new_with_fallback].
+    pub fn new_with_cookies(
+        listener: impl Listener<P = P>,
+        cookies: CookieSet,
+        fallback: Option<DynDialer>,
+    ) -> Self {
+        Self::new_with_replay_cache(
+            listener,
+            cookies,
+            fallback,
+            Arc::new(MemoryReplayCache::default()),
+        )
+    }
+
+    /// Like [SosistabListener::new_with_cookies], but checks handshakes against the given replay cache instead of one private to this listener. Listeners sharing a cookie, even across processes, should share a cache as well.
+    pub fn new_with_replay_cache(
+        listener: impl Listener<P = P>,
+        cookies: CookieSet,
+        fallback: Option<DynDialer>,
+        replay_cache: Arc<dyn ReplayCache>,
+    ) -> Self {
         let (send_pipe, recv_pipe) = tachyonix::channel(1);
-        let _task = smolscale::spawn(listen_loop(listener, send_pipe, cookie));
-        Self { recv_pipe, _task }
+        let _task = smolscale::spawn(listen_loop(
+            listener,
+            send_pipe,
+            cookies.clone(),
+            fallback,
+            replay_cache,
+        ));
+        Self {
+            recv_pipe,
+            cookies,
+            _task,
+        }
+    }
+
+    /// A handle to the cookies this listener accepts, through which they can be changed at runtime.
+    pub fn cookies(&self) -> CookieSet {
+        self.cookies.clone()
     }
 }
 
-#[tracing::instrument(skip(listener, send_pipe))]
+#[tracing::instrument(skip_all)]
 async fn listen_loop<P: Pipe>(
     mut listener: impl Listener<P = P>,
     send_pipe: Sender<SosistabPipe<P>>,
-    cookie: Cookie,
+    cookies: CookieSet,
+    fallback: Option<DynDialer>,
+    replay_cache: Arc<dyn ReplayCache>,
 ) -> std::io::Result<()> {
-    const WAIT_INTERVAL: Duration = Duration::from_secs(30);
-
     if std::env::var("SOSISTAB3_WAIT").is_ok() {
         async_io::Timer::after(WAIT_INTERVAL).await;
     }
 
-    let dedup = Mutex::new(Dedup::new(WAIT_INTERVAL * 2));
-    let dedup = &dedup;
+    let replay_cache = replay_cache.as_ref();
     let lexec = Executor::new();
     lexec
         .run(async {
             loop {
                 let mut lower = listener.accept().await?;
                 let send_pipe = send_pipe.clone();
+                let fallback = fallback.clone();
+                let cookies = cookies.clone();
                 lexec
                     .spawn(async move {
-                        let current_timestamp = SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs();
-                        // receive their handshake
-                        let mut their_handshake = [0u8; 140];
-                        lower.read_exact(&mut their_handshake).await?;
-                        let their_handshake_hash = blake3::hash(&their_handshake);
-                        let their_handshake = Handshake::decrypt(their_handshake, cookie, false)?;
-                        tracing::debug!(
-                            their_handshake_hash = debug(their_handshake_hash),
-                            "handshake received"
-                        );
-                        // read their padding
-                        let mut buff = vec![0u8; their_handshake.padding_len as usize];
-                        lower.read_exact(&mut buff).await?;
-                        if blake3::hash(&buff) != their_handshake.padding_hash {
-                            return Err(std::io::Error::new(
-                                ErrorKind::InvalidData,
-                                "the client handshake gave us an incorrect padding hash",
-                            ));
-                        }
-                        tracing::debug!(
-                            their_handshake_hash = debug(their_handshake_hash),
-                            their_padding_hash = debug(their_handshake.padding_hash),
-                            "handshake verified"
-                        );
-
-                        // verify timestamp / deduplicate.
+                        // everything the client sent during the handshake, in case it has to be replayed to the fallback
+                        let mut consumed = vec![];
+                        let mut early_decoy = None;
+                        match server_handshake(
+                            &mut lower,
+                            &cookies,
+                            replay_cache,
+                            &mut consumed,
+                            fallback.as_ref(),
+                            &mut early_decoy,
+                        )
+                        .await
                         {
-                            if their_handshake.timestamp.abs_diff(current_timestamp)
-                                > WAIT_INTERVAL.as_secs()
-                            {
-                                return Err(std::io::Error::new(
-                                    ErrorKind::InvalidData,
-                                    "the client handshake has a bad timestamp",
-                                ));
+                            Ok(state) => {
+                                let pipe = SosistabPipe::new(lower, state);
+                                let _ = send_pipe.send(pipe).await;
+                                Ok(())
                             }
-
-                            let mut dedup = dedup.lock().unwrap();
-                            if dedup.contains(&their_handshake_hash) {
-                                return Err(std::io::Error::new(
-                                    ErrorKind::InvalidData,
-                                    "handshake already seen",
-                                ));
-                            }
-                            dedup.insert(their_handshake_hash);
+                            Err(err) => match fallback {
+                                Some(fallback) => {
+                                    tracing::debug!(
+                                        err = debug(err),
+                                        consumed = consumed.len(),
+                                        "handshake failed, splicing to the fallback"
+                                    );
+                                    splice_to_fallback(lower, consumed, fallback, early_decoy).await
+                                }
+                                None => Err(err),
+                            },
                         }
-
-                        // send the upstream handshake
-                        let eph_sk =
-                            x25519_dalek::EphemeralSecret::random_from_rng(rand::thread_rng());
-                        let eph_pk: x25519_dalek::PublicKey = (&eph_sk).into();
-                        // we generate a whole lot of random padding
-                        let padding_len: u64 = rand::thread_rng().gen_range(0..=1024);
-                        let padding = vec![0; padding_len as usize]
-                            .tap_mut(|v| rand::thread_rng().fill_bytes(v));
-                        let padding_hash = blake3::hash(&padding);
-                        // generate the handshake
-                        let my_handshake = Handshake {
-                            eph_pk,
-                            timestamp: current_timestamp,
-                            padding_len,
-                            padding_hash,
-                            responding_to: their_handshake_hash,
-                        };
-                        // send the stuff
-                        let mut to_send = vec![];
-                        let my_handshake = my_handshake.encrypt(cookie, true);
-                        to_send.extend_from_slice(&my_handshake);
-                        to_send.extend_from_slice(&padding);
-                        lower.write_all(&to_send).await?;
-                        // we are ready for the shared secret
-                        let state = State::new(
-                            eph_sk.diffie_hellman(&their_handshake.eph_pk).as_bytes(),
-                            true,
-                            cookie.params,
-                        );
-                        tracing::debug!(
-                            their_handshake_hash = debug(their_handshake_hash),
-                            their_padding_hash = debug(their_handshake.padding_hash),
-                            "pipe established"
-                        );
-                        let pipe = SosistabPipe::new(lower, state);
-                        let _ = send_pipe.send(pipe).await;
-                        Ok(())
                     })
                     .detach()
             }
@@ -147,6 +201,244 @@ async fn listen_loop<P: Pipe>(
         .await
 }
 
+/// A connection to the fallback, opened while a handshake was still arriving.
+struct EarlyDecoy {
+    pipe: Box<dyn Pipe>,
+    // whatever the fallback already said back, which the client hasn't seen yet
+    answered: Vec<u8>,
+}
+
+/// Runs the server side of the handshake, returning the state of the established connection.
+///
+/// If the fallback is given, a client whose handshake doesn't arrive all at once has what it sent so far forwarded to the fallback, and should the fallback answer before the handshake is complete, we stop waiting for it. Genuine clients send their whole handshake in one go, while a prober sending a short request would otherwise be met with a tell-tale silence.
+async fn server_handshake(
+    lower: &mut impl Pipe,
+    cookies: &CookieSet,
+    replay_cache: &dyn ReplayCache,
+    consumed: &mut Vec<u8>,
+    fallback: Option<&DynDialer>,
+    early_decoy: &mut Option<EarlyDecoy>,
+) -> std::io::Result<State> {
+    let current_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    // receive their handshake
+    let mut their_handshake = [0u8; 140];
+    read_header(lower, &mut their_handshake, consumed, fallback, early_decoy).await?;
+    let their_handshake_hash = blake3::hash(&their_handshake);
+    // whichever cookie the client used, we answer with the same one
+    let (cookie, their_handshake) = cookies
+        .live()
+        .into_iter()
+        .find_map(|cookie| {
+            Handshake::decrypt(their_handshake, cookie, false)
+                .ok()
+                .map(|handshake| (cookie, handshake))
+        })
+        .ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                "the client handshake matches none of our cookies",
+            )
+        })?;
+    tracing::debug!(
+        their_handshake_hash = debug(their_handshake_hash),
+        "handshake received"
+    );
+    // it decrypted, so this is no prober that the fallback could fool
+    *early_decoy = None;
+    // read their padding
+    let mut buff = vec![0u8; their_handshake.padding_len as usize];
+    read_recorded(lower, &mut buff, consumed, fallback.is_some()).await?;
+    if blake3::hash(&buff) != their_handshake.padding_hash {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "the client handshake gave us an incorrect padding hash",
+        ));
+    }
+    tracing::debug!(
+        their_handshake_hash = debug(their_handshake_hash),
+        their_padding_hash = debug(their_handshake.padding_hash),
+        "handshake verified"
+    );
+
+    // verify timestamp / deduplicate.
+    {
+        if their_handshake.timestamp.abs_diff(current_timestamp) > WAIT_INTERVAL.as_secs() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "the client handshake has a bad timestamp",
+            ));
+        }
+
+        if !replay_cache.insert_if_new(their_handshake_hash.as_bytes()) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "handshake already seen",
+            ));
+        }
+    }
+
+    // send the upstream handshake
+    let eph_sk = x25519_dalek::EphemeralSecret::random_from_rng(rand::thread_rng());
+    let eph_pk: x25519_dalek::PublicKey = (&eph_sk).into();
+    // we generate a whole lot of random padding, with a KEM ciphertext hidden at the start if the client offered a KEM key. its length doesn't depend on whether they did
+    let kem = find_hidden(&buff, KEM_KEY_LEN, cookie, false).and_then(|pk| kem_encapsulate(&pk));
+    let padding = match &kem {
+        Some((ct, _)) => hidden_padding(ct, cookie, true, padding_len(true)),
+        None => random_padding(padding_len(true)),
+    };
+    let padding_len = padding.len() as u64;
+    let padding_hash = blake3::hash(&padding);
+    // generate the handshake
+    let my_handshake = Handshake {
+        eph_pk,
+        timestamp: current_timestamp,
+        padding_len,
+        padding_hash,
+        responding_to: their_handshake_hash,
+    };
+    // send the stuff
+    let mut to_send = vec![];
+    let my_handshake = my_handshake.encrypt(cookie, true);
+    to_send.extend_from_slice(&my_handshake);
+    to_send.extend_from_slice(&padding);
+    lower.write_all(&to_send).await?;
+    // we are ready for the shared secret
+    let mut shared_secret = *eph_sk.diffie_hellman(&their_handshake.eph_pk).as_bytes();
+    if let Some((_, kem_secret)) = &kem {
+        shared_secret = hybrid_secret(&shared_secret, kem_secret);
+    }
+    let state = State::new(&shared_secret, true, cookie.params);
+    tracing::debug!(
+        their_handshake_hash = debug(their_handshake_hash),
+        their_padding_hash = debug(their_handshake.padding_hash),
+        hybrid = kem.is_some(),
+        "pipe established"
+    );
+    Ok(state)
+}
+
+/// Like [read_recorded], but once the fallback is given and the buffer wasn't filled by the first read, everything is also forwarded to an [EarlyDecoy], and we give up as soon as it answers.
+async fn read_header(
+    lower: &mut impl Pipe,
+    buf: &mut [u8],
+    consumed: &mut Vec<u8>,
+    fallback: Option<&DynDialer>,
+    early_decoy: &mut Option<EarlyDecoy>,
+) -> std::io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = match early_decoy {
+            None => read_stalling(lower, &mut buf[filled..], fallback.is_some()).await?,
+            Some(decoy) => {
+                let mut answer = [0u8; 4096];
+                let lower_read = async {
+                    std::io::Result::Ok(Some(read_stalling(lower, &mut buf[filled..], true).await?))
+                };
+                let decoy_read = async {
+                    let n = decoy.pipe.read(&mut answer).await?;
+                    decoy.answered.extend_from_slice(&answer[..n]);
+                    Ok(None)
+                };
+                match lower_read.or(decoy_read).await? {
+                    Some(n) => n,
+                    None => {
+                        return Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            "the fallback answered before the handshake arrived",
+                        ))
+                    }
+                }
+            }
+        };
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        consumed.extend_from_slice(&buf[filled..][..n]);
+        filled += n;
+        match (early_decoy.as_mut(), fallback) {
+            (Some(decoy), _) => decoy.pipe.write_all(&buf[filled - n..filled]).await?,
+            (None, Some(fallback)) if filled < buf.len() => {
+                let mut pipe = fallback.dial().await?;
+                pipe.write_all(consumed).await?;
+                *early_decoy = Some(EarlyDecoy {
+                    pipe,
+                    answered: vec![],
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Fills the buffer, also appending whatever is read to `consumed`, even if we give up halfway.
+async fn read_recorded(
+    lower: &mut impl Pipe,
+    buf: &mut [u8],
+    consumed: &mut Vec<u8>,
+    stall: bool,
+) -> std::io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = read_stalling(lower, &mut buf[filled..], stall).await?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        consumed.extend_from_slice(&buf[filled..][..n]);
+        filled += n;
+    }
+    Ok(())
+}
+
+/// Reads once, giving up if `stall` is set and nothing arrives for [HANDSHAKE_STALL].
+async fn read_stalling(
+    lower: &mut impl Pipe,
+    buf: &mut [u8],
+    stall: bool,
+) -> std::io::Result<usize> {
+    if !stall {
+        return lower.read(buf).await;
+    }
+    async { lower.read(buf).await }
+        .or(async {
+            async_io::Timer::after(HANDSHAKE_STALL).await;
+            Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "handshake stalled",
+            ))
+        })
+        .await
+}
+
+/// Hands a connection that failed the handshake over to the fallback, replaying what it already sent, unless an [EarlyDecoy] has already seen it.
+async fn splice_to_fallback(
+    mut lower: impl Pipe,
+    consumed: Vec<u8>,
+    fallback: DynDialer,
+    early_decoy: Option<EarlyDecoy>,
+) -> std::io::Result<()> {
+    let decoy = match early_decoy {
+        Some(early) => {
+            lower.write_all(&early.answered).await?;
+            early.pipe
+        }
+        None => {
+            let mut decoy = fallback.dial().await?;
+            decoy.write_all(&consumed).await?;
+            decoy
+        }
+    };
+    let (lower_read, mut lower_write) = lower.split();
+    let (decoy_read, mut decoy_write) = decoy.split();
+    futures_util::io::copy(lower_read, &mut decoy_write)
+        .race(futures_util::io::copy(decoy_read, &mut lower_write))
+        .await?;
+    Ok(())
+}
+
 #[async_trait]
 impl<P: Pipe> Listener for SosistabListener<P> {
     type P = SosistabPipe<P>;
@@ -160,6 +452,196 @@ impl<P: Pipe> Listener for SosistabListener<P> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use sillad::{dialer::DialerExt, mem::MemListener};
+
+    use super::*;
+    use crate::dialer::SosistabDialer;
+
+    #[test]
+    fn fallback_on_bad_handshake() {
+        smolscale::block_on(async {
+            let mut decoy = MemListener::new();
+            let lower = MemListener::new();
+            let lower_dialer = lower.dialer();
+            let cookie = Cookie::new("hello");
+            let mut listener =
+                SosistabListener::new_with_fallback(lower, cookie, decoy.dialer().dynamic());
+
+            // a prober sending garbage ends up talking to the decoy, which sees everything the prober sent
+            let mut prober = lower_dialer.dial().await.unwrap();
+            let garbage = vec![0x42u8; 200];
+            prober.write_all(&garbage).await.unwrap();
+            let mut decoy_conn = decoy.accept().await.unwrap();
+            let mut seen = vec![0u8; 200];
+            decoy_conn.read_exact(&mut seen).await.unwrap();
+            assert_eq!(seen, garbage);
+            decoy_conn
+                .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = [0u8; 28];
+            prober.read_exact(&mut response).await.unwrap();
+            assert_eq!(&response, b"HTTP/1.1 400 Bad Request\r\n\r\n");
+
+            // real clients are unaffected
+            let dialer = SosistabDialer {
+                inner: lower_dialer,
+                cookie,
+            };
+            let (client, server) =
+                futures_lite::future::zip(dialer.dial(), listener.accept()).await;
+            let (mut client, mut server) = (client.unwrap(), server.unwrap());
+            client.write_all(b"hi").await.unwrap();
+            let mut buf = [0u8; 2];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hi");
+        })
+    }
+
+    #[test]
+    fn slow_clients_without_fallback() {
+        smolscale::block_on(async {
+            let front = MemListener::new();
+            let lower = MemListener::new();
+            let lower_dialer = lower.dialer();
+            let mut listener = SosistabListener::new(lower, Cookie::new("hello"));
+
+            // relays a client, but stalls in the middle of its handshake for longer than we'd wait with a fallback
+            let relay_dialer = front.dialer();
+            let relay = smolscale::spawn(async move {
+                let mut front = front;
+                let client = front.accept().await?;
+                let mut server = lower_dialer.dial().await?;
+                let (mut client_read, mut client_write) = client.split();
+                let mut buf = vec![0u8; 65536];
+                let n = client_read.read(&mut buf).await?;
+                server.write_all(&buf[..10]).await?;
+                async_io::Timer::after(HANDSHAKE_STALL + Duration::from_secs(1)).await;
+                server.write_all(&buf[10..n]).await?;
+                let (mut server_read, mut server_write) = server.split();
+                futures_lite::future::try_zip(
+                    futures_util::io::copy(&mut client_read, &mut server_write),
+                    futures_util::io::copy(&mut server_read, &mut client_write),
+                )
+                .await?;
+                std::io::Result::Ok(())
+            });
+
+            let dialer = SosistabDialer {
+                inner: relay_dialer,
+                cookie: Cookie::new("hello"),
+            };
+            let (client, server) =
+                futures_lite::future::zip(dialer.dial(), listener.accept()).await;
+            let (mut client, mut server) = (client.unwrap(), server.unwrap());
+            client.write_all(b"hi").await.unwrap();
+            let mut buf = [0u8; 2];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hi");
+            drop(relay);
+        })
+    }
+
+    #[test]
+    fn short_probes_are_answered_promptly() {
+        smolscale::block_on(async {
+            let mut decoy = MemListener::new();
+            let lower = MemListener::new();
+            let lower_dialer = lower.dialer();
+            let _listener = SosistabListener::new_with_fallback(
+                lower,
+                Cookie::new("hello"),
+                decoy.dialer().dynamic(),
+            );
+
+            // a request shorter than a handshake reaches the decoy right away, and so does its answer
+            let mut prober = lower_dialer.dial().await.unwrap();
+            prober.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            let mut decoy_conn = decoy.accept().await.unwrap();
+            let mut seen = [0u8; 18];
+            decoy_conn.read_exact(&mut seen).await.unwrap();
+            assert_eq!(&seen, b"GET / HTTP/1.1\r\n\r\n");
+            decoy_conn
+                .write_all(b"HTTP/1.1 200 OK\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = [0u8; 19];
+            async { prober.read_exact(&mut response).await.unwrap() }
+                .or(async {
+                    async_io::Timer::after(HANDSHAKE_STALL / 5).await;
+                    panic!("the prober was kept waiting")
+                })
+                .await;
+            assert_eq!(&response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+            // the rest of the conversation goes through as usual
+            prober.write_all(b"more").await.unwrap();
+            let mut more = [0u8; 4];
+            decoy_conn.read_exact(&mut more).await.unwrap();
+            assert_eq!(&more, b"more");
+        })
+    }
+
+    #[test]
+    fn clients_without_hybrid_key_exchange() {
+        smolscale::block_on(async {
+            let lower = MemListener::new();
+            let dialer = SosistabDialer {
+                inner: lower.dialer(),
+                cookie: Cookie::new("hello"),
+            };
+            let mut listener = SosistabListener::new(lower, Cookie::new("hello"));
+            for offer_kem in [true, false] {
+                let (client, server) =
+                    futures_lite::future::zip(dialer.dial_inner(offer_kem), listener.accept())
+                        .await;
+                let (mut client, mut server) = (client.unwrap(), server.unwrap());
+                // both ends must have come up with the same key to get anything across
+                client.write_all(b"hi").await.unwrap();
+                let mut buf = [0u8; 2];
+                server.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hi");
+            }
+        })
+    }
+
+    #[test]
+    fn cookie_rotation() {
+        smolscale::block_on(async {
+            let lower = MemListener::new();
+            let lower_dialer = lower.dialer();
+            let (old, new) = (Cookie::new("old"), Cookie::new("new"));
+            let mut listener = SosistabListener::new(lower, old);
+            let connects = |cookie| {
+                let dialer = SosistabDialer {
+                    inner: lower_dialer.clone(),
+                    cookie,
+                };
+                async move { dialer.dial().await.is_ok() }
+            };
+
+            assert!(!connects(new).await);
+            listener.cookies().add(new, None);
+            assert!(connects(new).await);
+            listener.accept().await.unwrap();
+            assert!(connects(old).await);
+            listener.accept().await.unwrap();
+
+            // the old cookie lingers until it expires, and can be removed outright
+            listener
+                .cookies()
+                .add(old, Some(SystemTime::now() - Duration::from_secs(1)));
+            assert!(!connects(old).await);
+            listener.cookies().add(old, None);
+            listener.cookies().remove(&old);
+            assert!(!connects(old).await);
+            assert!(connects(new).await);
+        })
+    }
+}
+
 // fn dedup_handshake(current_timestamp: u64, handshake: Handshake) -> std::io::Result<()> {
 //     if current_timestamp.abs_diff(handshake.timestamp) > 600 {
 //         return Err(std::io::Error::new(
@@ -0,0 +1,113 @@
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::state::RECORD_OVERHEAD;
+
+/// Cells smaller than this could not always fit a padding record into the leftover space.
+const MIN_CELL_SIZE: usize = 64;
+
+/// Buckets of (smallest, largest, weight), roughly following the sizes of TCP segments carrying HTTPS: mostly small requests and headers, or full-sized segments in the middle of a download.
+const HTTPS_HISTOGRAM: &[(usize, usize, u32)] = &[
+    (40, 100, 15),
+    (100, 300, 20),
+    (300, 700, 15),
+    (700, 1200, 10),
+    (1200, 1460, 40),
+];
+
+/// How short records are padded, when `obfs_lengths` is on.
+///
+/// Padding is only ever added by the sender and discarded by the receiver, so the two ends of a connection need not agree on a profile.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PaddingProfile {
+    /// Pads every record shorter than `max` bytes to a uniformly random length no longer than `max`.
+    Uniform { max: u16 },
+    /// Pads records to lengths drawn from a histogram of typical HTTPS segment sizes.
+    #[default]
+    Https,
+    /// Pads every record to a whole number of `size`-byte cells.
+    FixedCells { size: u16 },
+}
+
+impl PaddingProfile {
+    /// Given the on-the-wire length of a data record, returns the on-the-wire length of the padding record to send after it, or zero to send none.
+    pub(crate) fn padding_len(&self, record_len: usize) -> usize {
+        let mut rng = rand::thread_rng();
+        let target = match *self {
+            PaddingProfile::Uniform { max } => {
+                let max = max as usize;
+                if record_len >= max {
+                    return 0;
+                }
+                rng.gen_range(record_len..=max)
+            }
+            PaddingProfile::Https => {
+                // only the buckets that the record still fits into
+                let buckets: Vec<_> = HTTPS_HISTOGRAM
+                    .iter()
+                    .filter(|(_, largest, _)| *largest > record_len)
+                    .collect();
+                let Ok((smallest, largest, _)) = buckets.choose_weighted(&mut rng, |b| b.2) else {
+                    return 0;
+                };
+                rng.gen_range((*smallest).max(record_len)..=*largest)
+            }
+            PaddingProfile::FixedCells { size } => {
+                let size = (size as usize).max(MIN_CELL_SIZE);
+                let target = record_len.next_multiple_of(size);
+                if target > record_len && target - record_len < RECORD_OVERHEAD {
+                    // not enough room for a padding record, so spill into the next cell
+                    target + size
+                } else {
+                    target
+                }
+            }
+        };
+        let gap = target - record_len;
+        if gap < RECORD_OVERHEAD {
+            0
+        } else {
+            gap
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padding_profiles_respect_their_shapes() {
+        for record_len in [RECORD_OVERHEAD, 50, 100, 537, 1400, 1459, 1460, 5000] {
+            for _ in 0..100 {
+                let padding = PaddingProfile::Uniform { max: 1000 }.padding_len(record_len);
+                assert!(padding == 0 || padding >= RECORD_OVERHEAD);
+                assert!(padding == 0 || record_len + padding <= 1000);
+
+                let padding = PaddingProfile::Https.padding_len(record_len);
+                assert!(padding == 0 || padding >= RECORD_OVERHEAD);
+                assert!(padding == 0 || record_len + padding <= 1460);
+
+                let padding = PaddingProfile::FixedCells { size: 512 }.padding_len(record_len);
+                assert_eq!((record_len + padding) % 512, 0);
+                assert!(padding == 0 || padding >= RECORD_OVERHEAD);
+            }
+        }
+    }
+
+    #[test]
+    fn padding_profile_from_cookie() {
+        let cookie = crate::Cookie::new(
+            r#"hello---{"obfs_lengths":true,"obfs_timing":false,"padding":{"kind":"fixed_cells","size":512}}"#,
+        );
+        assert_eq!(
+            cookie.params.padding,
+            PaddingProfile::FixedCells { size: 512 }
+        );
+        // cookies from before padding profiles were a thing
+        let cookie = crate::Cookie::new(r#"hello---{"obfs_lengths":true,"obfs_timing":false}"#);
+        assert!(cookie.params.obfs_lengths);
+        assert_eq!(cookie.params.padding, PaddingProfile::Https);
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    fs::{File, OpenOptions},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use memmap2::MmapMut;
+
+use crate::{dedup::Dedup, listener::WAIT_INTERVAL};
+
+/// How long handshakes are remembered. Any older handshake already fails the timestamp check.
+const REPLAY_WINDOW: Duration = Duration::from_secs(WAIT_INTERVAL.as_secs() * 2);
+
+/// Remembers the handshakes a listener has recently seen, so that replays of captured handshakes can be rejected.
+///
+/// Listeners that share a cookie should share a cache too. Otherwise, a handshake captured on its way to one of them can be replayed against another.
+pub trait ReplayCache: Send + Sync + 'static {
+    /// Records the hash of a handshake, returning false if it was already seen within the cache's window.
+    fn insert_if_new(&self, hash: &[u8; 32]) -> bool;
+}
+
+/// A replay cache private to one process.
+pub struct MemoryReplayCache {
+    dedup: Mutex<Dedup<[u8; 32]>>,
+}
+
+impl Default for MemoryReplayCache {
+    fn default() -> Self {
+        Self {
+            dedup: Mutex::new(Dedup::new(REPLAY_WINDOW)),
+        }
+    }
+}
+
+impl ReplayCache for MemoryReplayCache {
+    fn insert_if_new(&self, hash: &[u8; 32]) -> bool {
+        let mut dedup = self.dedup.lock().unwrap();
+        if dedup.contains(hash) {
+            return false;
+        }
+        dedup.insert(*hash);
+        true
+    }
+}
+
+/// Each slot holds a hash and the time it was inserted, in seconds since the epoch. A time of zero marks an empty slot.
+const SLOT_LEN: usize = 32 + 8;
+
+/// How many slots after its home slot a hash may end up in. Past that, the oldest of them gets evicted.
+const MAX_PROBE: usize = 32;
+
+/// A replay cache kept in a memory-mapped file, so that every process on a host that opens the same file shares it.
+///
+/// The file is a fixed-size hash table, and concurrent access is serialized with a lock on the file. Once the table is full of live entries, the oldest ones are forgotten early, so it should be sized well above the number of handshakes expected within the window.
+pub struct FileReplayCache {
+    file: File,
+    map: Mutex<MmapMut>,
+    slots: usize,
+}
+
+impl FileReplayCache {
+    /// Opens the cache at the given path, creating it with room for `slots` entries if it doesn't exist. Every process must use the same number of slots.
+    pub fn open(path: impl AsRef<Path>, slots: usize) -> std::io::Result<Self> {
+        if slots < MAX_PROBE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "replay cache too small",
+            ));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let len = (slots * SLOT_LEN) as u64;
+        {
+            file.lock()?;
+            let res = (|| {
+                let existing = file.metadata()?.len();
+                if existing == 0 {
+                    file.set_len(len)?;
+                } else if existing != len {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "replay cache file has a different number of slots",
+                    ));
+                }
+                Ok(())
+            })();
+            file.unlock()?;
+            res?;
+        }
+        // SAFETY: the file is only ever modified through mappings like this one, while holding the file lock
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(Self {
+            file,
+            map: Mutex::new(map),
+            slots,
+        })
+    }
+}
+
+impl ReplayCache for FileReplayCache {
+    fn insert_if_new(&self, hash: &[u8; 32]) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let is_live = |time: u64| time != 0 && now.saturating_sub(time) <= REPLAY_WINDOW.as_secs();
+
+        // the mutex keeps out other threads, since the file lock doesn't
+        let mut map = self.map.lock().unwrap();
+        if let Err(err) = self.file.lock() {
+            // failing open would let replays through, but failing closed would lock out everybody
+            tracing::warn!(err = debug(err), "could not lock the replay cache");
+            return true;
+        }
+        let home = u64::from_le_bytes(hash[..8].try_into().unwrap()) as usize % self.slots;
+        let mut victim: Option<(usize, u64)> = None;
+        let mut fresh = true;
+        // slots in the chain empty out as they expire, so a hash may sit past an empty slot, and we must look at all of them
+        for probe in 0..MAX_PROBE {
+            let idx = (home + probe) % self.slots;
+            let slot = &map[idx * SLOT_LEN..][..SLOT_LEN];
+            let time = u64::from_le_bytes(slot[32..].try_into().unwrap());
+            if !is_live(time) {
+                // the first empty or expired slot is as good as it gets
+                if victim.is_none_or(|(_, oldest)| oldest != 0) {
+                    victim = Some((idx, 0));
+                }
+                continue;
+            }
+            if &slot[..32] == hash {
+                fresh = false;
+                break;
+            }
+            if victim.is_none_or(|(_, oldest)| oldest != 0 && time < oldest) {
+                victim = Some((idx, time));
+            }
+        }
+        if fresh {
+            let (idx, _) = victim.expect("probed at least one slot");
+            let slot = &mut map[idx * SLOT_LEN..][..SLOT_LEN];
+            slot[..32].copy_from_slice(hash);
+            slot[32..].copy_from_slice(&now.to_le_bytes());
+        }
+        let _ = self.file.unlock();
+        fresh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tap::Tap;
+
+    use super::*;
+
+    #[test]
+    fn file_replay_cache_is_shared() {
+        let path =
+            std::env::temp_dir().join(format!("sosistab3-replay-test-{}", rand::random::<u64>()));
+        let first = FileReplayCache::open(&path, 1024).unwrap();
+        // as if opened by another process
+        let second = FileReplayCache::open(&path, 1024).unwrap();
+
+        let hashes: Vec<[u8; 32]> = (0..500).map(|_| rand::random()).collect();
+        for hash in &hashes {
+            assert!(first.insert_if_new(hash));
+        }
+        for hash in &hashes {
+            assert!(!second.insert_if_new(hash));
+            assert!(!first.insert_if_new(hash));
+        }
+        assert!(second.insert_if_new(&rand::random()));
+
+        assert!(FileReplayCache::open(&path, 2048).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_replay_cache_probes_past_expired_slots() {
+        let path =
+            std::env::temp_dir().join(format!("sosistab3-replay-test-{}", rand::random::<u64>()));
+        let cache = FileReplayCache::open(&path, 1024).unwrap();
+
+        // hashes that all share a home slot fill up a probe chain
+        let chain: Vec<[u8; 32]> = (0..MAX_PROBE)
+            .map(|_| rand::random::<[u8; 32]>().tap_mut(|hash| hash[..8].fill(0)))
+            .collect();
+        for hash in &chain {
+            assert!(cache.insert_if_new(hash));
+        }
+
+        // the first one expires, which must not hide the ones stored past it
+        cache.map.lock().unwrap()[32..SLOT_LEN].copy_from_slice(&1u64.to_le_bytes());
+        for hash in &chain[1..] {
+            assert!(!cache.insert_if_new(hash));
+        }
+        assert!(cache.insert_if_new(&chain[0]));
+        assert!(!cache.insert_if_new(&chain[0]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn memory_replay_cache() {
+        let cache = MemoryReplayCache::default();
+        let hash = rand::random();
+        assert!(cache.insert_if_new(&hash));
+        assert!(!cache.insert_if_new(&hash));
+    }
+}
@@ -0,0 +1,143 @@
+/// Somewhere to put encrypted records, each of which gets encrypted in place in a contiguous region handed out by [RecordSink::alloc].
+pub(crate) trait RecordSink {
+    /// Appends `len` bytes to the output, returning them to be filled in. Their contents are unspecified.
+    fn alloc(&mut self, len: usize) -> &mut [u8];
+}
+
+impl RecordSink for Vec<u8> {
+    fn alloc(&mut self, len: usize) -> &mut [u8] {
+        let start = self.len();
+        self.resize(start + len, 0);
+        &mut self[start..]
+    }
+}
+
+/// A ring buffer of encrypted records waiting to go out on the lower pipe.
+///
+/// Records must stay contiguous to be encrypted in place, so one that doesn't fit before the end of the buffer starts over at the beginning, and the space it skipped is left unused until the data before it is consumed. Whatever is queued is always at most two slices, suitable for a vectored write.
+pub(crate) struct WriteRing {
+    buf: Vec<u8>,
+    // queued data starts here...
+    head: usize,
+    // ...and ends here, having wrapped around if `wrap` is set
+    tail: usize,
+    // if the queued data wraps around, the end of its first part
+    wrap: Option<usize>,
+}
+
+impl WriteRing {
+    /// Creates an empty ring, which grows past the given capacity if it has to.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buf: vec![0; capacity],
+            head: 0,
+            tail: 0,
+            wrap: None,
+        }
+    }
+
+    /// How many bytes are queued.
+    pub fn len(&self) -> usize {
+        match self.wrap {
+            None => self.tail - self.head,
+            Some(wrap) => wrap - self.head + self.tail,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The queued bytes, in order. The second slice is empty unless they wrap around.
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        match self.wrap {
+            None => (&self.buf[self.head..self.tail], &[]),
+            Some(wrap) => (&self.buf[self.head..wrap], &self.buf[..self.tail]),
+        }
+    }
+
+    /// Drops the first `n` queued bytes, once they have been written out.
+    pub fn consume(&mut self, n: usize) {
+        assert!(n <= self.len(), "consumed more than was queued");
+        match self.wrap {
+            Some(wrap) if self.head + n >= wrap => {
+                self.head = n - (wrap - self.head);
+                self.wrap = None;
+            }
+            _ => self.head += n,
+        }
+        if self.wrap.is_none() && self.head == self.tail {
+            // starting from the top keeps records from wrapping around as much
+            self.head = 0;
+            self.tail = 0;
+        }
+    }
+
+    /// Moves the queued data to the start of a new buffer, big enough for it plus `extra` more bytes.
+    fn grow(&mut self, extra: usize) {
+        let len = self.len();
+        let mut buf = vec![0; (self.buf.len() * 2).max(len + extra)];
+        let (first, second) = self.as_slices();
+        buf[..first.len()].copy_from_slice(first);
+        buf[first.len()..len].copy_from_slice(second);
+        self.buf = buf;
+        self.head = 0;
+        self.tail = len;
+        self.wrap = None;
+    }
+}
+
+impl RecordSink for WriteRing {
+    fn alloc(&mut self, len: usize) -> &mut [u8] {
+        let start = match self.wrap {
+            None if self.buf.len() - self.tail >= len => self.tail,
+            None if self.head >= len => {
+                self.wrap = Some(self.tail);
+                0
+            }
+            Some(_) if self.head - self.tail >= len => self.tail,
+            _ => {
+                self.grow(len);
+                self.tail
+            }
+        };
+        self.tail = start + len;
+        &mut self.buf[start..self.tail]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use rand::Rng;
+
+    use super::*;
+
+    #[test]
+    fn ring_matches_a_plain_queue() {
+        let mut rng = rand::thread_rng();
+        let mut ring = WriteRing::new(1000);
+        let mut model = VecDeque::new();
+        let mut counter = 0u8;
+        for _ in 0..100_000 {
+            if rng.gen_bool(0.5) {
+                let len = rng.gen_range(0..400);
+                let record = ring.alloc(len);
+                assert_eq!(record.len(), len);
+                for byte in record {
+                    *byte = counter;
+                    model.push_back(counter);
+                    counter = counter.wrapping_add(1);
+                }
+            } else {
+                let n = rng.gen_range(0..=ring.len());
+                ring.consume(n);
+                model.drain(..n);
+            }
+            let (first, second) = ring.as_slices();
+            assert_eq!(ring.len(), model.len());
+            assert!(first.iter().chain(second).eq(model.iter()));
+        }
+    }
+}
@@ -1,21 +1,53 @@
-use std::io::Write;
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use arrayref::array_ref;
 use blake3::derive_key;
 use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, Key, KeyInit};
-use rand::Rng;
 use smallvec::{SmallVec, ToSmallVec};
 
-use crate::ObfsParams;
+use crate::{ring::RecordSink, ObfsParams};
 
+/// Every record carries an encrypted length, plus two tags.
+pub(crate) const RECORD_OVERHEAD: usize = 4 + 16 + 16;
+
+/// Control records are padding records whose body is this prefix followed by a single byte saying what they are. Ordinary padding is all zeros, and peers that predate control records discard them like any other padding.
+const CONTROL_PREFIX: [u8; 8] = *b"\xffsos3ctl";
+
+/// Announces that we understand rekeying, so the other side may start doing it.
+const CONTROL_HELLO: u8 = b'h';
+
+/// Says that every record after this one is encrypted with the next key in the ratchet.
+const CONTROL_REKEY: u8 = b'r';
+
+/// We move to a new sending key after this many records...
+const REKEY_AFTER_RECORDS: u64 = 1 << 20;
+
+/// ...or after this long, whichever comes first.
+const REKEY_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Clone)]
 pub struct State {
     shared_secret: Vec<u8>,
+    send_key: [u8; 32],
     send_aead: ChaCha20Poly1305,
     send_nonce: u64,
-    send_buf: Vec<u8>,
+    recv_key: [u8; 32],
     recv_aead: ChaCha20Poly1305,
     recv_nonce: u64,
 
+    // we never rekey until the other side tells us it can follow along. shared between the copies of the state used for sending and receiving
+    peer_rekeys: Arc<AtomicBool>,
+    hello_sent: bool,
+    last_rekey: Instant,
+    rekey_after_records: u64,
+
     obfs_params: ObfsParams,
 }
 
@@ -29,9 +61,7 @@ impl State {
             ("up", "dn")
         };
         let send_key = derive_key(send_key_label, ss);
-        let send_key = Key::from_slice(&send_key);
         let recv_key = derive_key(recv_key_label, ss);
-        let recv_key = Key::from_slice(&recv_key);
 
         tracing::debug!(
             send_key = hex::encode(send_key),
@@ -39,16 +69,21 @@ impl State {
             "created a new state"
         );
 
-        let send_aead = ChaCha20Poly1305::new(send_key);
-        let recv_aead = ChaCha20Poly1305::new(recv_key);
+        let send_aead = ChaCha20Poly1305::new(Key::from_slice(&send_key));
+        let recv_aead = ChaCha20Poly1305::new(Key::from_slice(&recv_key));
 
         State {
             shared_secret: ss.to_vec(),
+            send_key,
             send_aead,
             send_nonce: 0,
-            send_buf: vec![],
+            recv_key,
             recv_aead,
             recv_nonce: 0,
+            peer_rekeys: Default::default(),
+            hello_sent: false,
+            last_rekey: Instant::now(),
+            rekey_after_records: REKEY_AFTER_RECORDS,
             obfs_params,
         }
     }
@@ -57,6 +92,10 @@ impl State {
         &self.shared_secret
     }
 
+    pub fn obfs_params(&self) -> ObfsParams {
+        self.obfs_params
+    }
+
     fn send_nonce(&mut self) -> [u8; 12] {
         let mut nonce = [0u8; 12];
         nonce[..8].copy_from_slice(&self.send_nonce.to_le_bytes());
@@ -65,68 +104,99 @@ impl State {
     }
 
     /// Encrypts a hunk of data.
-    pub fn encrypt(&mut self, bts: &[u8], output: &mut Vec<u8>) {
-        let orig_len = self.encrypt_inner(bts, output, false);
-        let overhead = orig_len - bts.len();
+    pub fn encrypt(&mut self, bts: &[u8], output: &mut impl RecordSink) {
+        let orig_len =
+            self.encrypt_inner(bts.len(), false, output, |body| body.copy_from_slice(bts));
+        self.pad_after(orig_len, output);
+        if self.peer_rekeys.load(Ordering::Relaxed)
+            && (self.send_nonce / 2 >= self.rekey_after_records
+                || self.last_rekey.elapsed() >= REKEY_INTERVAL)
+        {
+            // the announcement itself still goes out under the old key
+            let record_len = self.encrypt_control(CONTROL_REKEY, output);
+            self.send_key = ratchet(&self.send_key);
+            self.send_aead = ChaCha20Poly1305::new(Key::from_slice(&self.send_key));
+            self.send_nonce = 0;
+            self.last_rekey = Instant::now();
+            // the other side switches keys as soon as it sees the announcement, so its padding must use the new key
+            self.pad_after(record_len, output);
+            tracing::debug!("rekeyed the sending direction");
+        }
+    }
+
+    /// Tells the other side that we understand rekeying. Only does anything the first time it's called, which should be before anything else is encrypted.
+    pub fn encrypt_hello(&mut self, output: &mut impl RecordSink) {
+        if !self.hello_sent {
+            let record_len = self.encrypt_control(CONTROL_HELLO, output);
+            self.pad_after(record_len, output);
+            self.hello_sent = true;
+        }
+    }
+
+    /// Encrypts a control record, returning its length. Callers pad it like any other record, so that it doesn't stand out by its fixed length.
+    fn encrypt_control(&mut self, kind: u8, output: &mut impl RecordSink) -> usize {
+        self.encrypt_inner(CONTROL_PREFIX.len() + 1, true, output, |body| {
+            body[..CONTROL_PREFIX.len()].copy_from_slice(&CONTROL_PREFIX);
+            body[CONTROL_PREFIX.len()] = kind;
+        })
+    }
+
+    /// Follows a record of the given on-the-wire length with whatever padding the profile calls for.
+    fn pad_after(&mut self, record_len: usize, output: &mut impl RecordSink) {
         if self.obfs_params.obfs_lengths {
-            // only add padding if the length is shorter than 2000 bytes
-            if orig_len < 2000 {
-                let desired_len =
-                    (orig_len + overhead).next_power_of_two() + rand::thread_rng().gen_range(0..10);
-                let padding_inner_len = desired_len - orig_len - overhead;
-                let lala = [0u8; 2000];
-                let padding_len = self.encrypt_inner(&lala[..padding_inner_len], output, true);
-                assert_eq!(orig_len + padding_len, desired_len)
+            let padding_len = self.obfs_params.padding.padding_len(record_len);
+            if padding_len > 0 {
+                self.encrypt_padding(padding_len - RECORD_OVERHEAD, output);
             }
         }
     }
 
-    fn encrypt_inner(&mut self, bts: &[u8], output: &mut Vec<u8>, is_padding: bool) -> usize {
-        let mut tally = 0;
+    /// Encrypts a padding record with a body of the given length, which the other side discards.
+    pub fn encrypt_padding(&mut self, len: usize, output: &mut impl RecordSink) {
+        self.encrypt_inner(len, true, output, |body| body.fill(0));
+    }
 
+    /// Encrypts one record in place, right where it goes in the output, with a body of `body_len` bytes written by `fill_body`. Returns the length of the record.
+    fn encrypt_inner(
+        &mut self,
+        body_len: usize,
+        is_padding: bool,
+        output: &mut impl RecordSink,
+        fill_body: impl FnOnce(&mut [u8]),
+    ) -> usize {
         let length = if is_padding {
-            -(bts.len() as i32)
+            -(body_len as i32)
         } else {
-            bts.len() as i32
+            body_len as i32
         };
-        let mut length = length.to_le_bytes();
+        let record = output.alloc(RECORD_OVERHEAD + body_len);
+        let (length_part, rest) = record.split_at_mut(4 + 16);
+        let (body, tag_body_out) = rest.split_at_mut(body_len);
 
-        // Pad the nonce to 96 bits (12 bytes)
+        // Encrypt the length, followed by its tag
+        length_part[..4].copy_from_slice(&length.to_le_bytes());
         let nonce = self.send_nonce();
-
-        // Encrypt the length with send_aead
         let tag_length = self
             .send_aead
-            .encrypt_in_place_detached(&nonce.into(), &[], &mut length)
+            .encrypt_in_place_detached(&nonce.into(), &[], &mut length_part[..4])
             .expect("encryption failure!");
+        length_part[4..].copy_from_slice(&tag_length);
 
-        // Append the encrypted length and its tag to the output
-        tally += length.len() + tag_length.len();
-        output.extend_from_slice(&length);
-        output.extend_from_slice(tag_length.as_slice());
-
-        // Prepare the next nonce for the body encryption
+        // Encrypt the body with the next nonce, followed by its tag
+        fill_body(body);
         let nonce = self.send_nonce();
-
-        // Encrypt the body with send_aead
-        self.send_buf.clear();
-        self.send_buf.extend_from_slice(bts);
         let tag_body = self
             .send_aead
-            .encrypt_in_place_detached(&nonce.into(), &[], &mut self.send_buf)
+            .encrypt_in_place_detached(&nonce.into(), &[], body)
             .expect("encryption failure!");
         tracing::trace!(
-            body_len = self.send_buf.len(),
+            body_len,
             nonce = hex::encode(nonce),
             tag = hex::encode(tag_body),
             "encrypted a body"
         );
-
-        // Append the encrypted body and its tag to the output
-        tally += self.send_buf.len() + tag_body.len();
-        output.extend_from_slice(&self.send_buf);
-        output.extend_from_slice(tag_body.as_slice());
-        tally
+        tag_body_out.copy_from_slice(&tag_body);
+        RECORD_OVERHEAD + body_len
     }
 
     fn recv_nonce(&self, offset: u64) -> [u8; 12] {
@@ -207,13 +277,36 @@ impl State {
             output.write_all(&enc_body).unwrap();
         }
         self.recv_nonce += 2;
+        if length < 0 && enc_body.len() == CONTROL_PREFIX.len() + 1 {
+            if let Some(&kind) = enc_body
+                .strip_prefix(&CONTROL_PREFIX)
+                .and_then(|k| k.first())
+            {
+                match kind {
+                    CONTROL_HELLO => self.peer_rekeys.store(true, Ordering::Relaxed),
+                    CONTROL_REKEY => {
+                        self.recv_key = ratchet(&self.recv_key);
+                        self.recv_aead = ChaCha20Poly1305::new(Key::from_slice(&self.recv_key));
+                        self.recv_nonce = 0;
+                        tracing::debug!("rekeyed the receiving direction");
+                    }
+                    _ => {}
+                }
+            }
+        }
         Ok(enc_length.len() + tag_length.len() + tag_body.len() + enc_body.len())
     }
 }
 
+/// Derives the next key in the ratchet. Old keys cannot be recovered from new ones, so a key leaked late in a long session does not expose what came before it.
+fn ratchet(key: &[u8; 32]) -> [u8; 32] {
+    derive_key("sosistab3 rekey", key)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::PaddingProfile;
     use rand::rngs::OsRng;
     use x25519_dalek::EphemeralSecret;
 
@@ -228,6 +321,8 @@ mod tests {
             ObfsParams {
                 obfs_lengths: true,
                 obfs_timing: true,
+                obfs_chaff: true,
+                ..Default::default()
             },
         );
 
@@ -241,6 +336,8 @@ mod tests {
             ObfsParams {
                 obfs_lengths: true,
                 obfs_timing: true,
+                obfs_chaff: true,
+                ..Default::default()
             },
         );
         let mut decrypted_data = vec![];
@@ -278,4 +375,91 @@ mod tests {
         assert_eq!(data1, decrypted_data1.as_slice());
         assert_eq!(data2, decrypted_data2.as_slice());
     }
+
+    #[test]
+    fn test_state_fixed_cells() {
+        let shared_secret: [u8; 32] = rand::random();
+        let params = ObfsParams {
+            obfs_lengths: true,
+            padding: PaddingProfile::FixedCells { size: 256 },
+            ..Default::default()
+        };
+        let mut sender = State::new(&shared_secret, false, params);
+        let mut receiver = State::new(&shared_secret, true, params);
+        sender.rekey_after_records = 3;
+
+        // the hello is padded to a whole cell too
+        let mut hello = vec![];
+        sender.encrypt_hello(&mut hello);
+        assert_eq!(hello.len() % 256, 0);
+        let mut decrypted = vec![];
+        let mut offset = 0;
+        while offset < hello.len() {
+            offset += receiver.decrypt(&hello[offset..], &mut decrypted).unwrap();
+        }
+        assert!(decrypted.is_empty());
+
+        // pretend the receiver said hello back, so the sender rekeys every few records
+        receiver.encrypt_hello(&mut vec![]);
+        sender.peer_rekeys.store(true, Ordering::Relaxed);
+        for len in [0, 1, 200, 219, 220, 1000] {
+            let data = vec![7u8; len];
+            let mut encrypted = vec![];
+            sender.encrypt(&data, &mut encrypted);
+            assert_eq!(encrypted.len() % 256, 0);
+
+            let mut decrypted = vec![];
+            let mut offset = 0;
+            while offset < encrypted.len() {
+                offset += receiver
+                    .decrypt(&encrypted[offset..], &mut decrypted)
+                    .unwrap();
+            }
+            assert_eq!(decrypted, data);
+        }
+        assert_ne!(sender.send_key, derive_key("up", &shared_secret));
+        assert_eq!(receiver.recv_key, sender.send_key);
+    }
+
+    #[test]
+    fn test_state_rekeying() {
+        let shared_secret: [u8; 32] = rand::random();
+        let mut client = State::new(&shared_secret, false, ObfsParams::default());
+        let mut server = State::new(&shared_secret, true, ObfsParams::default());
+        client.rekey_after_records = 10;
+        server.rekey_after_records = 10;
+
+        // until the server says hello, the client must not rekey, since the server might predate rekeying
+        let mut upstream = vec![];
+        client.encrypt_hello(&mut upstream);
+        for _ in 0..50 {
+            client.encrypt(b"hello", &mut upstream);
+        }
+        assert_eq!(client.send_key, derive_key("up", &shared_secret));
+
+        let mut received = vec![];
+        let mut offset = 0;
+        while offset < upstream.len() {
+            offset += server.decrypt(&upstream[offset..], &mut received).unwrap();
+        }
+        assert_eq!(received, b"hello".repeat(50));
+
+        // the server heard the client's hello, so it rekeys, and the client follows along
+        let mut downstream = vec![];
+        server.encrypt_hello(&mut downstream);
+        for i in 0..50u32 {
+            server.encrypt(&i.to_be_bytes(), &mut downstream);
+        }
+        assert_ne!(server.send_key, derive_key("dn", &shared_secret));
+        let mut received = vec![];
+        let mut offset = 0;
+        while offset < downstream.len() {
+            offset += client
+                .decrypt(&downstream[offset..], &mut received)
+                .unwrap();
+        }
+        let expected: Vec<u8> = (0..50u32).flat_map(|i| i.to_be_bytes()).collect();
+        assert_eq!(received, expected);
+        assert_eq!(client.recv_key, server.send_key);
+    }
 }
@@ -0,0 +1,176 @@
+use std::time::{Duration, Instant};
+
+use async_io::Timer;
+use bipe::BipeReader;
+use futures_lite::FutureExt;
+use futures_util::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use rand::Rng;
+
+use crate::state::{State, RECORD_OVERHEAD};
+
+/// The longest a write is held back, waiting to be coalesced with later writes.
+const MAX_HOLD: Duration = Duration::from_millis(10);
+
+/// Once this many bytes are waiting, they are released immediately, so that bulk transfers are not slowed down.
+const MAX_BATCH: usize = 16384;
+
+/// For every byte of real data sent, this many bytes of chaff may be sent later.
+const CHAFF_RATIO: f64 = 0.1;
+
+/// Unspent chaff allowance never accumulates past this, so a long burst does not buy a long stream of chaff.
+const MAX_CHAFF_BUDGET: usize = 32768;
+
+/// How long the connection must stay idle before each chaff record, in milliseconds.
+const CHAFF_GAP_MS: std::ops::Range<u64> = 20..300;
+
+/// The on-the-wire size of a chaff record, overhead included.
+const CHAFF_LEN: std::ops::RangeInclusive<usize> = 64..=1200;
+
+/// Takes plaintext written to a [crate::SosistabPipe], and writes it out as records on a randomized schedule.
+///
+/// The first write of a batch starts a timer of random length, up to [MAX_HOLD], and everything written before it fires goes into the same record. When `chaff` is set, idle periods are then filled with padding records, paid for out of a budget that real traffic replenishes. This bounds the chaff to [CHAFF_RATIO] of the real bytes sent, and means that a connection that goes quiet for good stops sending chaff soon afterwards.
+pub(crate) async fn shaper_loop(
+    mut plain: BipeReader,
+    mut state: State,
+    mut lower: impl AsyncWrite + Unpin,
+    chaff: bool,
+) -> std::io::Result<()> {
+    let mut buf = vec![0u8; MAX_BATCH];
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    let mut output = Vec::new();
+    let mut chaff_budget = 0usize;
+    loop {
+        // wait for the start of the next batch, sending chaff in the meantime if we can afford it
+        let n = loop {
+            if !chaff || chaff_budget < *CHAFF_LEN.start() {
+                break plain.read(&mut buf).await?;
+            }
+            let gap = Duration::from_millis(rand::thread_rng().gen_range(CHAFF_GAP_MS));
+            if let Some(n) = read_before(&mut plain, &mut buf, Instant::now() + gap).await? {
+                break n;
+            }
+            let len = rand::thread_rng().gen_range(CHAFF_LEN).min(chaff_budget);
+            chaff_budget -= len;
+            output.clear();
+            state.encrypt_padding(len - RECORD_OVERHEAD, &mut output);
+            tracing::trace!(len, chaff_budget, "sending chaff");
+            lower.write_all(&output).await?;
+            lower.flush().await?;
+        };
+        if n == 0 {
+            return lower.close().await;
+        }
+        batch.extend_from_slice(&buf[..n]);
+
+        // collect everything else that arrives before the deadline
+        let deadline = Instant::now() + MAX_HOLD.mul_f64(rand::thread_rng().gen());
+        let mut closed = false;
+        while batch.len() < MAX_BATCH {
+            let room = MAX_BATCH - batch.len();
+            match read_before(&mut plain, &mut buf[..room], deadline).await? {
+                Some(0) => {
+                    closed = true;
+                    break;
+                }
+                Some(n) => batch.extend_from_slice(&buf[..n]),
+                None => break,
+            }
+        }
+        output.clear();
+        state.encrypt_hello(&mut output);
+        state.encrypt(&batch, &mut output);
+        tracing::trace!(
+            batch_len = batch.len(),
+            output_len = output.len(),
+            "releasing a batch"
+        );
+        lower.write_all(&output).await?;
+        lower.flush().await?;
+        chaff_budget =
+            (chaff_budget + (batch.len() as f64 * CHAFF_RATIO) as usize).min(MAX_CHAFF_BUDGET);
+        batch.clear();
+        if closed {
+            return lower.close().await;
+        }
+    }
+}
+
+/// Reads from the plaintext side, giving up with `None` once the deadline passes.
+async fn read_before(
+    plain: &mut BipeReader,
+    buf: &mut [u8],
+    deadline: Instant,
+) -> std::io::Result<Option<usize>> {
+    async { plain.read(buf).await.map(Some) }
+        .or(async {
+            Timer::at(deadline).await;
+            Ok(None)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ObfsParams;
+
+    use super::*;
+
+    #[test]
+    fn shaping_coalesces_and_bounds_chaff() {
+        let params = ObfsParams {
+            obfs_lengths: false,
+            obfs_timing: true,
+            obfs_chaff: true,
+            ..Default::default()
+        };
+        let shared_secret: [u8; 32] = rand::random();
+        let (mut plain_write, plain_read) = bipe::bipe(1 << 20);
+        let (lower_write, mut lower_read) = bipe::bipe(1 << 20);
+        let shaper = shaper_loop(
+            plain_read,
+            State::new(&shared_secret, false, params),
+            lower_write,
+            true,
+        );
+
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let writer = async {
+            for chunk in data.chunks(100) {
+                plain_write.write_all(chunk).await.unwrap();
+            }
+            // long enough to be guaranteed some chaff
+            Timer::after(Duration::from_millis(600)).await;
+            plain_write.close().await.unwrap();
+        };
+        let reader = async {
+            let mut wire = vec![];
+            lower_read.read_to_end(&mut wire).await.unwrap();
+            wire
+        };
+        let ((res, _), wire) = async_io::block_on(futures_lite::future::zip(
+            futures_lite::future::zip(shaper, writer),
+            reader,
+        ));
+        res.unwrap();
+
+        let mut state = State::new(&shared_secret, true, params);
+        let mut received = vec![];
+        let mut offset = 0;
+        let mut data_records = 0;
+        let mut chaff_bytes = 0;
+        while offset < wire.len() {
+            let before = received.len();
+            let n = state.decrypt(&wire[offset..], &mut received).unwrap();
+            if received.len() == before {
+                chaff_bytes += n;
+            } else {
+                data_records += 1;
+            }
+            offset += n;
+        }
+        assert_eq!(received, data);
+        assert!(data_records < data.len() / 100);
+        assert!(chaff_bytes > 0);
+        assert!(chaff_bytes as f64 <= data.len() as f64 * CHAFF_RATIO);
+    }
+}
@@ -0,0 +1,271 @@
+use std::pin::Pin;
+
+use async_task::Task;
+use async_trait::async_trait;
+use async_tungstenite::{tungstenite::Message, WebSocketStream};
+use bipe::{BipeReader, BipeWriter};
+use bytes::Bytes;
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, SinkExt, StreamExt};
+use pin_project::pin_project;
+use sillad::{dialer::Dialer, listener::Listener, Pipe};
+
+/// WsPipe carries a byte stream inside the binary frames of a WebSocket connection.
+#[pin_project]
+pub struct WsPipe {
+    #[pin]
+    read_incoming: BipeReader,
+    _read_task: Task<()>,
+    #[pin]
+    write_outgoing: BipeWriter,
+    _write_task: Task<()>,
+
+    remote_addr: Option<String>,
+}
+
+impl WsPipe {
+    fn new<P: Pipe>(ws: WebSocketStream<P>, remote_addr: Option<String>) -> Self {
+        let (mut ws_write, mut ws_read) = ws.split();
+        let (mut write_incoming, read_incoming) = bipe::bipe(32768);
+        let (write_outgoing, mut read_outgoing) = bipe::bipe(32768);
+
+        let _read_task = smolscale::spawn(async move {
+            let fallible = async {
+                while let Some(msg) = ws_read.next().await {
+                    match msg.map_err(to_io_error)? {
+                        Message::Binary(bts) => write_incoming.write_all(&bts).await?,
+                        Message::Close(_) => break,
+                        // pings are answered by tungstenite itself, and we never send text
+                        _ => continue,
+                    }
+                }
+                std::io::Result::Ok(())
+            };
+            if let Err(err) = fallible.await {
+                tracing::debug!(err = debug(err), "websocket read side stopped");
+            }
+        });
+
+        let _write_task = smolscale::spawn(async move {
+            let fallible = async {
+                let mut buf = [0; 8192];
+                loop {
+                    let n = read_outgoing.read(&mut buf).await?;
+                    if n == 0 {
+                        ws_write.close().await.map_err(to_io_error)?;
+                        return std::io::Result::Ok(());
+                    }
+                    ws_write
+                        .send(Message::Binary(Bytes::copy_from_slice(&buf[..n])))
+                        .await
+                        .map_err(to_io_error)?;
+                }
+            };
+            if let Err(err) = fallible.await {
+                tracing::debug!(err = debug(err), "websocket write side stopped");
+            }
+        });
+
+        Self {
+            read_incoming,
+            _read_task,
+            write_outgoing,
+            _write_task,
+
+            remote_addr,
+        }
+    }
+}
+
+impl AsyncRead for WsPipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().read_incoming.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for WsPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().write_outgoing.poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().write_outgoing.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().write_outgoing.poll_close(cx)
+    }
+}
+
+impl Pipe for WsPipe {
+    fn protocol(&self) -> &str {
+        "websocket"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.remote_addr.as_deref()
+    }
+}
+
+/// WsDialer wraps a Dialer to establish a WebSocket connection to the given URL.
+///
+/// The URL is only used to fill in the HTTP upgrade request. The actual connection is made by the inner dialer, so a `wss://` URL should be paired with a TLS dialer underneath.
+pub struct WsDialer<D: Dialer> {
+    inner: D,
+    url: String,
+}
+
+impl<D: Dialer> WsDialer<D> {
+    pub fn new(inner: D, url: String) -> Self {
+        Self { inner, url }
+    }
+}
+
+#[async_trait]
+impl<D: Dialer> Dialer for WsDialer<D> {
+    type P = WsPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let lower = self.inner.dial().await?;
+        let remote_addr = lower.remote_addr().map(|s| s.to_string());
+        let (ws, _) = async_tungstenite::client_async(self.url.as_str(), lower)
+            .await
+            .inspect_err(|e| {
+                tracing::warn!(
+                    err = display(e),
+                    addr = debug(&remote_addr),
+                    url = self.url,
+                    "WebSocket handshake failed"
+                )
+            })
+            .map_err(to_io_error)?;
+        Ok(WsPipe::new(ws, remote_addr))
+    }
+}
+
+/// WsListener wraps a Listener to accept WebSocket connections on any path.
+pub struct WsListener {
+    incoming: tachyonix::Receiver<WsPipe>,
+    _accept_task: Task<()>,
+}
+
+impl WsListener {
+    pub fn new(mut inner: impl Listener) -> Self {
+        let (tx, rx) = tachyonix::channel(1);
+
+        let accept_task = smolscale::spawn(async move {
+            loop {
+                let raw_conn = match inner.accept().await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        tracing::warn!(err = debug(err), "underlying listener failed");
+                        break;
+                    }
+                };
+                // do the upgrade handshake in the background, so that slow clients do not block others
+                let tx = tx.clone();
+                smolscale::spawn(async move {
+                    let remote_addr = raw_conn.remote_addr().map(|s| s.to_string());
+                    match async_tungstenite::accept_async(raw_conn).await {
+                        Ok(ws) => {
+                            let _ = tx.send(WsPipe::new(ws, remote_addr)).await;
+                        }
+                        Err(err) => {
+                            tracing::debug!(
+                                err = display(err),
+                                addr = debug(&remote_addr),
+                                "WebSocket handshake failed"
+                            );
+                        }
+                    }
+                })
+                .detach();
+            }
+        });
+
+        Self {
+            incoming: rx,
+            _accept_task: accept_task,
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for WsListener {
+    type P = WsPipe;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        self.incoming.recv().await.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "underlying listener failure",
+            )
+        })
+    }
+}
+
+fn to_io_error(err: async_tungstenite::tungstenite::Error) -> std::io::Error {
+    match err {
+        async_tungstenite::tungstenite::Error::Io(err) => err,
+        err => std::io::Error::new(std::io::ErrorKind::ConnectionAborted, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sillad::tcp::{TcpDialer, TcpListener};
+
+    #[test]
+    fn websocket_echo() -> std::io::Result<()> {
+        async_io::block_on(async {
+            let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).await?;
+            let local_addr = tcp_listener.local_addr().await;
+            let mut listener = WsListener::new(tcp_listener);
+
+            let server = smolscale::spawn(async move {
+                let mut conn = listener.accept().await?;
+                let mut buf = [0u8; 1024];
+                loop {
+                    let n = conn.read(&mut buf).await?;
+                    if n == 0 {
+                        return std::io::Result::Ok(());
+                    }
+                    conn.write_all(&buf[..n]).await?;
+                }
+            });
+
+            let dialer = WsDialer::new(
+                TcpDialer {
+                    dest_addr: local_addr,
+                },
+                format!("ws://{local_addr}/"),
+            );
+            let mut client = dialer.dial().await?;
+            assert_eq!(client.protocol(), "websocket");
+
+            let message = vec![42u8; 100_000];
+            client.write_all(&message).await?;
+            let mut echo = vec![0u8; message.len()];
+            client.read_exact(&mut echo).await?;
+            assert_eq!(echo, message);
+
+            client.close().await?;
+            server.await
+        })
+    }
+}
@@ -1,8 +1,15 @@
-use std::{pin::Pin, sync::Arc};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use crate::{EitherPipe, Pipe};
 use async_trait::async_trait;
 use futures_lite::{Future, FutureExt};
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use rand::Rng;
 use smol_timeout2::TimeoutExt;
 
 #[async_trait]
@@ -51,6 +58,22 @@ pub trait DialerExt: Dialer {
             delay: Box::new(duration),
         }
     }
+
+    fn retry(self, policy: RetryPolicy) -> RetryDialer<Self> {
+        RetryDialer {
+            dialer: self,
+            policy,
+        }
+    }
+
+    fn circuit_breaker(self, threshold: usize, cooldown: Duration) -> CircuitBreakerDialer<Self> {
+        CircuitBreakerDialer {
+            dialer: self,
+            threshold,
+            cooldown,
+            state: Default::default(),
+        }
+    }
 }
 
 impl<T: Dialer> DialerExt for T {}
@@ -122,6 +145,198 @@ where
     }
 }
 
+/// MultiRaceDialer races any number of dialers, each starting after its own delay, and learns which ones tend to win.
+///
+/// Every win adds one to the winning branch's score, every failure halves it, and scores decay exponentially with the half-life of the [RaceMemory]. On every dial, the branch with the highest score starts immediately, skipping its delay, while all the other branches additionally wait for as long as that favorite took to win last time, unless the favorite fails before then.
+pub struct MultiRaceDialer {
+    branches: Vec<(String, DynDialer, Duration)>,
+    memory: RaceMemory,
+}
+
+impl MultiRaceDialer {
+    /// Creates a race between the given dialers and their start delays, with fresh statistics.
+    pub fn new(branches: impl IntoIterator<Item = (DynDialer, Duration)>) -> Self {
+        Self::new_keyed(
+            branches
+                .into_iter()
+                .enumerate()
+                .map(|(idx, (dialer, delay))| (idx.to_string(), dialer, delay)),
+            RaceMemory::default(),
+        )
+    }
+
+    /// Creates a race where every branch is identified by a key, with statistics kept in the given memory. Sharing the memory between dialers lets what was learned survive rebuilding the dialer.
+    pub fn new_keyed(
+        branches: impl IntoIterator<Item = (String, DynDialer, Duration)>,
+        memory: RaceMemory,
+    ) -> Self {
+        Self {
+            branches: branches.into_iter().collect(),
+            memory,
+        }
+    }
+
+    /// Returns the current statistics of every branch, in the order the branches were given.
+    pub fn stats(&self) -> Vec<RaceStats> {
+        self.branches
+            .iter()
+            .map(|(key, _, _)| self.memory.stats(key))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Dialer for MultiRaceDialer {
+    type P = Box<dyn Pipe>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let stats = self.stats();
+        let favorite = stats
+            .iter()
+            .enumerate()
+            .filter(|(_, stats)| stats.score > 0.0)
+            .max_by(|a, b| a.1.score.total_cmp(&b.1.score))
+            .map(|(idx, _)| idx);
+        let head_start = favorite
+            .and_then(|idx| stats[idx].last_latency)
+            .unwrap_or_default();
+
+        // the favorite holds the only sender, so the channel closes as soon as the favorite gives up
+        let (favorite_send, favorite_failed) = async_channel::bounded::<()>(1);
+        let mut favorite_send = Some(favorite_send);
+        let race_start = Instant::now();
+        let mut racers: FuturesUnordered<_> = self
+            .branches
+            .iter()
+            .enumerate()
+            .map(|(idx, (key, dialer, delay))| {
+                let favorite_send = if Some(idx) == favorite {
+                    favorite_send.take()
+                } else {
+                    None
+                };
+                let favorite_failed = favorite_failed.clone();
+                async move {
+                    if favorite_send.is_none() {
+                        async_io::Timer::at(race_start + *delay + head_start)
+                            .or(async {
+                                let _ = favorite_failed.recv().await;
+                                async_io::Timer::at(race_start + *delay).await
+                            })
+                            .await;
+                    }
+                    let start = Instant::now();
+                    let res = dialer.dial().await;
+                    drop(favorite_send);
+                    (key, start.elapsed(), res)
+                }
+            })
+            .collect();
+        drop(favorite_send);
+
+        let mut last_err = std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no dialers to race between",
+        );
+        while let Some((key, latency, res)) = racers.next().await {
+            match res {
+                Ok(pipe) => {
+                    tracing::debug!(key, latency = debug(latency), "race branch won");
+                    self.memory.record_win(key, latency);
+                    return Ok(pipe);
+                }
+                Err(err) => {
+                    tracing::debug!(key, err = debug(&err), "race branch failed");
+                    self.memory.record_failure(key);
+                    last_err = err
+                }
+            }
+        }
+        Err(last_err)
+    }
+}
+
+/// A shared record of which branches of a [MultiRaceDialer] recently won.
+#[derive(Clone)]
+pub struct RaceMemory {
+    inner: Arc<Mutex<HashMap<String, (RaceStats, Instant)>>>,
+    half_life: Duration,
+}
+
+impl Default for RaceMemory {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(600))
+    }
+}
+
+impl RaceMemory {
+    /// Creates a new, empty memory in which scores halve every `half_life`.
+    pub fn new(half_life: Duration) -> Self {
+        Self {
+            inner: Default::default(),
+            half_life,
+        }
+    }
+
+    /// Gets the statistics of a particular branch, with the score decayed to the present.
+    pub fn stats(&self, key: &str) -> RaceStats {
+        let inner = self.inner.lock().unwrap();
+        match inner.get(key) {
+            Some((stats, updated)) => RaceStats {
+                score: stats.score * self.decay_since(*updated),
+                ..stats.clone()
+            },
+            None => RaceStats::default(),
+        }
+    }
+
+    fn record_win(&self, key: &str, latency: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        let decay = inner
+            .get(key)
+            .map(|(_, updated)| self.decay_since(*updated))
+            .unwrap_or_default();
+        let (stats, updated) = inner
+            .entry(key.to_string())
+            .or_insert_with(|| (RaceStats::default(), Instant::now()));
+        stats.score = stats.score * decay + 1.0;
+        stats.wins += 1;
+        stats.last_latency = Some(latency);
+        *updated = Instant::now();
+    }
+
+    fn record_failure(&self, key: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let decay = inner
+            .get(key)
+            .map(|(_, updated)| self.decay_since(*updated))
+            .unwrap_or_default();
+        let (stats, updated) = inner
+            .entry(key.to_string())
+            .or_insert_with(|| (RaceStats::default(), Instant::now()));
+        stats.score = stats.score * decay * 0.5;
+        stats.failures += 1;
+        *updated = Instant::now();
+    }
+
+    fn decay_since(&self, updated: Instant) -> f64 {
+        0.5f64.powf(updated.elapsed().as_secs_f64() / self.half_life.as_secs_f64())
+    }
+}
+
+/// Statistics about one branch of a [MultiRaceDialer].
+#[derive(Clone, Debug, Default)]
+pub struct RaceStats {
+    /// Exponentially decaying count of recent wins.
+    pub score: f64,
+    /// Total number of wins.
+    pub wins: u64,
+    /// Total number of failed dials, not counting branches that were cut off by another branch winning.
+    pub failures: u64,
+    /// How long the branch took to connect the last time it won.
+    pub last_latency: Option<Duration>,
+}
+
 /// FailingDialer is a dialer that always fails and never returns anything.
 pub struct FailingDialer;
 
@@ -204,3 +419,361 @@ impl<D: Dialer> Dialer for DynDelayDialer<D> {
         self.dialer.dial().await
     }
 }
+
+/// How a [RetryDialer] spaces out its attempts: exponential backoff with random jitter.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one. `None` retries forever.
+    pub max_attempts: Option<usize>,
+    /// The delay before the first retry.
+    pub initial_delay: Duration,
+    /// The cap on the delay between attempts.
+    pub max_delay: Duration,
+    /// The factor by which the delay grows after every failure.
+    pub multiplier: f64,
+    /// The fraction of every delay, between 0 and 1, that is randomly taken off.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: Some(5),
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns how long to wait after the given number of consecutive failures, starting from 1.
+    pub fn backoff(&self, failures: usize) -> Duration {
+        let exponent = failures.saturating_sub(1).min(i32::MAX as usize) as i32;
+        let delay = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+        let jitter = rand::thread_rng().gen_range(0.0..=self.jitter.clamp(0.0, 1.0));
+        Duration::from_secs_f64(delay * (1.0 - jitter))
+    }
+}
+
+/// RetryDialer retries its inner dialer according to a [RetryPolicy], returning the last error if every attempt fails.
+pub struct RetryDialer<D: Dialer> {
+    dialer: D,
+    policy: RetryPolicy,
+}
+
+#[async_trait]
+impl<D: Dialer> Dialer for RetryDialer<D> {
+    type P = D::P;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let mut failures = 0;
+        loop {
+            match self.dialer.dial().await {
+                Ok(pipe) => return Ok(pipe),
+                Err(err) => {
+                    failures += 1;
+                    if self
+                        .policy
+                        .max_attempts
+                        .is_some_and(|max_attempts| failures >= max_attempts)
+                    {
+                        return Err(err);
+                    }
+                    let backoff = self.policy.backoff(failures);
+                    tracing::debug!(
+                        err = debug(err),
+                        failures,
+                        backoff = debug(backoff),
+                        "dial failed, retrying"
+                    );
+                    async_io::Timer::after(backoff).await;
+                }
+            }
+        }
+    }
+}
+
+/// CircuitBreakerDialer fails fast for a cooldown period after its inner dialer fails too many times in a row, instead of hammering a dead destination.
+///
+/// Once the cooldown is over, a single probe dial goes through while other dials keep failing fast. If the probe fails, the circuit reopens, while if it succeeds, the circuit closes.
+pub struct CircuitBreakerDialer<D: Dialer> {
+    dialer: D,
+    threshold: usize,
+    cooldown: Duration,
+    state: Mutex<CircuitState>,
+}
+
+#[derive(Default)]
+struct CircuitState {
+    consecutive_failures: usize,
+    open_until: Option<Instant>,
+    probing: bool,
+}
+
+/// Marks the half-open probe as finished when dropped, even if the dial was cancelled.
+struct ProbeGuard<'a>(Option<&'a Mutex<CircuitState>>);
+
+impl Drop for ProbeGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(state) = self.0 {
+            state.lock().unwrap().probing = false;
+        }
+    }
+}
+
+#[async_trait]
+impl<D: Dialer> Dialer for CircuitBreakerDialer<D> {
+    type P = D::P;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let probe = {
+            let mut state = self.state.lock().unwrap();
+            match state.open_until {
+                Some(open_until) if open_until > Instant::now() => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionRefused,
+                        "circuit breaker is open",
+                    ));
+                }
+                Some(_) if state.probing => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionRefused,
+                        "circuit breaker is half-open and already probing",
+                    ));
+                }
+                Some(_) => {
+                    state.probing = true;
+                    ProbeGuard(Some(&self.state))
+                }
+                None => ProbeGuard(None),
+            }
+        };
+        let res = self.dialer.dial().await;
+        drop(probe);
+        let mut state = self.state.lock().unwrap();
+        match &res {
+            Ok(_) => *state = CircuitState::default(),
+            Err(err) => {
+                state.consecutive_failures += 1;
+                if state.consecutive_failures >= self.threshold {
+                    tracing::warn!(
+                        err = debug(err),
+                        consecutive_failures = state.consecutive_failures,
+                        cooldown = debug(self.cooldown),
+                        "opening circuit breaker"
+                    );
+                    state.open_until = Some(Instant::now() + self.cooldown);
+                }
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemListener;
+
+    #[test]
+    fn multi_race_prefers_winner() {
+        async_io::block_on(async {
+            // memory dials complete without anybody accepting
+            let fast = MemListener::new();
+            let slow = MemListener::new();
+            let dialer = MultiRaceDialer::new([
+                (FailingDialer.dynamic(), Duration::ZERO),
+                (slow.dialer().dynamic(), Duration::from_millis(300)),
+                (fast.dialer().dynamic(), Duration::from_millis(100)),
+            ]);
+
+            let start = Instant::now();
+            dialer.dial().await.unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(100));
+            assert_eq!(dialer.stats()[2].wins, 1);
+
+            // the previous winner now gets to skip its delay
+            let start = Instant::now();
+            dialer.dial().await.unwrap();
+            assert!(start.elapsed() < Duration::from_millis(100));
+            assert_eq!(dialer.stats()[2].wins, 2);
+            assert_eq!(dialer.stats()[1].wins, 0);
+        })
+    }
+
+    #[test]
+    fn multi_race_favorite_fails() {
+        async_io::block_on(async {
+            let flaky_listener = MemListener::new();
+            let backup = MemListener::new();
+            let slow = Arc::new(std::sync::atomic::AtomicBool::new(true));
+            let dialer = MultiRaceDialer::new([
+                (
+                    flaky_listener
+                        .dialer()
+                        .dyn_delay({
+                            let slow = slow.clone();
+                            move || {
+                                if slow.load(std::sync::atomic::Ordering::SeqCst) {
+                                    Duration::from_millis(500)
+                                } else {
+                                    Duration::ZERO
+                                }
+                            }
+                        })
+                        .dynamic(),
+                    Duration::ZERO,
+                ),
+                (backup.dialer().dynamic(), Duration::from_millis(600)),
+            ]);
+            // the first branch wins slowly, so the backup would now wait another 500ms behind it
+            dialer.dial().await.unwrap();
+            assert_eq!(dialer.stats()[0].wins, 1);
+
+            // once the favorite fails, the backup starts after its own delay instead of sitting out the head start too
+            slow.store(false, std::sync::atomic::Ordering::SeqCst);
+            drop(flaky_listener);
+            let start = Instant::now();
+            dialer.dial().await.unwrap();
+            assert!(start.elapsed() < Duration::from_millis(1000));
+            let stats = dialer.stats();
+            assert_eq!(stats[0].failures, 1);
+            assert!(stats[0].score < 0.6);
+            assert_eq!(stats[1].wins, 1);
+        })
+    }
+
+    #[test]
+    fn multi_race_all_fail() {
+        async_io::block_on(async {
+            let dialer = MultiRaceDialer::new([
+                (FailingDialer.dynamic(), Duration::ZERO),
+                (FailingDialer.dynamic(), Duration::from_millis(10)),
+            ]);
+            assert!(dialer.dial().await.is_err());
+            assert!(MultiRaceDialer::new([]).dial().await.is_err());
+        })
+    }
+
+    /// Fails until it has been dialed `failures` times, counting every attempt.
+    struct FlakyDialer {
+        inner: crate::mem::MemDialer,
+        failures: usize,
+        attempts: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Dialer for FlakyDialer {
+        type P = crate::mem::MemPipe;
+
+        async fn dial(&self) -> std::io::Result<Self::P> {
+            let attempt = self
+                .attempts
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if attempt < self.failures {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionRefused,
+                    "flaky",
+                ));
+            }
+            self.inner.dial().await
+        }
+    }
+
+    fn flaky(listener: &MemListener, failures: usize) -> FlakyDialer {
+        FlakyDialer {
+            inner: listener.dialer(),
+            failures,
+            attempts: Default::default(),
+        }
+    }
+
+    #[test]
+    fn retry_backs_off() {
+        async_io::block_on(async {
+            let listener = MemListener::new();
+            let policy = RetryPolicy {
+                max_attempts: Some(3),
+                initial_delay: Duration::from_millis(20),
+                max_delay: Duration::from_millis(30),
+                multiplier: 2.0,
+                jitter: 0.0,
+            };
+            assert_eq!(policy.backoff(1), Duration::from_millis(20));
+            assert_eq!(policy.backoff(5), Duration::from_millis(30));
+
+            let start = Instant::now();
+            flaky(&listener, 2).retry(policy).dial().await.unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(50));
+
+            let dialer = flaky(&listener, 3).retry(policy);
+            assert!(dialer.dial().await.is_err());
+            assert_eq!(
+                dialer
+                    .dialer
+                    .attempts
+                    .load(std::sync::atomic::Ordering::SeqCst),
+                3
+            );
+        })
+    }
+
+    #[test]
+    fn circuit_breaker_fails_fast() {
+        async_io::block_on(async {
+            let listener = MemListener::new();
+            let dialer = flaky(&listener, 2).circuit_breaker(2, Duration::from_millis(50));
+            assert!(dialer.dial().await.is_err());
+            assert!(dialer.dial().await.is_err());
+
+            // the circuit is now open, so the inner dialer is not touched even though it would succeed
+            assert!(dialer.dial().await.is_err());
+            assert_eq!(
+                dialer
+                    .dialer
+                    .attempts
+                    .load(std::sync::atomic::Ordering::SeqCst),
+                2
+            );
+
+            async_io::Timer::after(Duration::from_millis(60)).await;
+            dialer.dial().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn circuit_breaker_single_probe() {
+        async_io::block_on(async {
+            let listener = MemListener::new();
+            let dialer = flaky(&listener, 2)
+                .delay(Duration::from_millis(20))
+                .circuit_breaker(2, Duration::from_millis(50));
+            assert!(dialer.dial().await.is_err());
+            assert!(dialer.dial().await.is_err());
+            async_io::Timer::after(Duration::from_millis(60)).await;
+
+            // only one dial gets to probe the half-open circuit, while the other fails fast
+            let (probe, other) = futures_lite::future::zip(dialer.dial(), async {
+                async_io::Timer::after(Duration::from_millis(5)).await;
+                dialer.dial().await
+            })
+            .await;
+            probe.unwrap();
+            assert!(other.is_err());
+            assert_eq!(
+                dialer
+                    .dialer
+                    .dialer
+                    .attempts
+                    .load(std::sync::atomic::Ordering::SeqCst),
+                3
+            );
+
+            // the successful probe closed the circuit again
+            dialer.dial().await.unwrap();
+        })
+    }
+}
@@ -1,11 +1,15 @@
-use std::pin::{pin, Pin};
+use std::pin::Pin;
 
 use futures_util::{AsyncRead, AsyncWrite};
 use pin_project::pin_project;
 
 pub mod dialer;
 pub mod listener;
+pub mod mem;
+pub mod proxy_protocol;
 pub mod tcp;
+#[cfg(unix)]
+pub mod unix;
 
 /// Sillad overall is based on returning connection-like items that implement AsyncRead and AsyncWrite, as well as a few other things. This is called a Pipe.
 pub trait Pipe: AsyncRead + AsyncWrite + Send + Unpin + 'static {
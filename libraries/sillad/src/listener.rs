@@ -1,6 +1,15 @@
-use std::{future::Future, pin::Pin, sync::Arc};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use async_lock::{Semaphore, SemaphoreGuardArc};
 use async_trait::async_trait;
+use futures_lite::FutureExt;
+use futures_util::{AsyncRead, AsyncWrite};
+use pin_project::pin_project;
 
 use crate::{EitherPipe, Pipe};
 
@@ -19,6 +28,50 @@ pub trait ListenerExt: Listener {
     fn dynamic(self) -> DynListener {
         DynListener::new(self)
     }
+
+    /// Drops incoming connections whose remote address does not pass the given predicate.
+    fn filter(
+        self,
+        pred: impl Fn(Option<&str>) -> bool + Send + Sync + 'static,
+    ) -> FilterListener<Self> {
+        FilterListener {
+            listener: self,
+            pred: Box::new(move |remote_addr| {
+                let pass = pred(remote_addr.as_deref());
+                async move { pass }.boxed()
+            }),
+        }
+    }
+
+    /// Like [ListenerExt::filter], but with a predicate that needs to do asynchronous work, such as a database lookup.
+    fn filter_async<F: Future<Output = bool> + Send + 'static>(
+        self,
+        pred: impl Fn(Option<String>) -> F + Send + Sync + 'static,
+    ) -> FilterListener<Self> {
+        FilterListener {
+            listener: self,
+            pred: Box::new(move |remote_addr| pred(remote_addr).boxed()),
+        }
+    }
+
+    /// Stops accepting new connections while `limit` of the connections it accepted are still alive.
+    fn max_concurrent(self, limit: usize) -> MaxConcurrentListener<Self> {
+        MaxConcurrentListener {
+            listener: self,
+            semaph: Arc::new(Semaphore::new(limit)),
+        }
+    }
+
+    /// Accepts at most `per_sec` connections per second on average, allowing bursts of up to a second's worth.
+    fn accept_rate(self, per_sec: f64) -> AcceptRateListener<Self> {
+        assert!(per_sec > 0.0, "accept rate must be positive");
+        AcceptRateListener {
+            listener: self,
+            per_sec,
+            tokens: per_sec.max(1.0),
+            last_refill: Instant::now(),
+        }
+    }
 }
 
 impl<T: Listener> ListenerExt for T {}
@@ -56,6 +109,142 @@ impl<L: Listener, R: Listener> Listener for EitherListener<L, R> {
     }
 }
 
+type FilterPredicate =
+    Box<dyn Fn(Option<String>) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync>;
+
+/// FilterListener is a listener that only lets through connections whose remote address passes a predicate.
+pub struct FilterListener<L: Listener> {
+    listener: L,
+    pred: FilterPredicate,
+}
+
+#[async_trait]
+impl<L: Listener> Listener for FilterListener<L> {
+    type P = L::P;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        loop {
+            let pipe = self.listener.accept().await?;
+            let remote_addr = pipe.remote_addr().map(|s| s.to_string());
+            if (self.pred)(remote_addr.clone()).await {
+                return Ok(pipe);
+            }
+            tracing::debug!(
+                remote_addr = debug(remote_addr),
+                "connection rejected by filter"
+            );
+        }
+    }
+}
+
+/// MaxConcurrentListener is a listener that holds off accepting while too many of its connections are alive.
+pub struct MaxConcurrentListener<L: Listener> {
+    listener: L,
+    semaph: Arc<Semaphore>,
+}
+
+#[async_trait]
+impl<L: Listener> Listener for MaxConcurrentListener<L> {
+    type P = AdmittedPipe<L::P>;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        let permit = self.semaph.acquire_arc().await;
+        let inner = self.listener.accept().await?;
+        Ok(AdmittedPipe {
+            inner,
+            _permit: permit,
+        })
+    }
+}
+
+/// A pipe accepted by a [MaxConcurrentListener], which counts as alive until it is dropped.
+#[pin_project]
+pub struct AdmittedPipe<P: Pipe> {
+    #[pin]
+    inner: P,
+    _permit: SemaphoreGuardArc,
+}
+
+impl<P: Pipe> AsyncRead for AdmittedPipe<P> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().inner.poll_read(cx, buf)
+    }
+}
+
+impl<P: Pipe> AsyncWrite for AdmittedPipe<P> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<P: Pipe> Pipe for AdmittedPipe<P> {
+    fn shared_secret(&self) -> Option<&[u8]> {
+        self.inner.shared_secret()
+    }
+
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.inner.remote_addr()
+    }
+}
+
+/// AcceptRateListener is a listener that limits how quickly connections are accepted, using a token bucket.
+pub struct AcceptRateListener<L: Listener> {
+    listener: L,
+    per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+#[async_trait]
+impl<L: Listener> Listener for AcceptRateListener<L> {
+    type P = L::P;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        let now = Instant::now();
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.per_sec.max(1.0));
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            // connections pile up in the backlog of the inner listener in the meantime
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.per_sec);
+            async_io::Timer::after(wait).await;
+            self.tokens = 1.0;
+            self.last_refill = Instant::now();
+        }
+        let pipe = self.listener.accept().await?;
+        self.tokens -= 1.0;
+        Ok(pipe)
+    }
+}
+
 type DynListenerFuture = Pin<Box<dyn Send + Future<Output = std::io::Result<Box<dyn Pipe>>>>>;
 
 /// A type-erased `Listener` that always returns a type-erased `Pipe`.
@@ -97,3 +286,81 @@ impl Listener for DynListener {
         closure().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::future::zip;
+
+    use super::*;
+    use crate::{dialer::Dialer, mem::MemListener};
+
+    async fn give_up<T>() -> std::io::Result<T> {
+        async_io::Timer::after(Duration::from_millis(50)).await;
+        Err(std::io::ErrorKind::TimedOut.into())
+    }
+
+    #[test]
+    fn filter_drops_rejected() {
+        async_io::block_on(async {
+            // memory pipes have the name of their listener, such as "mem-1", as the remote address
+            let inner = MemListener::new();
+            let dialer = inner.dialer();
+            dialer.dial().await.unwrap();
+            let mut listener =
+                inner.filter(|addr| !addr.is_some_and(|addr| addr.starts_with("mem-")));
+            assert!(listener.accept().or(give_up()).await.is_err());
+
+            let inner = MemListener::new();
+            let dialer = inner.dialer();
+            dialer.dial().await.unwrap();
+            let mut listener = inner.filter_async(|addr| async move { addr.is_some() });
+            listener.accept().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn max_concurrent_holds_accepts() {
+        async_io::block_on(async {
+            let inner = MemListener::new();
+            let dialer = inner.dialer();
+            let mut listener = inner.max_concurrent(1);
+            let _a = dialer.dial().await.unwrap();
+            let _b = dialer.dial().await.unwrap();
+
+            let first = listener.accept().await.unwrap();
+            assert!(listener.accept().or(give_up()).await.is_err());
+
+            // once the first connection is gone, the second one gets through
+            let (_, second) = zip(
+                async {
+                    async_io::Timer::after(Duration::from_millis(10)).await;
+                    drop(first)
+                },
+                listener.accept(),
+            )
+            .await;
+            second.unwrap();
+        })
+    }
+
+    #[test]
+    fn accept_rate_paces_accepts() {
+        async_io::block_on(async {
+            let inner = MemListener::new();
+            let dialer = inner.dialer();
+            let mut listener = inner.accept_rate(20.0);
+            let mut pipes = vec![];
+            for _ in 0..25 {
+                pipes.push(dialer.dial().await.unwrap());
+            }
+            let start = Instant::now();
+            for _ in 0..25 {
+                listener.accept().await.unwrap();
+            }
+            // a burst of 20, then 5 more at 20 per second
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+            assert!(elapsed < Duration::from_millis(600), "{elapsed:?}");
+        })
+    }
+}
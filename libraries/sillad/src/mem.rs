@@ -0,0 +1,545 @@
+//! In-process pipes and network fault injection, mostly useful for testing protocols built on sillad without real sockets.
+
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use async_trait::async_trait;
+use bipe::{BipeReader, BipeWriter};
+use futures_lite::{AsyncRead, AsyncWrite, FutureExt};
+use pin_project::pin_project;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{dialer::Dialer, listener::Listener, Pipe};
+
+const MEM_PIPE_CAPACITY: usize = 65536;
+
+/// How many bytes a [FaultyPipe] holds back for latency and jitter before it stops reading from the lower pipe.
+const MAX_DELAYED_BYTES: usize = MEM_PIPE_CAPACITY;
+
+/// A MemListener accepts pipes created by its [MemDialer]s, entirely in memory.
+pub struct MemListener {
+    send: async_channel::Sender<MemPipe>,
+    recv: async_channel::Receiver<MemPipe>,
+    name: String,
+}
+
+impl MemListener {
+    /// Creates a new in-memory listener with a unique name.
+    pub fn new() -> Self {
+        static ID_CTR: AtomicU64 = AtomicU64::new(0);
+        let (send, recv) = async_channel::unbounded();
+        Self {
+            send,
+            recv,
+            name: format!("mem-{}", ID_CTR.fetch_add(1, Ordering::Relaxed)),
+        }
+    }
+
+    /// Creates a dialer that connects to this listener.
+    pub fn dialer(&self) -> MemDialer {
+        MemDialer {
+            send: self.send.downgrade(),
+            name: self.name.clone(),
+        }
+    }
+}
+
+impl Default for MemListener {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Listener for MemListener {
+    type P = MemPipe;
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        self.recv.recv().await.map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "memory listener closed")
+        })
+    }
+}
+
+/// A MemDialer connects to a [MemListener]. Dialing fails with ConnectionRefused once the listener is dropped.
+#[derive(Clone)]
+pub struct MemDialer {
+    send: async_channel::WeakSender<MemPipe>,
+    name: String,
+}
+
+#[async_trait]
+impl Dialer for MemDialer {
+    type P = MemPipe;
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let refused = || {
+            std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                "memory listener is gone",
+            )
+        };
+        let send = self.send.upgrade().ok_or_else(refused)?;
+        let (client, server) = MemPipe::pair(&self.name);
+        send.send(server).await.map_err(|_| refused())?;
+        Ok(client)
+    }
+}
+
+/// One end of an in-memory, bidirectional byte pipe.
+#[pin_project]
+pub struct MemPipe {
+    #[pin]
+    read: BipeReader,
+    #[pin]
+    write: BipeWriter,
+    name: String,
+}
+
+impl MemPipe {
+    /// Creates a pair of connected pipes, both identifying the other side by the given name.
+    pub fn pair(name: &str) -> (Self, Self) {
+        let (write_a, read_b) = bipe::bipe(MEM_PIPE_CAPACITY);
+        let (write_b, read_a) = bipe::bipe(MEM_PIPE_CAPACITY);
+        (
+            Self {
+                read: read_a,
+                write: write_a,
+                name: name.to_string(),
+            },
+            Self {
+                read: read_b,
+                write: write_b,
+                name: name.to_string(),
+            },
+        )
+    }
+}
+
+impl AsyncRead for MemPipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().read.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().write.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().write.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().write.poll_close(cx)
+    }
+}
+
+impl Pipe for MemPipe {
+    fn protocol(&self) -> &str {
+        "mem"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+}
+
+/// Describes the network faults that a [FaultyPipe] injects. The default injects nothing.
+#[derive(Clone, Debug, Default)]
+pub struct FaultConfig {
+    /// Fixed delay before data read from the lower pipe becomes visible.
+    pub latency: Duration,
+    /// Additional random delay, uniform in `0..jitter`, on top of `latency`. Ordering of the byte stream is preserved.
+    pub jitter: Duration,
+    /// Maximum write rate in bytes per second.
+    pub bandwidth: Option<u64>,
+    /// Reset the pipe once this many bytes in total have been read or written.
+    pub reset_after: Option<u64>,
+    /// Whether writes accept only a random, nonempty prefix of the given buffer.
+    pub partial_writes: bool,
+    /// Seed for all the randomness, so that tests are reproducible.
+    pub seed: u64,
+}
+
+/// FaultyPipe wraps any pipe, adding latency, jitter, bandwidth limits, resets, and partial writes as described by a [FaultConfig].
+#[pin_project]
+pub struct FaultyPipe<P: Pipe> {
+    #[pin]
+    inner: P,
+    config: FaultConfig,
+    rng: StdRng,
+
+    delayed: VecDeque<(Instant, Vec<u8>)>,
+    delayed_bytes: usize,
+    read_eof: bool,
+    read_timer: Option<Timer>,
+
+    write_ready_at: Option<Instant>,
+    write_timer: Option<Timer>,
+
+    total_bytes: u64,
+    inner_closed: bool,
+}
+
+impl<P: Pipe> FaultyPipe<P> {
+    /// Wraps a pipe with the given faults.
+    pub fn new(inner: P, config: FaultConfig) -> Self {
+        let rng = StdRng::seed_from_u64(config.seed);
+        Self {
+            inner,
+            config,
+            rng,
+            delayed: VecDeque::new(),
+            delayed_bytes: 0,
+            read_eof: false,
+            read_timer: None,
+            write_ready_at: None,
+            write_timer: None,
+            total_bytes: 0,
+            inner_closed: false,
+        }
+    }
+}
+
+fn connection_reset() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::ConnectionReset,
+        "injected connection reset",
+    )
+}
+
+fn is_reset(total_bytes: u64, config: &FaultConfig) -> bool {
+    config
+        .reset_after
+        .is_some_and(|reset_after| total_bytes >= reset_after)
+}
+
+/// Closes the lower pipe once, so that the other side sees the injected reset too, then fails with a reset.
+fn poll_reset<P: Pipe>(
+    inner: Pin<&mut P>,
+    inner_closed: &mut bool,
+    cx: &mut Context<'_>,
+) -> Poll<std::io::Error> {
+    if !*inner_closed {
+        // the lower pipe failing to close doesn't matter, since we're tearing it down anyway
+        let _ = futures_lite::ready!(inner.poll_close(cx));
+        *inner_closed = true;
+    }
+    Poll::Ready(connection_reset())
+}
+
+impl<P: Pipe> AsyncRead for FaultyPipe<P> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut this = self.project();
+        if is_reset(*this.total_bytes, this.config) {
+            return poll_reset(this.inner, this.inner_closed, cx).map(Err);
+        }
+        // eagerly pull in what the lower pipe has, stamping each chunk with when it should become visible. once enough is held back, we stop reading and let backpressure reach the sender
+        let mut tmp = [0u8; 8192];
+        while !*this.read_eof && *this.delayed_bytes < MAX_DELAYED_BYTES {
+            match this.inner.as_mut().poll_read(cx, &mut tmp) {
+                Poll::Ready(Ok(0)) => *this.read_eof = true,
+                Poll::Ready(Ok(n)) => {
+                    let mut delay = this.config.latency;
+                    if !this.config.jitter.is_zero() {
+                        delay += this.config.jitter.mul_f64(this.rng.gen());
+                    }
+                    let mut deliver_at = Instant::now() + delay;
+                    if let Some((last, _)) = this.delayed.back() {
+                        deliver_at = deliver_at.max(*last);
+                    }
+                    this.delayed.push_back((deliver_at, tmp[..n].to_vec()));
+                    *this.delayed_bytes += n;
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => break,
+            }
+        }
+
+        let Some((deliver_at, _)) = this.delayed.front() else {
+            return if *this.read_eof {
+                Poll::Ready(Ok(0))
+            } else {
+                Poll::Pending
+            };
+        };
+        if *deliver_at > Instant::now() {
+            let timer = this
+                .read_timer
+                .get_or_insert_with(|| Timer::at(*deliver_at));
+            timer.set_at(*deliver_at);
+            futures_lite::ready!(timer.poll(cx));
+        }
+        *this.read_timer = None;
+
+        let (_, chunk) = this.delayed.front_mut().unwrap();
+        let mut n = chunk.len().min(buf.len());
+        if let Some(reset_after) = this.config.reset_after {
+            n = n.min((reset_after - *this.total_bytes) as usize);
+        }
+        buf[..n].copy_from_slice(&chunk[..n]);
+        chunk.drain(..n);
+        *this.delayed_bytes -= n;
+        if chunk.is_empty() {
+            this.delayed.pop_front();
+        }
+        *this.total_bytes += n as u64;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<P: Pipe> AsyncWrite for FaultyPipe<P> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        if is_reset(*this.total_bytes, this.config) {
+            return poll_reset(this.inner, this.inner_closed, cx).map(Err);
+        }
+        if let Some(ready_at) = *this.write_ready_at {
+            if ready_at > Instant::now() {
+                let timer = this.write_timer.get_or_insert_with(|| Timer::at(ready_at));
+                timer.set_at(ready_at);
+                futures_lite::ready!(timer.poll(cx));
+            }
+            *this.write_timer = None;
+        }
+
+        let mut n = buf.len();
+        if this.config.partial_writes && n > 1 {
+            n = this.rng.gen_range(1..=n);
+        }
+        if let Some(reset_after) = this.config.reset_after {
+            n = n.min((reset_after - *this.total_bytes) as usize);
+        }
+        let n = futures_lite::ready!(this.inner.poll_write(cx, &buf[..n]))?;
+        *this.total_bytes += n as u64;
+        if let Some(bandwidth) = this.config.bandwidth {
+            let start = this
+                .write_ready_at
+                .unwrap_or_else(Instant::now)
+                .max(Instant::now());
+            *this.write_ready_at =
+                Some(start + Duration::from_secs_f64(n as f64 / bandwidth.max(1) as f64));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        if is_reset(*this.total_bytes, this.config) {
+            return poll_reset(this.inner, this.inner_closed, cx).map(Err);
+        }
+        this.inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<P: Pipe> Pipe for FaultyPipe<P> {
+    fn shared_secret(&self) -> Option<&[u8]> {
+        self.inner.shared_secret()
+    }
+
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.inner.remote_addr()
+    }
+}
+
+/// FaultyDialer wraps every pipe its inner dialer produces in a [FaultyPipe].
+pub struct FaultyDialer<D: Dialer> {
+    pub inner: D,
+    pub config: FaultConfig,
+}
+
+#[async_trait]
+impl<D: Dialer> Dialer for FaultyDialer<D> {
+    type P = FaultyPipe<D::P>;
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let inner = self.inner.dial().await?;
+        Ok(FaultyPipe::new(inner, self.config.clone()))
+    }
+}
+
+/// FaultyListener wraps every pipe its inner listener accepts in a [FaultyPipe].
+pub struct FaultyListener<L: Listener> {
+    pub inner: L,
+    pub config: FaultConfig,
+}
+
+#[async_trait]
+impl<L: Listener> Listener for FaultyListener<L> {
+    type P = FaultyPipe<L::P>;
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        let inner = self.inner.accept().await?;
+        Ok(FaultyPipe::new(inner, self.config.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn mem_roundtrip() {
+        async_io::block_on(async {
+            let mut listener = MemListener::new();
+            let dialer = listener.dialer();
+            let mut client = dialer.dial().await.unwrap();
+            let mut server = listener.accept().await.unwrap();
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            drop(listener);
+            assert_eq!(
+                dialer.dial().await.err().unwrap().kind(),
+                std::io::ErrorKind::ConnectionRefused
+            );
+        })
+    }
+
+    #[test]
+    fn faulty_latency_and_partial_writes() {
+        async_io::block_on(async {
+            let (client, server) = MemPipe::pair("test");
+            let mut client = FaultyPipe::new(
+                client,
+                FaultConfig {
+                    partial_writes: true,
+                    ..Default::default()
+                },
+            );
+            let mut server = FaultyPipe::new(
+                server,
+                FaultConfig {
+                    latency: Duration::from_millis(100),
+                    jitter: Duration::from_millis(20),
+                    ..Default::default()
+                },
+            );
+            let message: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+            let start = Instant::now();
+            client.write_all(&message).await.unwrap();
+            let mut buf = vec![0u8; message.len()];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, message);
+            assert!(start.elapsed() >= Duration::from_millis(100));
+        })
+    }
+
+    #[test]
+    fn faulty_reset() {
+        async_io::block_on(async {
+            let (client, mut server) = MemPipe::pair("test");
+            let mut client = FaultyPipe::new(
+                client,
+                FaultConfig {
+                    reset_after: Some(1000),
+                    ..Default::default()
+                },
+            );
+            let err = client.write_all(&[0u8; 5000]).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+            // the other side sees exactly the bytes before the reset, then the pipe closing
+            let mut buf = vec![];
+            server.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf.len(), 1000);
+        })
+    }
+
+    #[test]
+    fn faulty_latency_backpressure() {
+        async_io::block_on(async {
+            let (mut client, server) = MemPipe::pair("test");
+            let mut server = FaultyPipe::new(
+                server,
+                FaultConfig {
+                    latency: Duration::from_secs(3600),
+                    ..Default::default()
+                },
+            );
+            // nothing is ever delivered, but reading must not drain the sender without bound
+            let reader = async {
+                let mut buf = [0u8; 1];
+                server.read_exact(&mut buf).await.unwrap();
+                unreachable!()
+            };
+            let writer = async {
+                let mut written = 0;
+                while client
+                    .write(&[0u8; 8192])
+                    .or(async {
+                        Timer::after(Duration::from_millis(100)).await;
+                        Ok(0)
+                    })
+                    .await
+                    .unwrap()
+                    > 0
+                {
+                    written += 8192;
+                }
+                written
+            };
+            let written = reader.or(writer).await;
+            assert!(written <= MAX_DELAYED_BYTES + MEM_PIPE_CAPACITY + 8192);
+            assert!(server.delayed_bytes <= MAX_DELAYED_BYTES);
+        })
+    }
+
+    #[test]
+    fn faulty_bandwidth() {
+        async_io::block_on(async {
+            let (client, mut server) = MemPipe::pair("test");
+            let mut client = FaultyPipe::new(
+                client,
+                FaultConfig {
+                    bandwidth: Some(100_000),
+                    ..Default::default()
+                },
+            );
+            let start = Instant::now();
+            let reader = async {
+                let mut buf = vec![0u8; 30_000];
+                server.read_exact(&mut buf).await.unwrap();
+            };
+            let writer = async {
+                for _ in 0..3 {
+                    client.write_all(&[0u8; 10_000]).await.unwrap();
+                }
+            };
+            futures_lite::future::zip(reader, writer).await;
+            // the first write goes through immediately, the other two each wait 100ms
+            assert!(start.elapsed() >= Duration::from_millis(200));
+        })
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Mutex,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures_lite::FutureExt;
+use futures_util::{stream::FuturesUnordered, AsyncRead, AsyncReadExt, AsyncWrite, StreamExt};
+use pin_project::pin_project;
+use smol_timeout2::TimeoutExt;
+
+use crate::{listener::Listener, Pipe};
+
+/// The first 12 bytes of every version 2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest possible version 1 header, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// How long a connection gets to send its header before it is dropped.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+type PendingHeader<P> = Pin<Box<dyn Future<Output = std::io::Result<ProxyProtocolPipe<P>>> + Send>>;
+
+/// ProxyProtocolListener wraps a listener that sits behind a load balancer or reverse proxy, such as HAProxy, that speaks the PROXY protocol.
+///
+/// Every accepted connection must start with a version 1 or version 2 PROXY header, which is stripped. The client address in the header is then reported as the remote address. Connections without a valid header are dropped, so this must only be used when *all* connections come through the proxy.
+pub struct ProxyProtocolListener<L: Listener> {
+    inner: L,
+    // only ever accessed through &mut, but the mutex makes the listener Sync
+    pending: Mutex<FuturesUnordered<PendingHeader<L::P>>>,
+}
+
+impl<L: Listener> ProxyProtocolListener<L> {
+    pub fn new(inner: L) -> Self {
+        Self {
+            inner,
+            pending: Mutex::new(FuturesUnordered::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl<L: Listener> Listener for ProxyProtocolListener<L> {
+    type P = ProxyProtocolPipe<L::P>;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        enum Event<P: Pipe> {
+            Accepted(std::io::Result<P>),
+            Parsed(std::io::Result<ProxyProtocolPipe<P>>),
+        }
+
+        let Self { inner, pending } = self;
+        let pending = pending.get_mut().unwrap();
+        loop {
+            // headers are read concurrently, so that a slow connection cannot hold up the others
+            let event = async { Event::Parsed(next_parsed(pending).await) }
+                .or(async { Event::Accepted(inner.accept().await) })
+                .await;
+            match event {
+                Event::Accepted(pipe) => {
+                    let pipe = pipe?;
+                    pending.push(
+                        async move {
+                            read_header(pipe)
+                                .timeout(HEADER_TIMEOUT)
+                                .await
+                                .unwrap_or_else(|| {
+                                    Err(std::io::Error::new(
+                                        std::io::ErrorKind::TimedOut,
+                                        "timed out waiting for PROXY header",
+                                    ))
+                                })
+                        }
+                        .boxed(),
+                    );
+                }
+                Event::Parsed(Ok(pipe)) => return Ok(pipe),
+                Event::Parsed(Err(err)) => {
+                    tracing::debug!(
+                        err = debug(err),
+                        "dropping connection without a valid PROXY header"
+                    )
+                }
+            }
+        }
+    }
+}
+
+async fn next_parsed<P: Pipe>(
+    pending: &mut FuturesUnordered<PendingHeader<P>>,
+) -> std::io::Result<ProxyProtocolPipe<P>> {
+    match pending.next().await {
+        Some(res) => res,
+        None => futures_lite::future::pending().await,
+    }
+}
+
+async fn read_header<P: Pipe>(mut pipe: P) -> std::io::Result<ProxyProtocolPipe<P>> {
+    // both versions have at least 12 bytes of header, so this never reads into the payload
+    let mut start = [0u8; 12];
+    pipe.read_exact(&mut start).await?;
+    let real_addr = if start == V2_SIGNATURE {
+        read_v2(&mut pipe).await?
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(&mut pipe, &start).await?
+    } else {
+        return Err(invalid("no PROXY header"));
+    };
+    Ok(ProxyProtocolPipe {
+        inner: pipe,
+        real_addr: real_addr.map(|addr| addr.to_string()),
+    })
+}
+
+/// Reads the rest of a text header, returning the source address unless the proxy said it's UNKNOWN.
+async fn read_v1(pipe: &mut impl Pipe, start: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+    // one byte at a time, since the line has no length prefix
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        let mut byte = [0u8; 1];
+        pipe.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src_ip, _dst_ip, src_port, _dst_port] => {
+            let ip: IpAddr = src_ip
+                .parse()
+                .map_err(|_| invalid("bad source address in PROXY v1 header"))?;
+            let port: u16 = src_port
+                .parse()
+                .map_err(|_| invalid("bad source port in PROXY v1 header"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+/// Reads the rest of a binary header after its signature, returning the source address if it's an IP one.
+async fn read_v2(pipe: &mut impl Pipe) -> std::io::Result<Option<SocketAddr>> {
+    let mut fixed = [0u8; 4];
+    pipe.read_exact(&mut fixed).await?;
+    let [ver_cmd, family, len_hi, len_lo] = fixed;
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported PROXY header version"));
+    }
+    // the addresses and any TLVs, which we skip over
+    let mut body = vec![0u8; u16::from_be_bytes([len_hi, len_lo]) as usize];
+    pipe.read_exact(&mut body).await?;
+    match ver_cmd & 0xf {
+        // LOCAL: health checks and such from the proxy itself
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+    let short = || invalid("PROXY v2 address block too short");
+    match family >> 4 {
+        1 => {
+            let addrs: [u8; 12] = body.get(..12).ok_or_else(short)?.try_into().unwrap();
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addrs[..4]).unwrap());
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        2 => {
+            let addrs: [u8; 36] = body.get(..36).ok_or_else(short)?.try_into().unwrap();
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addrs[..16]).unwrap());
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // unspecified or unix socket addresses tell us nothing useful
+        _ => Ok(None),
+    }
+}
+
+fn invalid(msg: &'static str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// A pipe accepted by a [ProxyProtocolListener], whose remote address is the one the proxy reported.
+#[pin_project]
+pub struct ProxyProtocolPipe<P: Pipe> {
+    #[pin]
+    inner: P,
+    real_addr: Option<String>,
+}
+
+impl<P: Pipe> AsyncRead for ProxyProtocolPipe<P> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().inner.poll_read(cx, buf)
+    }
+}
+
+impl<P: Pipe> AsyncWrite for ProxyProtocolPipe<P> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<P: Pipe> Pipe for ProxyProtocolPipe<P> {
+    fn shared_secret(&self) -> Option<&[u8]> {
+        self.inner.shared_secret()
+    }
+
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        // when the proxy didn't tell us, the best we have is the address of the proxy itself
+        self.real_addr
+            .as_deref()
+            .or_else(|| self.inner.remote_addr())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::AsyncWriteExt;
+
+    use super::*;
+    use crate::{dialer::Dialer, mem::MemListener};
+
+    async fn accept_with_header(header: &[u8]) -> std::io::Result<(String, Vec<u8>)> {
+        let inner = MemListener::new();
+        let mut client = inner.dialer().dial().await?;
+        let mut listener = ProxyProtocolListener::new(inner);
+        client.write_all(header).await?;
+        client.write_all(b"payload").await?;
+        let mut pipe = listener
+            .accept()
+            .or(async {
+                async_io::Timer::after(Duration::from_millis(100)).await;
+                Err(std::io::ErrorKind::TimedOut.into())
+            })
+            .await?;
+        let mut payload = vec![0u8; 7];
+        pipe.read_exact(&mut payload).await?;
+        Ok((pipe.remote_addr().unwrap().to_string(), payload))
+    }
+
+    #[test]
+    fn proxy_v1() {
+        async_io::block_on(async {
+            let (addr, payload) =
+                accept_with_header(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\n")
+                    .await
+                    .unwrap();
+            assert_eq!(addr, "203.0.113.7:51234");
+            assert_eq!(payload, b"payload");
+
+            let (addr, _) = accept_with_header(b"PROXY TCP6 2001:db8::1 ::1 8080 443\r\n")
+                .await
+                .unwrap();
+            assert_eq!(addr, "[2001:db8::1]:8080");
+
+            // the address of the proxy itself is all we know
+            let (addr, _) = accept_with_header(b"PROXY UNKNOWN\r\n").await.unwrap();
+            assert!(addr.starts_with("mem-"));
+        })
+    }
+
+    #[test]
+    fn proxy_v2() {
+        async_io::block_on(async {
+            let mut header = V2_SIGNATURE.to_vec();
+            // PROXY command over TCP/IPv4, with the addresses followed by a 3-byte TLV
+            header.extend_from_slice(&[0x21, 0x11, 0, 15]);
+            header.extend_from_slice(&[198, 51, 100, 9, 10, 0, 0, 1]);
+            header.extend_from_slice(&4000u16.to_be_bytes());
+            header.extend_from_slice(&443u16.to_be_bytes());
+            header.extend_from_slice(&[0x04, 0, 0]);
+            let (addr, payload) = accept_with_header(&header).await.unwrap();
+            assert_eq!(addr, "198.51.100.9:4000");
+            assert_eq!(payload, b"payload");
+
+            let mut header = V2_SIGNATURE.to_vec();
+            header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+            let (addr, _) = accept_with_header(&header).await.unwrap();
+            assert!(addr.starts_with("mem-"));
+        })
+    }
+
+    #[test]
+    fn proxy_missing_header() {
+        async_io::block_on(async {
+            assert!(accept_with_header(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        })
+    }
+}
@@ -9,23 +9,167 @@ use async_trait::async_trait;
 use futures_lite::{AsyncRead, AsyncWrite};
 use pin_project::pin_project;
 use rand::Rng as _;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 
 use crate::{
-    dialer::{Dialer, DialerExt},
+    dialer::{Dialer, DialerExt, MultiRaceDialer},
     listener::Listener,
     Pipe,
 };
 
+/// Socket options for TCP dialers and listeners. The default only disables Nagle's algorithm, leaving everything else to the OS.
+#[derive(Clone, Debug)]
+pub struct TcpOptions {
+    /// Whether to set `TCP_NODELAY`.
+    pub nodelay: bool,
+    /// TCP keepalive settings. `None` leaves keepalives off.
+    pub keepalive: Option<TcpKeepalive>,
+    /// `TCP_NOTSENT_LOWAT`, which bounds how much unsent data sits in the kernel buffer. Linux and Android only.
+    pub notsent_lowat: Option<u32>,
+    /// `SO_SNDBUF`, in bytes.
+    pub send_buffer_size: Option<usize>,
+    /// `SO_RCVBUF`, in bytes.
+    pub recv_buffer_size: Option<usize>,
+    /// The local address that outgoing connections are bound to. Ignored by listeners, which already have a local address.
+    pub local_addr: Option<SocketAddr>,
+    /// `SO_MARK`, used for policy routing. Linux and Android only, and needs `CAP_NET_ADMIN`.
+    pub mark: Option<u32>,
+    /// `SO_BINDTODEVICE`, the name of the network interface to send through. Linux and Android only.
+    pub bind_device: Option<String>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            notsent_lowat: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            local_addr: None,
+            mark: None,
+            bind_device: None,
+        }
+    }
+}
+
+/// When and how often TCP keepalive probes are sent.
+#[derive(Clone, Copy, Debug)]
+pub struct TcpKeepalive {
+    /// How long a connection is idle before the first probe.
+    pub idle: Duration,
+    /// The time between unanswered probes.
+    pub interval: Duration,
+    /// How many unanswered probes it takes to drop the connection. Ignored on Windows.
+    pub retries: u32,
+}
+
+impl TcpOptions {
+    /// Sets the options that have to be in place before the socket connects or listens.
+    fn apply_before_connect(&self, socket: &Socket) -> std::io::Result<()> {
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(mark) = self.mark {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            socket.set_mark(mark)?;
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            return Err(unsupported(format!("SO_MARK {mark}")));
+        }
+        if let Some(device) = &self.bind_device {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            socket.bind_device(Some(device.as_bytes()))?;
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            return Err(unsupported(format!("SO_BINDTODEVICE {device}")));
+        }
+        Ok(())
+    }
+
+    /// Sets the options that apply to an established connection.
+    fn apply_to_conn(&self, conn: &Async<TcpStream>) -> std::io::Result<()> {
+        let socket = SockRef::from(conn.get_ref());
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(keepalive) = &self.keepalive {
+            let params = socket2::TcpKeepalive::new().with_time(keepalive.idle);
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd",
+                target_os = "windows"
+            ))]
+            let params = params.with_interval(keepalive.interval);
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd"
+            ))]
+            let params = params.with_retries(keepalive.retries);
+            socket.set_tcp_keepalive(&params)?;
+        }
+        if let Some(lowat) = self.notsent_lowat {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            unsafe {
+                use std::os::fd::AsRawFd;
+                let lowat = lowat as libc::c_int;
+                let ret = libc::setsockopt(
+                    conn.as_raw_fd(),
+                    libc::IPPROTO_TCP,
+                    libc::TCP_NOTSENT_LOWAT,
+                    &lowat as *const _ as *const libc::c_void,
+                    std::mem::size_of_val(&lowat) as libc::socklen_t,
+                );
+                if ret != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            return Err(unsupported(format!("TCP_NOTSENT_LOWAT {lowat}")));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn unsupported(what: String) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("{what} is not supported on this platform"),
+    )
+}
+
 /// A TcpListener is a listener for TCP endpoints.
 pub struct TcpListener {
     inner: Async<std::net::TcpListener>,
+    options: TcpOptions,
 }
 
 impl TcpListener {
     /// Creates a new TcpListener by listening to a particular address.
     pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
-        let new = Async::<std::net::TcpListener>::bind(addr)?;
-        Ok(Self { inner: new })
+        Self::bind_with_options(addr, TcpOptions::default()).await
+    }
+
+    /// Creates a new TcpListener by listening to a particular address, applying the given options to the listening socket and to every accepted connection.
+    pub async fn bind_with_options(addr: SocketAddr, options: TcpOptions) -> std::io::Result<Self> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        // the same as what the standard library does
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        options.apply_before_connect(&socket)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        let new = Async::new(std::net::TcpListener::from(socket))?;
+        Ok(Self {
+            inner: new,
+            options,
+        })
     }
 
     /// Get the local listening address.
@@ -47,7 +191,7 @@ impl Listener for TcpListener {
                     .accept()
                     .await
                     .inspect_err(|e| tracing::error!(err = debug(e), "failed to accept"))?;
-                set_tcp_options(&conn).inspect_err(|e| {
+                self.options.apply_to_conn(&conn).inspect_err(|e| {
                     tracing::error!(err = debug(e), "failed to set TCP options")
                 })?;
                 let addr = conn.as_ref().peer_addr()?.to_string();
@@ -73,68 +217,128 @@ impl Listener for TcpListener {
     }
 }
 
-fn set_tcp_options(conn: &Async<TcpStream>) -> std::io::Result<()> {
-    conn.get_ref().set_nodelay(true)?;
-
-    // #[cfg(any(target_os = "linux", target_os = "android"))]
-    // unsafe {
-    //     use std::os::fd::AsRawFd;
-    //     let lowat: libc::c_int = 32768;
-    //     let ret = libc::setsockopt(
-    //         conn.as_raw_fd(),
-    //         libc::IPPROTO_TCP,
-    //         libc::TCP_NOTSENT_LOWAT,
-    //         &lowat as *const _ as *const libc::c_void,
-    //         std::mem::size_of_val(&lowat) as libc::socklen_t,
-    //     );
-    //     if ret != 0 {
-    //         return Err(std::io::Error::last_os_error());
-    //     }
-    // }
-    Ok(())
-}
-
 /// A HappyEyeballsTcpDialer is a dialer for TCP endpoints which tries the given addresses in sequence intelligently.
 pub struct HappyEyeballsTcpDialer(pub Vec<SocketAddr>);
 
+impl HappyEyeballsTcpDialer {
+    /// Returns a dialer to the same addresses that applies the given socket options to every connection.
+    pub fn with_options(self, options: TcpOptions) -> TcpOptionsDialer<Self> {
+        TcpOptionsDialer {
+            dialer: self,
+            options,
+        }
+    }
+}
+
 #[async_trait]
 impl Dialer for HappyEyeballsTcpDialer {
     type P = Box<dyn Pipe>;
     async fn dial(&self) -> std::io::Result<Self::P> {
-        let res = self
-            .0
-            .iter()
-            .enumerate()
-            .map(|(idx, addr)| {
-                let delay = Duration::from_millis(250 * idx as u64);
-                TcpDialer { dest_addr: *addr }.delay(delay).dynamic()
-            })
-            .reduce(|a, b| a.race(b).dynamic());
-        match res {
-            None => Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "no addresses given",
-            )),
-            Some(dialer) => dialer.dial().await,
-        }
+        happy_eyeballs(&self.0, &TcpOptions::default()).await
+    }
+}
+
+async fn happy_eyeballs(
+    addrs: &[SocketAddr],
+    options: &TcpOptions,
+) -> std::io::Result<Box<dyn Pipe>> {
+    if addrs.is_empty() {
+        return Err(std::io::Error::other("no addresses given"));
     }
+    MultiRaceDialer::new(addrs.iter().enumerate().map(|(idx, addr)| {
+        let delay = Duration::from_millis(250 * idx as u64);
+        let dialer = TcpDialer::new(*addr).with_options(options.clone());
+        (dialer.dynamic(), delay)
+    }))
+    .dial()
+    .await
 }
 
-/// A TcpDialer is a dialer for TCP endpoints. It is configured by its fields.
+/// A TcpDialer is a dialer for TCP endpoints. It is configured by its fields, and uses the default [TcpOptions] unless given others through [TcpDialer::with_options].
 pub struct TcpDialer {
     pub dest_addr: SocketAddr,
 }
 
+impl TcpDialer {
+    /// Creates a dialer to the given address.
+    pub fn new(dest_addr: SocketAddr) -> Self {
+        Self { dest_addr }
+    }
+
+    /// Returns a dialer to the same address that applies the given socket options to every connection.
+    pub fn with_options(self, options: TcpOptions) -> TcpOptionsDialer<Self> {
+        TcpOptionsDialer {
+            dialer: self,
+            options,
+        }
+    }
+}
+
 #[async_trait]
 impl Dialer for TcpDialer {
     type P = TcpPipe;
     async fn dial(&self) -> std::io::Result<Self::P> {
-        let inner = Async::<TcpStream>::connect(self.dest_addr)
-            .await
-            .inspect_err(|e| tracing::warn!("inner dial failed: {:?}", e))?;
-        let _ =
-            set_tcp_options(&inner).inspect_err(|e| tracing::warn!("tcp option set fail: {:?}", e));
-        Ok(TcpPipe(inner, self.dest_addr.to_string()))
+        dial_one(self.dest_addr, &TcpOptions::default()).await
+    }
+}
+
+/// A TcpOptionsDialer is a [TcpDialer] or [HappyEyeballsTcpDialer] that applies non-default [TcpOptions], created through their `with_options` methods.
+pub struct TcpOptionsDialer<D> {
+    dialer: D,
+    options: TcpOptions,
+}
+
+#[async_trait]
+impl Dialer for TcpOptionsDialer<TcpDialer> {
+    type P = TcpPipe;
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        dial_one(self.dialer.dest_addr, &self.options).await
+    }
+}
+
+#[async_trait]
+impl Dialer for TcpOptionsDialer<HappyEyeballsTcpDialer> {
+    type P = Box<dyn Pipe>;
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        happy_eyeballs(&self.dialer.0, &self.options).await
+    }
+}
+
+async fn dial_one(dest_addr: SocketAddr, options: &TcpOptions) -> std::io::Result<TcpPipe> {
+    let inner = connect(dest_addr, options)
+        .await
+        .inspect_err(|e| tracing::warn!("inner dial failed: {:?}", e))?;
+    let _ = options
+        .apply_to_conn(&inner)
+        .inspect_err(|e| tracing::warn!("tcp option set fail: {:?}", e));
+    Ok(TcpPipe(inner, dest_addr.to_string()))
+}
+
+/// Connects a new socket, which needs to be set up by hand so that options like the local address can be set before connecting.
+async fn connect(dest_addr: SocketAddr, options: &TcpOptions) -> std::io::Result<Async<TcpStream>> {
+    let socket = Socket::new(
+        Domain::for_address(dest_addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    options.apply_before_connect(&socket)?;
+    if let Some(local_addr) = options.local_addr {
+        socket.bind(&local_addr.into())?;
+    }
+    socket.set_nonblocking(true)?;
+    match socket.connect(&dest_addr.into()) {
+        Ok(()) => {}
+        #[cfg(unix)]
+        Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+        Err(err) => return Err(err),
+    }
+    let stream = Async::new(TcpStream::from(socket))?;
+    // the socket becomes writable once the connection either succeeds or fails
+    stream.writable().await?;
+    match stream.get_ref().take_error()? {
+        Some(err) => Err(err),
+        None => Ok(stream),
     }
 }
 
@@ -184,3 +388,49 @@ impl Pipe for TcpPipe {
         Some(&self.1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp_with_options() {
+        async_io::block_on(async {
+            let options = TcpOptions {
+                keepalive: Some(TcpKeepalive {
+                    idle: Duration::from_secs(30),
+                    interval: Duration::from_secs(5),
+                    retries: 3,
+                }),
+                send_buffer_size: Some(65536),
+                recv_buffer_size: Some(65536),
+                ..Default::default()
+            };
+            let mut listener =
+                TcpListener::bind_with_options("127.0.0.1:0".parse().unwrap(), options.clone())
+                    .await
+                    .unwrap();
+            let local_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let dialer = TcpDialer::new(listener.local_addr().await).with_options(TcpOptions {
+                local_addr: Some(local_addr),
+                ..options
+            });
+            let (client, server) =
+                futures_lite::future::zip(async { dialer.dial().await.unwrap() }, async {
+                    listener.accept().await.unwrap()
+                })
+                .await;
+            let client_local = client.0.get_ref().local_addr().unwrap();
+            assert_eq!(
+                server.remote_addr(),
+                Some(client_local.to_string().as_str())
+            );
+            assert!(SockRef::from(client.0.get_ref()).keepalive().unwrap());
+            assert!(SockRef::from(server.0.get_ref()).nodelay().unwrap());
+
+            // connecting to a closed port fails rather than hanging
+            drop(listener);
+            assert!(dialer.dial().await.is_err());
+        })
+    }
+}
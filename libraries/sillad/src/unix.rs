@@ -0,0 +1,217 @@
+use std::{
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+};
+
+use async_io::Async;
+use async_trait::async_trait;
+
+use futures_lite::{AsyncRead, AsyncWrite};
+use pin_project::pin_project;
+
+use crate::{dialer::Dialer, listener::Listener, Pipe};
+
+/// A UnixListener is a listener for Unix domain socket endpoints.
+pub struct UnixListener {
+    inner: Async<std::os::unix::net::UnixListener>,
+    path: String,
+}
+
+impl UnixListener {
+    /// Creates a new UnixListener by listening on a particular path. A stale socket left over at the path is removed first, but any other kind of file is left alone.
+    pub async fn bind(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if meta.file_type().is_socket() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        let new = Async::<std::os::unix::net::UnixListener>::bind(path)?;
+        Ok(Self {
+            inner: new,
+            path: path.to_string_lossy().into_owned(),
+        })
+    }
+
+    /// Creates a new UnixListener like [`UnixListener::bind`], but only makes the socket reachable at `path` once its permissions have been set to `mode`. The socket is bound at a temporary path next to `path`, chmod-ed, then renamed into place, so it is never reachable with looser permissions.
+    pub async fn bind_with_mode(path: impl AsRef<Path>, mode: u32) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(format!(".{}.tmp", std::process::id()));
+        let tmp_path = PathBuf::from(tmp_path);
+        let _ = std::fs::remove_file(&tmp_path);
+        let new = Async::<std::os::unix::net::UnixListener>::bind(&tmp_path)?;
+        let placed = std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(mode))
+            .and_then(|_| {
+                if let Ok(meta) = std::fs::symlink_metadata(path) {
+                    if !meta.file_type().is_socket() {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::AlreadyExists,
+                            "refusing to replace a non-socket file",
+                        ));
+                    }
+                }
+                std::fs::rename(&tmp_path, path)
+            });
+        if let Err(err) = placed {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(err);
+        }
+        Ok(Self {
+            inner: new,
+            path: path.to_string_lossy().into_owned(),
+        })
+    }
+
+    /// Get the local listening path.
+    pub fn local_path(&self) -> &str {
+        &self.path
+    }
+}
+
+#[async_trait]
+impl Listener for UnixListener {
+    type P = UnixPipe;
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        let (conn, _) = self
+            .inner
+            .accept()
+            .await
+            .inspect_err(|e| tracing::error!(err = debug(e), "failed to accept"))?;
+        // the peers of a unix socket are almost always unnamed, so we identify them by the socket path instead
+        Ok(UnixPipe(conn, self.path.clone()))
+    }
+}
+
+/// A UnixDialer is a dialer for Unix domain socket endpoints. It is configured by its fields.
+pub struct UnixDialer {
+    pub dest_path: PathBuf,
+}
+
+#[async_trait]
+impl Dialer for UnixDialer {
+    type P = UnixPipe;
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let inner = Async::<UnixStream>::connect(&self.dest_path)
+            .await
+            .inspect_err(|e| tracing::warn!("inner dial failed: {:?}", e))?;
+        Ok(UnixPipe(
+            inner,
+            self.dest_path.to_string_lossy().into_owned(),
+        ))
+    }
+}
+
+#[pin_project]
+pub struct UnixPipe(#[pin] Async<UnixStream>, String);
+
+impl AsyncRead for UnixPipe {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().0.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UnixPipe {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().0.poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().0.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().0.poll_close(cx)
+    }
+}
+
+impl Pipe for UnixPipe {
+    fn protocol(&self) -> &str {
+        "unix"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        Some(&self.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn unix_roundtrip() {
+        async_io::block_on(async {
+            let path =
+                std::env::temp_dir().join(format!("sillad-test-{}.sock", rand::random::<u64>()));
+            let mut listener = UnixListener::bind(&path).await.unwrap();
+            let dialer = UnixDialer {
+                dest_path: path.clone(),
+            };
+            let (mut client, mut server) =
+                futures_lite::future::zip(async { dialer.dial().await.unwrap() }, async {
+                    listener.accept().await.unwrap()
+                })
+                .await;
+            assert_eq!(client.remote_addr(), server.remote_addr());
+
+            client.write_all(b"hello unix").await.unwrap();
+            let mut buf = [0u8; 10];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello unix");
+
+            // binding again over the stale socket must work
+            drop(listener);
+            UnixListener::bind(&path).await.unwrap();
+            std::fs::remove_file(&path).unwrap();
+        })
+    }
+
+    #[test]
+    fn unix_bind_with_mode() {
+        async_io::block_on(async {
+            let path =
+                std::env::temp_dir().join(format!("sillad-test-{}.sock", rand::random::<u64>()));
+            let mut listener = UnixListener::bind_with_mode(&path, 0o600).await.unwrap();
+            assert_eq!(listener.local_path(), path.to_string_lossy());
+            let meta = std::fs::symlink_metadata(&path).unwrap();
+            assert!(meta.file_type().is_socket());
+            assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+
+            let dialer = UnixDialer {
+                dest_path: path.clone(),
+            };
+            futures_lite::future::zip(async { dialer.dial().await.unwrap() }, async {
+                listener.accept().await.unwrap()
+            })
+            .await;
+
+            // a regular file at the path must not be clobbered
+            drop(listener);
+            std::fs::remove_file(&path).unwrap();
+            std::fs::write(&path, b"not a socket").unwrap();
+            assert!(UnixListener::bind_with_mode(&path, 0o600).await.is_err());
+            assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+            std::fs::remove_file(&path).unwrap();
+        })
+    }
+}